@@ -0,0 +1,1287 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anndata::{
+    data::{DynCsrMatrix, SelectInfoElem},
+    ArrayData,
+};
+use indexmap::IndexMap;
+use nalgebra_sparse::{CooMatrix, CscMatrix, CsrMatrix};
+use ndarray::Array2;
+use polars::{frame::DataFrame, prelude::NamedFrom, series::Series};
+use rand::seq::SliceRandom;
+
+use crate::{utils::dispatch_numeric, CsrBuilder, IMAnnData, IMArrayElement};
+
+/// Number of expression bins used to pick control genes in `score_genes`.
+const SCORE_GENES_N_BINS: usize = 25;
+
+/// Relative spread below which a column is treated as constant, see
+/// [`ColumnMoments::is_constant`].
+const CONSTANT_TOLERANCE: f64 = 1e-10;
+
+/// Selects whether a per-row statistic of X is computed for every observation or every variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatrixAxis {
+    /// One value per observation, reducing over the variables.
+    Obs,
+    /// One value per variable, reducing over the observations.
+    Var,
+}
+
+/// Aggregation applied to the values of rows that are merged into one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggOp {
+    /// Sum of the merged rows.
+    Sum,
+    /// Mean of the merged rows.
+    Mean,
+}
+
+/// Distance between two points of an embedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Euclidean (L2) distance.
+    Euclidean,
+    /// One minus the cosine similarity. Zero vectors have distance 1 to every other point.
+    Cosine,
+}
+
+/// How per-variable statistics treat NaN values in X.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NanPolicy {
+    /// A NaN makes the statistic of its variable NaN.
+    #[default]
+    Propagate,
+    /// NaNs are left out, like `nanmean`. A variable with only NaNs gets NaN.
+    Skip,
+}
+
+impl IMAnnData {
+    /// Resolves variable names to their column positions.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error listing every name that is not present in `var_names`.
+    pub(crate) fn var_positions(&self, names: &[String]) -> anyhow::Result<Vec<usize>> {
+        let lookup: HashMap<String, usize> = self
+            .var_names()
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (name, i))
+            .collect();
+        let missing: Vec<&str> = names
+            .iter()
+            .filter(|name| !lookup.contains_key(*name))
+            .map(|name| name.as_str())
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Variables not found: {}",
+                missing.join(", ")
+            ));
+        }
+        Ok(names.iter().map(|name| lookup[name]).collect())
+    }
+
+    /// Scores each observation for the expression of a gene set.
+    ///
+    /// The score is the mean expression over `gene_list` minus the mean expression over a set
+    /// of control genes. Genes are split into expression bins by their mean over all
+    /// observations, and for every gene in `gene_list` up to `ctrl_size` genes of the same bin
+    /// that are not part of the set are drawn as controls. If no control gene is left, the
+    /// control mean is zero. The result is also stored in the obs column `score_name`,
+    /// replacing an existing column of that name.
+    ///
+    /// # Arguments
+    ///
+    /// * `gene_list` - Names of the genes in the set.
+    /// * `ctrl_size` - Number of control genes drawn per gene of the set.
+    /// * `seed` - Seed of the random number generator used to draw the control genes.
+    /// * `score_name` - Name of the obs column receiving the scores.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `gene_list` is empty, contains unknown genes or X is not numeric.
+    pub fn score_genes(
+        &self,
+        gene_list: &[String],
+        ctrl_size: usize,
+        seed: u64,
+        score_name: &str,
+    ) -> anyhow::Result<Vec<f64>> {
+        if gene_list.is_empty() {
+            return Err(anyhow::anyhow!("Gene list is empty"));
+        }
+        let gene_positions: BTreeSet<usize> = self.var_positions(gene_list)?.into_iter().collect();
+        let csr = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        let n_obs = csr.nrows();
+        let n_vars = csr.ncols();
+
+        // Bin genes by the rank of their mean expression
+        let mut gene_means = vec![0.0; n_vars];
+        for (&col, &value) in csr.col_indices().iter().zip(csr.values()) {
+            gene_means[col] += value;
+        }
+        let mut order: Vec<usize> = (0..n_vars).collect();
+        order.sort_by(|&a, &b| gene_means[a].total_cmp(&gene_means[b]));
+        let n_bins = SCORE_GENES_N_BINS.min(n_vars);
+        let mut gene_bin = vec![0; n_vars];
+        let mut bins: Vec<Vec<usize>> = vec![Vec::new(); n_bins];
+        for (rank, &gene) in order.iter().enumerate() {
+            let bin = rank * n_bins / n_vars;
+            gene_bin[gene] = bin;
+            bins[bin].push(gene);
+        }
+
+        let mut in_set = vec![false; n_vars];
+        gene_positions.iter().for_each(|&g| in_set[g] = true);
+
+        let mut rng = crate::utils::rng_from_seed(seed);
+        let mut control_positions = BTreeSet::new();
+        for &gene in &gene_positions {
+            let pool: Vec<usize> = bins[gene_bin[gene]]
+                .iter()
+                .copied()
+                .filter(|&g| !in_set[g])
+                .collect();
+            control_positions.extend(pool.choose_multiple(&mut rng, ctrl_size.min(pool.len())));
+        }
+
+        let mut in_control = vec![false; n_vars];
+        control_positions.iter().for_each(|&g| in_control[g] = true);
+
+        let mut scores = Vec::with_capacity(n_obs);
+        for row in csr.row_iter() {
+            let mut set_sum = 0.0;
+            let mut control_sum = 0.0;
+            for (&col, &value) in row.col_indices().iter().zip(row.values()) {
+                if in_set[col] {
+                    set_sum += value;
+                }
+                if in_control[col] {
+                    control_sum += value;
+                }
+            }
+            let set_mean = set_sum / gene_positions.len() as f64;
+            let control_mean = if control_positions.is_empty() {
+                0.0
+            } else {
+                control_sum / control_positions.len() as f64
+            };
+            scores.push(set_mean - control_mean);
+        }
+
+        self.obs
+            .attach_column_to_df(Series::new(score_name, scores.clone()))?;
+        self.record("score_genes", format!("out={}", score_name));
+        Ok(scores)
+    }
+
+    /// Returns the X values of the given genes as a long (tidy) DataFrame.
+    ///
+    /// The frame has the columns `obs_name`, `var_name` and `value` with one row per
+    /// observation and gene, grouped by gene in the order of `genes`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a gene is unknown, X is not numeric or the frame would exceed the
+    /// dense size limit.
+    pub fn to_long_df(&self, genes: &[String]) -> anyhow::Result<DataFrame> {
+        let n_obs = self.n_obs();
+        let values = self.extract_genes_dense(genes)?;
+
+        let obs_names = self.obs_names();
+        let n_rows = n_obs * genes.len();
+        let mut obs_column = Vec::with_capacity(n_rows);
+        let mut var_column = Vec::with_capacity(n_rows);
+        let mut value_column = Vec::with_capacity(n_rows);
+        for (gene, column) in genes.iter().zip(values.columns()) {
+            obs_column.extend(obs_names.iter().cloned());
+            var_column.extend(std::iter::repeat(gene.clone()).take(n_obs));
+            value_column.extend(column.iter().copied());
+        }
+        Ok(DataFrame::new(vec![
+            Series::new("obs_name", obs_column),
+            Series::new("var_name", var_column),
+            Series::new("value", value_column),
+        ])?)
+    }
+
+    /// Gathers the X columns of the given genes into a dense `n_obs x genes.len()` matrix.
+    ///
+    /// Sparse CSR matrices are scanned once and only the entries of the requested columns
+    /// are copied, so neither X nor a sparse subset of it is materialized. Columns are in the
+    /// order of `genes`, a gene listed twice yields two identical columns.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a gene is unknown, X is not numeric or the result would exceed the
+    /// dense size limit.
+    pub fn extract_genes_dense(&self, genes: &[String]) -> anyhow::Result<Array2<f64>> {
+        let positions = self.var_positions(genes)?;
+        let n_obs = self.n_obs();
+        crate::utils::check_dense_size(n_obs, positions.len())?;
+        // Output columns of every X column, most are empty
+        let mut targets: Vec<Vec<usize>> = vec![Vec::new(); self.n_vars()];
+        for (out, &col) in positions.iter().enumerate() {
+            targets[col].push(out);
+        }
+        {
+            let read_guard = self.x.0.read_inner();
+            if let ArrayData::CsrMatrix(csr) = &*read_guard {
+                let mut result = Array2::<f64>::zeros((n_obs, positions.len()));
+                dispatch_numeric!(
+                    csr,
+                    DynCsrMatrix,
+                    m => for (i, row) in m.row_iter().enumerate() {
+                        for (&col, &value) in row.col_indices().iter().zip(row.values()) {
+                            for &out in &targets[col] {
+                                result[[i, out]] = value as f64;
+                            }
+                        }
+                    },
+                    return Err(anyhow::anyhow!("Unsupported CSR matrix type"))
+                );
+                return Ok(result);
+            }
+        }
+        let var_sel = SelectInfoElem::Index(positions);
+        self.x
+            .subset(&[&SelectInfoElem::full(), &var_sel])?
+            .to_dense_f64()
+    }
+
+    /// Stores the z-scored X values of a gene panel as a dense obsm matrix.
+    ///
+    /// Each gene is centered to mean zero and scaled to unit (population) standard deviation
+    /// across observations; genes with zero variance yield a column of zeros. The resulting
+    /// `n_obs x genes.len()` matrix is stored in obsm under `key`, columns in the order of `genes`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a gene is unknown, X is not numeric or `key` already exists in obsm.
+    pub fn zscore_panel_to_obsm(&self, genes: &[String], key: String) -> anyhow::Result<()> {
+        let mut panel = self.extract_genes_dense(genes)?;
+        let n_obs = panel.nrows() as f64;
+        for mut column in panel.columns_mut() {
+            let mean = column.sum() / n_obs;
+            let std = (column.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n_obs).sqrt();
+            if std > 0.0 {
+                column.mapv_inplace(|v| (v - mean) / std);
+            } else {
+                column.fill(0.0);
+            }
+        }
+        let details = format!("out={}", key);
+        self.obsm
+            .add_array(key, IMArrayElement::new(ArrayData::from(panel.into_dyn())))?;
+        self.record("zscore_panel_to_obsm", details);
+        Ok(())
+    }
+
+    /// Returns the `n` genes with the highest X values for every observation.
+    ///
+    /// Only stored nonzero values are considered, so observations with fewer than `n` of them
+    /// return all of them. Genes are ordered by descending value, ties by position in `var`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if X is not numeric.
+    pub fn top_genes_per_cell(&self, n: usize) -> anyhow::Result<Vec<Vec<(String, f64)>>> {
+        let csr = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        let var_names = self.var_names();
+        let by_value = |a: &(usize, f64), b: &(usize, f64)| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0));
+        let mut result = Vec::with_capacity(csr.nrows());
+        for row in csr.row_iter() {
+            let mut entries: Vec<(usize, f64)> = row
+                .col_indices()
+                .iter()
+                .copied()
+                .zip(row.values().iter().copied())
+                .filter(|&(_, value)| value != 0.0)
+                .collect();
+            if n < entries.len() {
+                if n > 0 {
+                    entries.select_nth_unstable_by(n - 1, by_value);
+                }
+                entries.truncate(n);
+            }
+            entries.sort_unstable_by(by_value);
+            result.push(
+                entries
+                    .into_iter()
+                    .map(|(col, value)| (var_names[col].clone(), value))
+                    .collect(),
+            );
+        }
+        Ok(result)
+    }
+
+    /// Computes the Pearson correlation of every gene's expression with a per-cell reference.
+    ///
+    /// # Arguments
+    ///
+    /// * `reference` - One value per observation, for example a signature score.
+    ///
+    /// # Returns
+    ///
+    /// One correlation per variable. Genes with zero variance across cells get `NaN`, as do
+    /// all genes if the reference itself has zero variance.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `reference` does not have one value per observation or X is not
+    /// numeric.
+    pub fn correlate_genes(&self, reference: &[f64]) -> anyhow::Result<Vec<f64>> {
+        if reference.len() != self.n_obs() {
+            return Err(anyhow::anyhow!(
+                "Reference has length {}, expected {}",
+                reference.len(),
+                self.n_obs()
+            ));
+        }
+        let csr = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        let n = reference.len() as f64;
+        let ref_mean = reference.iter().sum::<f64>() / n;
+        let ref_ss: f64 = reference.iter().map(|r| (r - ref_mean).powi(2)).sum();
+        let n_vars = csr.ncols();
+        let rows = (0..reference.len()).map(|row| (row, 1.0));
+        let moments = column_moments(&csr, rows, NanPolicy::Propagate);
+        let mut cross = vec![0.0; n_vars];
+        for (row, &r) in csr.row_iter().zip(reference) {
+            for (&col, &value) in row.col_indices().iter().zip(row.values()) {
+                cross[col] += value * (r - ref_mean);
+            }
+        }
+        let ref_constant = ref_ss <= n * (CONSTANT_TOLERANCE * ref_mean).powi(2);
+        Ok((0..n_vars)
+            .map(|j| {
+                if ref_constant || moments.is_constant(j) {
+                    return f64::NAN;
+                }
+                // The centered reference sums to zero, so the gene mean drops out of the cross term
+                cross[j] / (moments.sum_sq[j] * ref_ss).sqrt()
+            })
+            .collect())
+    }
+
+    /// Computes the percentage of each observation's total counts found in its top genes.
+    ///
+    /// For every `k` in `n`, the `k` highest X values of an observation are summed and
+    /// divided by the observation's total. The results are stored in obs as
+    /// `pct_counts_in_top_{k}_genes`, replacing existing columns of that name. Observations
+    /// with a total of zero get 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Numbers of top genes to evaluate.
+    ///
+    /// # Returns
+    ///
+    /// The percentages per observation, keyed by `k`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any `k` is zero or exceeds the number of variables, or if X is not
+    /// numeric.
+    pub fn pct_counts_in_top(&self, n: &[usize]) -> anyhow::Result<HashMap<usize, Vec<f64>>> {
+        let n_vars = self.n_vars();
+        if let Some(&k) = n.iter().find(|&&k| k == 0 || k > n_vars) {
+            return Err(anyhow::anyhow!(
+                "Cannot compute the counts in the top {} genes of {} variables",
+                k,
+                n_vars
+            ));
+        }
+        let csr = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        let k_max = n.iter().copied().max().unwrap_or(0);
+        let mut result: HashMap<usize, Vec<f64>> = n
+            .iter()
+            .map(|&k| (k, Vec::with_capacity(csr.nrows())))
+            .collect();
+        for row in csr.row_iter() {
+            let mut values = row.values().to_vec();
+            let total: f64 = values.iter().sum();
+            // Only the k_max largest values are needed, in descending order
+            if k_max < values.len() {
+                values.select_nth_unstable_by(k_max - 1, |a, b| b.total_cmp(a));
+                values.truncate(k_max);
+            }
+            values.sort_unstable_by(|a, b| b.total_cmp(a));
+            let mut cumulative = Vec::with_capacity(values.len());
+            let mut running = 0.0;
+            for value in values {
+                running += value;
+                cumulative.push(running);
+            }
+            for (&k, pct) in result.iter_mut() {
+                let top = cumulative
+                    .get(k.min(cumulative.len()).wrapping_sub(1))
+                    .copied()
+                    .unwrap_or(0.0);
+                pct.push(if total == 0.0 { 0.0 } else { 100.0 * top / total });
+            }
+        }
+        let mut ks: Vec<usize> = result.keys().copied().collect();
+        ks.sort_unstable();
+        self.obs.attach_columns(
+            ks.into_iter()
+                .map(|k| Series::new(&format!("pct_counts_in_top_{}_genes", k), result[&k].clone()))
+                .collect(),
+        )?;
+        Ok(result)
+    }
+
+    /// Computes the fraction of nonzero X entries per observation or per variable.
+    ///
+    /// With `MatrixAxis::Var` this is the fraction of observations expressing each variable
+    /// (`pct_cells_expressing` divided by 100), with `MatrixAxis::Obs` the fraction of
+    /// variables detected in each observation.
+    ///
+    /// # Arguments
+    ///
+    /// * `axis` - Whether to compute one value per observation or per variable.
+    /// * `column` - If set, the result is also stored under this name in obs or var.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if X is not numeric or the column cannot be attached.
+    pub fn detection_rate(
+        &self,
+        axis: MatrixAxis,
+        column: Option<String>,
+    ) -> anyhow::Result<Vec<f64>> {
+        let csr = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        let (n_out, n_other) = match axis {
+            MatrixAxis::Obs => (csr.nrows(), csr.ncols()),
+            MatrixAxis::Var => (csr.ncols(), csr.nrows()),
+        };
+        let mut counts = vec![0usize; n_out];
+        for (row, row_values) in csr.row_iter().enumerate() {
+            for (&col, &value) in row_values.col_indices().iter().zip(row_values.values()) {
+                if value != 0.0 {
+                    counts[if axis == MatrixAxis::Obs { row } else { col }] += 1;
+                }
+            }
+        }
+        let rates: Vec<f64> = counts
+            .into_iter()
+            .map(|count| {
+                if n_other == 0 {
+                    0.0
+                } else {
+                    count as f64 / n_other as f64
+                }
+            })
+            .collect();
+        if let Some(column) = column {
+            let target = match axis {
+                MatrixAxis::Obs => &self.obs,
+                MatrixAxis::Var => &self.var,
+            };
+            target.attach_column_to_df(Series::new(&column, rates.clone()))?;
+        }
+        Ok(rates)
+    }
+
+    /// Computes the weighted mean of every variable over the observations of X.
+    ///
+    /// Each observation contributes its X values scaled by its weight, and the sums are
+    /// divided by the total weight. With equal weights the result is the plain mean.
+    ///
+    /// # Arguments
+    ///
+    /// * `weights` - One non-negative weight per observation.
+    /// * `nan_policy` - Whether NaN values make the mean NaN or are left out. With
+    ///   [`NanPolicy::Skip`] the sums of a variable are divided by the total weight of the
+    ///   observations without NaN.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `weights` does not have one value per observation, contains a
+    /// negative or non-finite value, sums to zero, or if X is not numeric.
+    pub fn var_means_weighted(
+        &self,
+        weights: &[f64],
+        nan_policy: NanPolicy,
+    ) -> anyhow::Result<Vec<f64>> {
+        Ok(self.var_moments(Some(weights), nan_policy)?.means)
+    }
+
+    /// Computes the weighted variance of every variable over the observations of X.
+    ///
+    /// The variance is `sum_i w_i (x_ij - m_j)^2 / sum_i w_i`, where `m_j` is the weighted
+    /// mean from [`IMAnnData::var_means_weighted`].
+    ///
+    /// # Arguments
+    ///
+    /// * `weights` - One non-negative weight per observation.
+    /// * `nan_policy` - Whether NaN values make the variance NaN or are left out.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`IMAnnData::var_means_weighted`].
+    ///
+    /// # Notes
+    ///
+    /// No bias correction is applied, so equal weights give the population variance
+    /// (`ddof = 0`) rather than the sample variance.
+    pub fn var_variances_weighted(
+        &self,
+        weights: &[f64],
+        nan_policy: NanPolicy,
+    ) -> anyhow::Result<Vec<f64>> {
+        Ok(self.var_moments(Some(weights), nan_policy)?.variances())
+    }
+
+    /// Computes the mean of every variable over the observations of X.
+    ///
+    /// # Arguments
+    ///
+    /// * `nan_policy` - Whether NaN values make the mean NaN or are left out. With
+    ///   [`NanPolicy::Skip`] the mean is taken over the remaining observations.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if X is not numeric.
+    pub fn var_means(&self, nan_policy: NanPolicy) -> anyhow::Result<Vec<f64>> {
+        Ok(self.var_moments(None, nan_policy)?.means)
+    }
+
+    /// Computes the population variance (`ddof = 0`) of every variable over the observations
+    /// of X.
+    ///
+    /// # Arguments
+    ///
+    /// * `nan_policy` - Whether NaN values make the variance NaN or are left out.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if X is not numeric.
+    pub fn var_variances(&self, nan_policy: NanPolicy) -> anyhow::Result<Vec<f64>> {
+        Ok(self.var_moments(None, nan_policy)?.variances())
+    }
+
+    /// Returns the means and centered sums of squares of every variable, weighting the
+    /// observations by `weights` if given.
+    fn var_moments(
+        &self,
+        weights: Option<&[f64]>,
+        nan_policy: NanPolicy,
+    ) -> anyhow::Result<ColumnMoments> {
+        let n_obs = self.n_obs();
+        if let Some(weights) = weights {
+            validate_weights(weights, n_obs)?;
+        }
+        let csr = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        let rows = (0..n_obs).map(|row| (row, weights.map_or(1.0, |w| w[row])));
+        Ok(column_moments(&csr, rows, nan_policy))
+    }
+
+    /// Computes the `q`-quantile of every variable over all observations of X.
+    ///
+    /// Implicit zeros of sparse matrices are part of the distribution, so the median of a
+    /// variable detected in fewer than half of the observations is zero. Quantiles between
+    /// two values are linearly interpolated, NaNs sort after all other values.
+    ///
+    /// # Arguments
+    ///
+    /// * `q` - Quantile between `0` (minimum) and `1` (maximum).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `q` is outside `[0, 1]` or X is not numeric.
+    pub fn var_quantile(&self, q: f64) -> anyhow::Result<Vec<f64>> {
+        self.axis_quantile(MatrixAxis::Var, q)
+    }
+
+    /// Computes the `q`-quantile of every observation over all variables of X.
+    ///
+    /// See [`IMAnnData::var_quantile`] for the handling of implicit zeros.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `q` is outside `[0, 1]` or X is not numeric.
+    pub fn obs_quantile(&self, q: f64) -> anyhow::Result<Vec<f64>> {
+        self.axis_quantile(MatrixAxis::Obs, q)
+    }
+
+    fn axis_quantile(&self, axis: MatrixAxis, q: f64) -> anyhow::Result<Vec<f64>> {
+        if !(0.0..=1.0).contains(&q) {
+            return Err(anyhow::anyhow!("Quantile must lie within [0, 1], got {}", q));
+        }
+        let csr = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        Ok(match axis {
+            MatrixAxis::Obs => csr
+                .row_iter()
+                .map(|row| lane_quantile(row.values(), csr.ncols(), q))
+                .collect(),
+            MatrixAxis::Var => {
+                let csc = CscMatrix::from(&csr);
+                csc.col_iter()
+                    .map(|col| lane_quantile(col.values(), csc.nrows(), q))
+                    .collect()
+            }
+        })
+    }
+
+    /// Computes the pairwise distances between observations in an obsm embedding and stores
+    /// them in obsp.
+    ///
+    /// The full `n_obs x n_obs` matrix is computed densely, so this is meant for small
+    /// datasets.
+    ///
+    /// # Arguments
+    ///
+    /// * `obsm_key` - Key of the 2-dimensional embedding in obsm.
+    /// * `metric` - Distance metric.
+    /// * `out` - Key of the new obsp entry.
+    /// * `max_distance` - If set, only distances up to this value between distinct
+    ///   observations are kept and the result is stored as CSR, otherwise it is stored dense.
+    ///   Distances of exactly zero are implicit in the sparse result.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the embedding is missing, not 2-dimensional or not numeric, the
+    /// distance matrix would exceed the dense size limit, or `out` already exists.
+    pub fn compute_distances(
+        &self,
+        obsm_key: &str,
+        metric: Metric,
+        out: String,
+        max_distance: Option<f64>,
+    ) -> anyhow::Result<()> {
+        let n_obs = self.n_obs();
+        crate::utils::check_dense_size(n_obs, n_obs)?;
+        let embedding = self.obsm.get_array_shallow(obsm_key)?.to_dense_f64()?;
+        let norms: Vec<f64> = embedding
+            .rows()
+            .into_iter()
+            .map(|row| row.dot(&row).sqrt())
+            .collect();
+        let mut distances = Array2::<f64>::zeros((n_obs, n_obs));
+        for i in 0..n_obs {
+            for j in (i + 1)..n_obs {
+                let (a, b) = (embedding.row(i), embedding.row(j));
+                let d = match metric {
+                    Metric::Euclidean => a
+                        .iter()
+                        .zip(b.iter())
+                        .map(|(x, y)| (x - y) * (x - y))
+                        .sum::<f64>()
+                        .sqrt(),
+                    Metric::Cosine if norms[i] == 0.0 || norms[j] == 0.0 => 1.0,
+                    Metric::Cosine => 1.0 - a.dot(&b) / (norms[i] * norms[j]),
+                };
+                distances[[i, j]] = d;
+                distances[[j, i]] = d;
+            }
+        }
+        let data = match max_distance {
+            None => ArrayData::from(distances.into_dyn()),
+            Some(max) => {
+                let mut coo = CooMatrix::new(n_obs, n_obs);
+                for ((i, j), &d) in distances.indexed_iter() {
+                    if i != j && d != 0.0 && d <= max {
+                        coo.push(i, j, d);
+                    }
+                }
+                ArrayData::CsrMatrix(DynCsrMatrix::from(CsrMatrix::from(&coo)))
+            }
+        };
+        self.obsp.add_array(out, IMArrayElement::new(data))
+    }
+
+    /// Divides every value of X by the total of its row, giving per-observation fractions.
+    ///
+    /// The result is stored as a sparse `f64` CSR matrix, rows summing to zero stay zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `out_layer` - Layer receiving the fractions, `None` replaces X. An existing layer of
+    ///   that name is overwritten.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if X is not numeric.
+    pub fn to_fractions(&self, out_layer: Option<String>) -> anyhow::Result<()> {
+        let mut csr = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        for mut row in csr.row_iter_mut() {
+            let total: f64 = row.values().iter().sum();
+            if total != 0.0 {
+                row.values_mut().iter_mut().for_each(|v| *v /= total);
+            }
+        }
+        self.store_x_result("to_fractions", ArrayData::CsrMatrix(DynCsrMatrix::F64(csr)), out_layer)
+    }
+
+    /// Replaces every observation's expression with the weighted mean over its graph neighbors.
+    ///
+    /// The connectivity matrix in obsp is row-normalized and multiplied with X, which is the
+    /// diffusion step of MAGIC-style imputation. The result is stored as a sparse `f64` CSR
+    /// matrix.
+    ///
+    /// # Arguments
+    ///
+    /// * `obsp_key` - Key of the connectivity graph in obsp.
+    /// * `out_layer` - Layer receiving the smoothed values, `None` replaces X. An existing
+    ///   layer of that name is overwritten.
+    ///
+    /// # Notes
+    ///
+    /// An observation only includes itself if the graph has a self-loop for it. Observations
+    /// without any neighbors keep their original values.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the graph is missing or not `n_obs x n_obs`, or if X or the graph
+    /// is not numeric.
+    pub fn smooth_x_with_graph(&self, obsp_key: &str, out_layer: Option<String>) -> anyhow::Result<()> {
+        let graph = crate::utils::to_csr_f64(&self.obsp.get_array_shallow(obsp_key)?.get_data()?)?;
+        let n_obs = self.n_obs();
+        if graph.nrows() != n_obs || graph.ncols() != n_obs {
+            return Err(anyhow::anyhow!(
+                "Graph {} has shape {}x{}, expected {}x{}",
+                obsp_key,
+                graph.nrows(),
+                graph.ncols(),
+                n_obs,
+                n_obs
+            ));
+        }
+        let mut weights = CooMatrix::new(n_obs, n_obs);
+        for (i, row) in graph.row_iter().enumerate() {
+            let total: f64 = row.values().iter().sum();
+            if total == 0.0 {
+                weights.push(i, i, 1.0);
+                continue;
+            }
+            for (&j, &w) in row.col_indices().iter().zip(row.values()) {
+                weights.push(i, j, w / total);
+            }
+        }
+        let weights = CsrMatrix::from(&weights);
+        let x = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        let smoothed = &weights * &x;
+        self.store_x_result(
+            "smooth_x_with_graph",
+            ArrayData::CsrMatrix(DynCsrMatrix::F64(smoothed)),
+            out_layer,
+        )
+    }
+
+    /// Assembles a matrix taking each row from X or from a layer.
+    ///
+    /// Row `i` of the result is row `i` of the layer if `selector[i]` is `true`, otherwise row
+    /// `i` of X. The result is stored as a sparse `f64` CSR matrix.
+    ///
+    /// # Arguments
+    ///
+    /// * `layer` - Name of the layer providing the selected rows.
+    /// * `selector` - One entry per observation, `true` takes the row from the layer.
+    /// * `out` - Layer receiving the result, `None` replaces X. An existing layer of that name
+    ///   is overwritten.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the layer does not exist, `selector` has the wrong length, the
+    /// shapes of X and the layer differ or either is not numeric.
+    pub fn select_layer_per_obs(
+        &self,
+        layer: &str,
+        selector: &[bool],
+        out: Option<String>,
+    ) -> anyhow::Result<()> {
+        let source = self.layers.get_array_shallow(layer)?;
+        if selector.len() != self.n_obs() {
+            return Err(anyhow::anyhow!(
+                "Selector has length {}, expected {}",
+                selector.len(),
+                self.n_obs()
+            ));
+        }
+        let x = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        let selected = crate::utils::to_csr_f64(&source.get_data()?)?;
+        if (x.nrows(), x.ncols()) != (selected.nrows(), selected.ncols()) {
+            return Err(anyhow::anyhow!(
+                "Layer {} has shape ({}, {}) but X has shape ({}, {})",
+                layer,
+                selected.nrows(),
+                selected.ncols(),
+                x.nrows(),
+                x.ncols()
+            ));
+        }
+        let nnz = selector
+            .iter()
+            .enumerate()
+            .map(|(i, &from_layer)| if from_layer { selected.row(i).nnz() } else { x.row(i).nnz() })
+            .sum();
+        let mut builder = CsrBuilder::with_capacity(x.ncols(), x.nrows(), nnz);
+        for (i, &from_layer) in selector.iter().enumerate() {
+            let row = if from_layer { selected.row(i) } else { x.row(i) };
+            builder.push_row(row.col_indices().to_vec(), row.values().to_vec())?;
+        }
+        self.store_x_result("select_layer_per_obs", builder.finish()?, out)
+    }
+
+    /// Writes a matrix derived from X either back to X or to the given layer.
+    ///
+    /// The write is recorded in the history under the name `operation`.
+    fn store_x_result(
+        &self,
+        operation: &str,
+        data: ArrayData,
+        out_layer: Option<String>,
+    ) -> anyhow::Result<()> {
+        let target = out_layer.clone().unwrap_or_else(|| "X".to_string());
+        match out_layer {
+            None => self.x.set_data(data)?,
+            Some(name) => match self.layers.get_array_shallow(&name) {
+                Ok(layer) => layer.set_data(data)?,
+                Err(_) => self.layers.add_array(name, IMArrayElement::new(data))?,
+            },
+        }
+        self.record(operation, format!("out={}", target));
+        Ok(())
+    }
+
+    /// Randomly selects up to `n_per_group` observations from every category of an obs column.
+    ///
+    /// Groups with fewer than `n_per_group` observations contribute all their members. The
+    /// selected observations keep their original relative order within each group, groups
+    /// are ordered by first appearance. Missing values form their own group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_col` - Name of the obs column defining the groups.
+    /// * `n_per_group` - Maximum number of observations selected per group.
+    /// * `seed` - Seed of the random number generator, the same seed yields the same subset.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the column does not exist.
+    pub fn balanced_subsample(
+        &self,
+        group_col: &str,
+        n_per_group: usize,
+        seed: u64,
+    ) -> anyhow::Result<IMAnnData> {
+        let mut rng = crate::utils::rng_from_seed(seed);
+        let mut indices = Vec::new();
+        for members in self.obs_groups(group_col)?.into_values() {
+            let n = n_per_group.min(members.len());
+            let mut chosen = crate::utils::sample_indices(&mut rng, members.len(), n, false)?;
+            chosen.sort_unstable();
+            indices.extend(chosen.into_iter().map(|i| members[i]));
+        }
+        let obs_sel = SelectInfoElem::Index(indices);
+        self.subset(&[&obs_sel, &SelectInfoElem::full()])
+    }
+
+    /// Splits the object into one subset per category of an obs column.
+    ///
+    /// This is the inverse of concatenating batches. Each subset keeps the observations of
+    /// its group in their original order together with all variables, so var, varm and varp
+    /// are the same in every part. Missing values form their own group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_col` - Name of the obs column defining the groups.
+    ///
+    /// # Returns
+    ///
+    /// The subsets keyed by group label, the group of missing values under `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the column does not exist or a subset fails.
+    pub fn split_by(
+        &self,
+        group_col: &str,
+    ) -> anyhow::Result<HashMap<Option<String>, IMAnnData>> {
+        self.obs_groups(group_col)?
+            .into_iter()
+            .map(|(label, members)| {
+                let obs_sel = SelectInfoElem::Index(members);
+                Ok((label, self.subset(&[&obs_sel, &SelectInfoElem::full()])?))
+            })
+            .collect()
+    }
+
+    /// Selects highly variable genes while ignoring variability that is specific to one batch.
+    ///
+    /// Within every batch the dispersion (variance over mean) of each variable is computed and
+    /// the `n_top` variables with the highest positive dispersion are marked. A variable is
+    /// selected if it is marked in more than half of the batches, so with two batches it has
+    /// to be highly variable in both. If more than `n_top` variables qualify, the ones marked
+    /// in the most batches are kept, ties are broken by their mean rank across batches.
+    ///
+    /// # Arguments
+    ///
+    /// * `n_top` - Number of variables marked per batch and maximum number selected.
+    /// * `batch_col` - Name of a string or categorical obs column assigning the batches.
+    ///
+    /// # Returns
+    ///
+    /// A mask over the variables, `true` for selected ones. It can contain fewer than `n_top`
+    /// entries if the batches disagree.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the batch column is missing or not string or categorical, or if X
+    /// is not numeric.
+    pub fn highly_variable_genes_batched(
+        &self,
+        n_top: usize,
+        batch_col: &str,
+    ) -> anyhow::Result<Vec<bool>> {
+        let dtype = self.obs.get_column_from_df(batch_col)?.dtype().clone();
+        if !matches!(
+            dtype,
+            polars::datatypes::DataType::String | polars::datatypes::DataType::Categorical(_, _)
+        ) {
+            return Err(anyhow::anyhow!(
+                "Batch column {} must be string or categorical, found {}",
+                batch_col,
+                dtype
+            ));
+        }
+        let batches = self.obs_groups(batch_col)?;
+        let csr = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        let n_vars = csr.ncols();
+        let mut votes = vec![0usize; n_vars];
+        let mut rank_sums = vec![0usize; n_vars];
+        for members in batches.values() {
+            let rows = members.iter().map(|&row| (row, 1.0));
+            let moments = column_moments(&csr, rows, NanPolicy::Propagate);
+            let n = members.len() as f64;
+            let dispersions: Vec<f64> = (0..n_vars)
+                .map(|j| {
+                    let mean = moments.means[j];
+                    if members.len() < 2 || mean <= 0.0 || moments.is_constant(j) {
+                        return 0.0;
+                    }
+                    moments.sum_sq[j] / (n - 1.0) / mean
+                })
+                .collect();
+            let mut order: Vec<usize> = (0..n_vars).collect();
+            order.sort_by(|&a, &b| dispersions[b].total_cmp(&dispersions[a]));
+            for (rank, &j) in order.iter().enumerate() {
+                rank_sums[j] += rank;
+                if rank < n_top && dispersions[j] > 0.0 {
+                    votes[j] += 1;
+                }
+            }
+        }
+        let mut candidates: Vec<usize> = (0..n_vars)
+            .filter(|&j| votes[j] * 2 > batches.len())
+            .collect();
+        candidates.sort_by(|&a, &b| votes[b].cmp(&votes[a]).then(rank_sums[a].cmp(&rank_sums[b])));
+        let mut selected = vec![false; n_vars];
+        candidates.into_iter().take(n_top).for_each(|j| selected[j] = true);
+        self.record(
+            "highly_variable_genes_batched",
+            format!("n_top={}, batch_col={}", n_top, batch_col),
+        );
+        Ok(selected)
+    }
+
+    /// Computes the mean expression of every variable within each group of observations.
+    ///
+    /// Groups are taken from an obs column and listed in order of first appearance. The sums
+    /// and counts of all groups are accumulated in a single pass over X.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_col` - Name of the obs column defining the groups.
+    ///
+    /// # Returns
+    ///
+    /// The group labels, `None` for observations with a missing value, and an
+    /// `n_groups x n_vars` matrix whose row `i` holds the means of group `i`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the grouping column does not exist or X is not numeric.
+    pub fn mean_expression_by_group(
+        &self,
+        group_col: &str,
+    ) -> anyhow::Result<(Vec<Option<String>>, Array2<f64>)> {
+        let groups = self.obs_groups(group_col)?;
+        let csr = crate::utils::to_csr_f64(&self.x.get_data()?)?;
+        let mut row_group = vec![0; csr.nrows()];
+        for (g, members) in groups.values().enumerate() {
+            members.iter().for_each(|&row| row_group[row] = g);
+        }
+        let mut means = Array2::<f64>::zeros((groups.len(), csr.ncols()));
+        for (row, values) in csr.row_iter().enumerate() {
+            let g = row_group[row];
+            for (&col, &value) in values.col_indices().iter().zip(values.values()) {
+                means[[g, col]] += value;
+            }
+        }
+        for (g, members) in groups.values().enumerate() {
+            let n = members.len() as f64;
+            means.row_mut(g).iter_mut().for_each(|v| *v /= n);
+        }
+        Ok((groups.into_keys().collect(), means))
+    }
+
+    /// Subtracts the per-variable mean of each group from the observations of that group.
+    ///
+    /// A lightweight batch correction: afterwards every group has mean zero for every
+    /// variable. Centering fills in the implicit zeros of sparse data, so X is replaced by a
+    /// dense `f64` matrix.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_col` - Name of the obs column defining the groups, e.g. the batch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the column does not exist, X is not numeric or the dense matrix
+    /// would exceed the dense size limit.
+    pub fn center_by_group(&self, group_col: &str) -> anyhow::Result<()> {
+        let (_, means) = self.mean_expression_by_group(group_col)?;
+        let groups = self.obs_groups(group_col)?;
+        let mut x = self.x.to_dense_f64()?;
+        for (g, members) in groups.values().enumerate() {
+            let mean = means.row(g);
+            for &row in members {
+                let mut row = x.row_mut(row);
+                row -= &mean;
+            }
+        }
+        self.x.set_data(ArrayData::from(x.into_dyn()))?;
+        self.record("center_by_group", group_col.to_string());
+        Ok(())
+    }
+
+    /// Merges observations sharing the same name into a single observation.
+    ///
+    /// Rows of X and of every layer belonging to the same obs name are aggregated with `how`
+    /// and stored as `f64` CSR matrices. Each name is kept at the position of its first
+    /// occurrence, whose obs metadata, obsm rows and obsp entries are kept. The var side,
+    /// varm, varp and uns are copied unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `how` - Whether duplicate rows are summed or averaged.
+    ///
+    /// # Returns
+    ///
+    /// A new object with unique obs names, `self` is left unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if X or a layer is not numeric.
+    pub fn collapse_duplicate_obs(&self, how: AggOp) -> anyhow::Result<IMAnnData> {
+        let names = self.obs_names();
+        let mut groups: IndexMap<&str, Vec<usize>> = IndexMap::new();
+        for (i, name) in names.iter().enumerate() {
+            groups.entry(name.as_str()).or_default().push(i);
+        }
+        let first: Vec<usize> = groups.values().map(|members| members[0]).collect();
+        let collapsed = self.subset(&[
+            &SelectInfoElem::Index(first),
+            &SelectInfoElem::full(),
+        ])?;
+        collapsed.record("collapse_duplicate_obs", format!("{:?}", how));
+        if groups.len() == names.len() {
+            return Ok(collapsed);
+        }
+
+        let mut row_group = vec![0; names.len()];
+        for (g, members) in groups.values().enumerate() {
+            members.iter().for_each(|&row| row_group[row] = g);
+        }
+        let sizes: Vec<usize> = groups.values().map(Vec::len).collect();
+        let aggregate = |element: &IMArrayElement| -> anyhow::Result<ArrayData> {
+            let csr = crate::utils::to_csr_f64(&element.get_data()?)?;
+            let mut coo = CooMatrix::new(sizes.len(), csr.ncols());
+            for (row, values) in csr.row_iter().enumerate() {
+                let g = row_group[row];
+                let scale = match how {
+                    AggOp::Sum => 1.0,
+                    AggOp::Mean => 1.0 / sizes[g] as f64,
+                };
+                for (&col, &value) in values.col_indices().iter().zip(values.values()) {
+                    coo.push(g, col, value * scale);
+                }
+            }
+            Ok(ArrayData::CsrMatrix(DynCsrMatrix::from(CsrMatrix::from(&coo))))
+        };
+
+        collapsed.x.set_data(aggregate(&self.x)?)?;
+        for key in self.layers.keys() {
+            let data = aggregate(&self.layers.get_array_shallow(&key)?)?;
+            collapsed.layers.get_array_shallow(&key)?.set_data(data)?;
+        }
+        Ok(collapsed)
+    }
+
+    /// Groups observation positions by the values of an obs column, in order of first appearance.
+    ///
+    /// Missing values are grouped under `None`, separately from a category named `null`.
+    pub(crate) fn obs_groups(
+        &self,
+        column: &str,
+    ) -> anyhow::Result<IndexMap<Option<String>, Vec<usize>>> {
+        let labels = self
+            .obs
+            .get_column_from_df(column)?
+            .cast(&polars::datatypes::DataType::String)?;
+        let mut groups: IndexMap<Option<String>, Vec<usize>> = IndexMap::new();
+        for (i, label) in labels.str()?.into_iter().enumerate() {
+            groups
+                .entry(label.map(str::to_string))
+                .or_default()
+                .push(i);
+        }
+        Ok(groups)
+    }
+}
+
+/// Weighted means and centered sums of squares of the columns of a matrix.
+struct ColumnMoments {
+    /// Total weight of the rows entering each column.
+    totals: Vec<f64>,
+    means: Vec<f64>,
+    /// Weighted sums of squared deviations from the means.
+    sum_sq: Vec<f64>,
+}
+
+impl ColumnMoments {
+    /// Returns whether column `j` varies by no more than the rounding error of its mean.
+    fn is_constant(&self, j: usize) -> bool {
+        self.sum_sq[j] <= self.totals[j] * (CONSTANT_TOLERANCE * self.means[j]).powi(2)
+    }
+
+    /// Returns the population variance of every column, zero for constant columns.
+    fn variances(&self) -> Vec<f64> {
+        (0..self.means.len())
+            .map(|j| {
+                if self.is_constant(j) {
+                    0.0
+                } else {
+                    self.sum_sq[j] / self.totals[j]
+                }
+            })
+            .collect()
+    }
+}
+
+/// Computes the weighted means and centered sums of squares of the columns of `csr`.
+///
+/// `rows` yields the positions of the rows to include together with their weights, implicit
+/// zeros count as values. The squared deviations are summed around the means in a second
+/// pass, which unlike `sum(x^2) - n * mean^2` does not cancel when the mean is large
+/// compared to the spread. With [`NanPolicy::Skip`] the weights of the rows holding a NaN
+/// are removed from the total of that column.
+fn column_moments<I>(csr: &CsrMatrix<f64>, rows: I, nan_policy: NanPolicy) -> ColumnMoments
+where
+    I: Iterator<Item = (usize, f64)> + Clone,
+{
+    let n_vars = csr.ncols();
+    let (mut sums, mut stored) = (vec![0.0; n_vars], vec![0.0; n_vars]);
+    let mut skipped = vec![0.0; n_vars];
+    let mut total = 0.0;
+    for (row, w) in rows.clone() {
+        total += w;
+        let row = csr.row(row);
+        for (&col, &value) in row.col_indices().iter().zip(row.values()) {
+            if value.is_nan() && nan_policy == NanPolicy::Skip {
+                skipped[col] += w;
+                continue;
+            }
+            sums[col] += w * value;
+            stored[col] += w;
+        }
+    }
+    let totals: Vec<f64> = skipped.iter().map(|s| total - s).collect();
+    let means: Vec<f64> = sums
+        .iter()
+        .zip(&totals)
+        .map(|(&sum, &t)| if t > 0.0 { sum / t } else { f64::NAN })
+        .collect();
+    let mut sum_sq = vec![0.0; n_vars];
+    for (row, w) in rows {
+        let row = csr.row(row);
+        for (&col, &value) in row.col_indices().iter().zip(row.values()) {
+            if value.is_nan() && nan_policy == NanPolicy::Skip {
+                continue;
+            }
+            sum_sq[col] += w * (value - means[col]).powi(2);
+        }
+    }
+    for j in 0..n_vars {
+        // Implicit zeros deviate by the mean itself
+        let implicit = totals[j] - stored[j];
+        if implicit > 0.0 {
+            sum_sq[j] += implicit * means[j] * means[j];
+        }
+    }
+    ColumnMoments {
+        totals,
+        means,
+        sum_sq,
+    }
+}
+
+/// Checks that there is one finite, non-negative weight per observation and that the weights
+/// do not sum to zero.
+fn validate_weights(weights: &[f64], n_obs: usize) -> anyhow::Result<()> {
+    if weights.len() != n_obs {
+        return Err(anyhow::anyhow!(
+            "Weights have length {}, expected {}",
+            weights.len(),
+            n_obs
+        ));
+    }
+    if let Some((i, w)) = weights
+        .iter()
+        .enumerate()
+        .find(|(_, w)| !w.is_finite() || **w < 0.0)
+    {
+        return Err(anyhow::anyhow!(
+            "Weight {} of observation {} is not a finite non-negative number",
+            w,
+            i
+        ));
+    }
+    if weights.iter().sum::<f64>() <= 0.0 {
+        return Err(anyhow::anyhow!("Weights sum to zero"));
+    }
+    Ok(())
+}
+
+/// Computes the `q`-quantile of a row or column of length `len` from its stored values, the
+/// remaining `len - stored.len()` entries being implicit zeros.
+fn lane_quantile(stored: &[f64], len: usize, q: f64) -> f64 {
+    if len == 0 {
+        return f64::NAN;
+    }
+    let mut sorted = stored.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let n_negative = sorted.partition_point(|&v| v < 0.0);
+    let n_implicit = len - sorted.len();
+    // Value at rank `k` of the full lane: negatives, implicit zeros, then the rest
+    let at = |k: usize| {
+        if k < n_negative {
+            sorted[k]
+        } else if k < n_negative + n_implicit {
+            0.0
+        } else {
+            sorted[k - n_implicit]
+        }
+    };
+    let position = q * (len - 1) as f64;
+    let (lower, upper) = (position.floor() as usize, position.ceil() as usize);
+    let (low, high) = (at(lower), at(upper));
+    if lower == upper {
+        low
+    } else {
+        low + (position - lower as f64) * (high - low)
+    }
+}
@@ -0,0 +1,622 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anndata::{
+    container::{Axis, Dim},
+    data::{DataFrameIndex, DynArray, DynCscMatrix, DynCsrMatrix, DynScalar},
+    ArrayData, Data,
+};
+use nalgebra_sparse::{CscMatrix, CsrMatrix};
+use ndarray::{ArrayD, IxDyn};
+use polars::{
+    datatypes::{CategoricalOrdering, DataType},
+    frame::DataFrame,
+    prelude::NamedFrom,
+    series::Series,
+};
+
+use super::helpers::with_category_order;
+use crate::{
+    base::RwSlot, utils::dispatch_numeric, Element, IMAnnData, IMArrayElement, IMAxisArrays,
+    IMDataFrameElement, IMElementCollection,
+};
+
+/// Magic bytes at the start of every cache file.
+const CACHE_MAGIC: &[u8; 8] = b"IMADCACH";
+/// Version of the cache layout, bumped on every incompatible change.
+const CACHE_VERSION: u32 = 1;
+
+const LAYOUT_DENSE: u8 = 0;
+const LAYOUT_CSR: u8 = 1;
+const LAYOUT_CSC: u8 = 2;
+
+const CODE_BOOL: u8 = 10;
+const CODE_STRING: u8 = 11;
+const CODE_CATEGORICAL: u8 = 12;
+
+const UNS_ARRAY: u8 = 0;
+const UNS_SCALAR: u8 = 1;
+
+impl IMAnnData {
+    /// Saves the object to a crate-specific binary cache file for fast reloading.
+    ///
+    /// The file starts with a magic header and the layout version, followed by one
+    /// length-prefixed section per component (obs, var, X, layers, obsm, obsp, varm, varp,
+    /// uns). It is meant for checkpoints between runs of the same crate version, use
+    /// `write_h5ad` for exchanging data.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be written or a component holds data the cache
+    /// layout does not support, such as sparse boolean matrices, list columns or nested
+    /// mappings in uns.
+    pub fn save_cache(&self, path: &Path) -> anyhow::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(CACHE_MAGIC)?;
+        out.write_all(&CACHE_VERSION.to_le_bytes())?;
+
+        let mut sections: Vec<Vec<u8>> = Vec::new();
+        for frame in [&self.obs, &self.var] {
+            let mut buf = Vec::new();
+            encode_frame(&mut buf, frame)?;
+            sections.push(buf);
+        }
+        let mut buf = Vec::new();
+        encode_array(&mut buf, &self.x.get_data()?)?;
+        sections.push(buf);
+        for arrays in [&self.layers, &self.obsm, &self.obsp, &self.varm, &self.varp] {
+            let mut buf = Vec::new();
+            encode_axis_arrays(&mut buf, arrays)?;
+            sections.push(buf);
+        }
+        let mut buf = Vec::new();
+        encode_uns(&mut buf, &self.uns)?;
+        sections.push(buf);
+
+        for section in sections {
+            out.write_all(&(section.len() as u64).to_le_bytes())?;
+            out.write_all(&section)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Loads an object written by [`IMAnnData::save_cache`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read, is not a cache file, was written with a
+    /// different layout version or is truncated.
+    pub fn load_cache(path: &Path) -> anyhow::Result<IMAnnData> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != CACHE_MAGIC {
+            return Err(anyhow::anyhow!("{} is not an IMAnnData cache file", path.display()));
+        }
+        let version = read_u32(&mut input)?;
+        if version != CACHE_VERSION {
+            return Err(anyhow::anyhow!(
+                "Cache file version {} is not supported, expected version {}",
+                version,
+                CACHE_VERSION
+            ));
+        }
+
+        let mut next_section = || -> anyhow::Result<Vec<u8>> {
+            let len = read_u64(&mut input)? as usize;
+            let mut section = vec![0u8; len];
+            input.read_exact(&mut section)?;
+            Ok(section)
+        };
+        let obs = decode_frame(&mut next_section()?.as_slice())?;
+        let var = decode_frame(&mut next_section()?.as_slice())?;
+        let x = decode_array(&mut next_section()?.as_slice())?;
+        let mut adata = IMAnnData::new(IMArrayElement::new(x), obs, var)?;
+        let (n_obs, n_vars) = (Dim::new(adata.n_obs()), Dim::new(adata.n_vars()));
+        let dims = [
+            (Axis::RowColumn, n_obs.clone(), Some(n_vars.clone())),
+            (Axis::Row, n_obs.clone(), None),
+            (Axis::Pairwise, n_obs, None),
+            (Axis::Row, n_vars.clone(), None),
+            (Axis::Pairwise, n_vars, None),
+        ];
+        let mut arrays = Vec::with_capacity(dims.len());
+        for (axis, dim1, dim2) in dims {
+            let target = IMAxisArrays::new(axis, dim1, dim2);
+            for (key, data) in decode_named_arrays(&mut next_section()?.as_slice())? {
+                target.add_array(key, IMArrayElement::new(data))?;
+            }
+            arrays.push(target);
+        }
+        let mut arrays = arrays.into_iter();
+        adata.layers = arrays.next().unwrap();
+        adata.obsm = arrays.next().unwrap();
+        adata.obsp = arrays.next().unwrap();
+        adata.varm = arrays.next().unwrap();
+        adata.varp = arrays.next().unwrap();
+        adata.uns = decode_uns(&mut next_section()?.as_slice())?;
+        Ok(adata)
+    }
+}
+
+/// Fixed-size numeric types stored little-endian in the cache.
+trait CacheScalar: Copy + Default {
+    const CODE: u8;
+    fn write_to(&self, out: &mut Vec<u8>);
+    fn read_from(input: &mut &[u8]) -> anyhow::Result<Self>;
+}
+
+macro_rules! impl_cache_scalar {
+    ($($t:ty => $code:expr),*) => {
+        $(
+            impl CacheScalar for $t {
+                const CODE: u8 = $code;
+
+                fn write_to(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn read_from(input: &mut &[u8]) -> anyhow::Result<Self> {
+                    let mut bytes = [0u8; std::mem::size_of::<$t>()];
+                    input.read_exact(&mut bytes)?;
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_cache_scalar!(
+    i8 => 0, i16 => 1, i32 => 2, i64 => 3, u8 => 4, u16 => 5, u32 => 6, u64 => 7,
+    f32 => 8, f64 => 9
+);
+
+/// Calls `$body` with `$t` bound to the numeric type of a cache type code.
+macro_rules! with_numeric_code {
+    ($code:expr, $t:ident => $body:expr, $fallback:expr) => {
+        match $code {
+            0 => { type $t = i8; $body }
+            1 => { type $t = i16; $body }
+            2 => { type $t = i32; $body }
+            3 => { type $t = i64; $body }
+            4 => { type $t = u8; $body }
+            5 => { type $t = u16; $body }
+            6 => { type $t = u32; $body }
+            7 => { type $t = u64; $body }
+            8 => { type $t = f32; $body }
+            9 => { type $t = f64; $body }
+            _ => $fallback,
+        }
+    };
+}
+
+fn write_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    write_u64(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn write_values<T: CacheScalar>(out: &mut Vec<u8>, values: &[T]) {
+    write_u64(out, values.len() as u64);
+    values.iter().for_each(|v| v.write_to(out));
+}
+
+fn write_usizes(out: &mut Vec<u8>, values: &[usize]) {
+    write_u64(out, values.len() as u64);
+    values.iter().for_each(|&v| write_u64(out, v as u64));
+}
+
+fn read_u8<R: Read>(input: &mut R) -> anyhow::Result<u8> {
+    let mut bytes = [0u8; 1];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32<R: Read>(input: &mut R) -> anyhow::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> anyhow::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Reads a length that must fit in the remaining input, guarding against corrupt files.
+fn read_len(input: &mut &[u8], element_size: usize) -> anyhow::Result<usize> {
+    let len = read_u64(input)? as usize;
+    if len.saturating_mul(element_size) > input.len() {
+        return Err(anyhow::anyhow!("Cache file is truncated or corrupt"));
+    }
+    Ok(len)
+}
+
+fn read_str(input: &mut &[u8]) -> anyhow::Result<String> {
+    let len = read_len(input, 1)?;
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
+fn read_values<T: CacheScalar>(input: &mut &[u8]) -> anyhow::Result<Vec<T>> {
+    let len = read_len(input, std::mem::size_of::<T>())?;
+    (0..len).map(|_| T::read_from(input)).collect()
+}
+
+fn read_usizes(input: &mut &[u8]) -> anyhow::Result<Vec<usize>> {
+    let len = read_len(input, 8)?;
+    (0..len).map(|_| Ok(read_u64(input)? as usize)).collect()
+}
+
+fn encode_array(out: &mut Vec<u8>, data: &ArrayData) -> anyhow::Result<()> {
+    fn dense<T: CacheScalar>(out: &mut Vec<u8>, arr: &ArrayD<T>) {
+        out.extend_from_slice(&[LAYOUT_DENSE, T::CODE]);
+        write_usizes(out, arr.shape());
+        write_values(out, &arr.iter().copied().collect::<Vec<_>>());
+    }
+    fn compressed<T: CacheScalar>(
+        out: &mut Vec<u8>,
+        layout: u8,
+        shape: [usize; 2],
+        offsets: &[usize],
+        indices: &[usize],
+        values: &[T],
+    ) {
+        out.extend_from_slice(&[layout, T::CODE]);
+        write_usizes(out, &shape);
+        write_usizes(out, offsets);
+        write_usizes(out, indices);
+        write_values(out, values);
+    }
+
+    match data {
+        ArrayData::Array(DynArray::Bool(arr)) => {
+            out.extend_from_slice(&[LAYOUT_DENSE, CODE_BOOL]);
+            write_usizes(out, arr.shape());
+            write_values(out, &arr.iter().map(|&v| v as u8).collect::<Vec<_>>());
+        }
+        ArrayData::Array(DynArray::String(arr)) => {
+            out.extend_from_slice(&[LAYOUT_DENSE, CODE_STRING]);
+            write_usizes(out, arr.shape());
+            arr.iter().for_each(|v| write_str(out, v));
+        }
+        ArrayData::Array(arr) => dispatch_numeric!(
+            arr,
+            DynArray,
+            a => dense(out, a),
+            return Err(anyhow::anyhow!("Dense array type is not supported by the cache"))
+        ),
+        ArrayData::CsrMatrix(csr) => dispatch_numeric!(
+            csr,
+            DynCsrMatrix,
+            m => compressed(
+                out,
+                LAYOUT_CSR,
+                [m.nrows(), m.ncols()],
+                m.row_offsets(),
+                m.col_indices(),
+                m.values(),
+            ),
+            return Err(anyhow::anyhow!("CSR matrix type is not supported by the cache"))
+        ),
+        ArrayData::CscMatrix(csc) => dispatch_numeric!(
+            csc,
+            DynCscMatrix,
+            m => compressed(
+                out,
+                LAYOUT_CSC,
+                [m.nrows(), m.ncols()],
+                m.col_offsets(),
+                m.row_indices(),
+                m.values(),
+            ),
+            return Err(anyhow::anyhow!("CSC matrix type is not supported by the cache"))
+        ),
+        _ => return Err(anyhow::anyhow!("Array type is not supported by the cache")),
+    }
+    Ok(())
+}
+
+fn decode_array(input: &mut &[u8]) -> anyhow::Result<ArrayData> {
+    let layout = read_u8(input)?;
+    let code = read_u8(input)?;
+    let shape = read_usizes(input)?;
+    match layout {
+        LAYOUT_DENSE => match code {
+            CODE_BOOL => {
+                let values = read_values::<u8>(input)?.into_iter().map(|v| v != 0).collect();
+                Ok(ArrayData::Array(DynArray::from(ArrayD::from_shape_vec(
+                    IxDyn(&shape),
+                    values,
+                )?)))
+            }
+            CODE_STRING => {
+                let n: usize = shape.iter().product();
+                let values = (0..n).map(|_| read_str(input)).collect::<anyhow::Result<Vec<_>>>()?;
+                Ok(ArrayData::Array(DynArray::from(ArrayD::from_shape_vec(
+                    IxDyn(&shape),
+                    values,
+                )?)))
+            }
+            _ => with_numeric_code!(
+                code,
+                T => {
+                    let values = read_values::<T>(input)?;
+                    Ok(ArrayData::Array(DynArray::from(ArrayD::from_shape_vec(
+                        IxDyn(&shape),
+                        values,
+                    )?)))
+                },
+                Err(anyhow::anyhow!("Unknown array type code {} in cache", code))
+            ),
+        },
+        LAYOUT_CSR | LAYOUT_CSC => {
+            if shape.len() != 2 {
+                return Err(anyhow::anyhow!("Sparse matrix in cache is not 2-dimensional"));
+            }
+            let offsets = read_usizes(input)?;
+            let indices = read_usizes(input)?;
+            with_numeric_code!(
+                code,
+                T => {
+                    let values = read_values::<T>(input)?;
+                    if layout == LAYOUT_CSR {
+                        let m = CsrMatrix::try_from_csr_data(shape[0], shape[1], offsets, indices, values)?;
+                        Ok(ArrayData::CsrMatrix(DynCsrMatrix::from(m)))
+                    } else {
+                        let m = CscMatrix::try_from_csc_data(shape[0], shape[1], offsets, indices, values)?;
+                        Ok(ArrayData::CscMatrix(DynCscMatrix::from(m)))
+                    }
+                },
+                Err(anyhow::anyhow!("Unknown sparse matrix type code {} in cache", code))
+            )
+        }
+        _ => Err(anyhow::anyhow!("Unknown array layout {} in cache", layout)),
+    }
+}
+
+fn encode_frame(out: &mut Vec<u8>, frame: &IMDataFrameElement) -> anyhow::Result<()> {
+    fn column<T: CacheScalar>(out: &mut Vec<u8>, code: u8, values: impl Iterator<Item = Option<T>>) {
+        out.push(code);
+        let (validity, values): (Vec<u8>, Vec<T>) = values
+            .map(|v| (v.is_some() as u8, v.unwrap_or_default()))
+            .unzip();
+        write_values(out, &validity);
+        write_values(out, &values);
+    }
+    fn strings<'a>(out: &mut Vec<u8>, values: impl Iterator<Item = Option<&'a str>>) {
+        let values: Vec<Option<&str>> = values.collect();
+        write_values(out, &values.iter().map(|v| v.is_some() as u8).collect::<Vec<_>>());
+        values.iter().for_each(|v| write_str(out, v.unwrap_or_default()));
+    }
+
+    let index = frame.get_index().into_vec();
+    write_u64(out, index.len() as u64);
+    index.iter().for_each(|name| write_str(out, name));
+    let df = frame.get_data();
+    write_u64(out, df.width() as u64);
+    for series in df.get_columns() {
+        write_str(out, series.name());
+        match series.dtype() {
+            DataType::Int8 => column(out, i8::CODE, series.i8()?.into_iter()),
+            DataType::Int16 => column(out, i16::CODE, series.i16()?.into_iter()),
+            DataType::Int32 => column(out, i32::CODE, series.i32()?.into_iter()),
+            DataType::Int64 => column(out, i64::CODE, series.i64()?.into_iter()),
+            DataType::UInt8 => column(out, u8::CODE, series.u8()?.into_iter()),
+            DataType::UInt16 => column(out, u16::CODE, series.u16()?.into_iter()),
+            DataType::UInt32 => column(out, u32::CODE, series.u32()?.into_iter()),
+            DataType::UInt64 => column(out, u64::CODE, series.u64()?.into_iter()),
+            DataType::Float32 => column(out, f32::CODE, series.f32()?.into_iter()),
+            DataType::Float64 => column(out, f64::CODE, series.f64()?.into_iter()),
+            DataType::Boolean => column(
+                out,
+                CODE_BOOL,
+                series.bool()?.into_iter().map(|v| v.map(u8::from)),
+            ),
+            DataType::String => {
+                out.push(CODE_STRING);
+                strings(out, series.str()?.into_iter());
+            }
+            DataType::Categorical(_, ordering) => {
+                out.push(CODE_CATEGORICAL);
+                out.push((*ordering == CategoricalOrdering::Lexical) as u8);
+                let categorical = series.categorical()?;
+                let categories: Vec<&str> =
+                    categorical.get_rev_map().get_categories().values_iter().collect();
+                write_u64(out, categories.len() as u64);
+                categories.iter().for_each(|c| write_str(out, c));
+                strings(out, categorical.iter_str());
+            }
+            dtype => {
+                return Err(anyhow::anyhow!(
+                    "Column {} of type {} is not supported by the cache",
+                    series.name(),
+                    dtype
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decode_frame(input: &mut &[u8]) -> anyhow::Result<IMDataFrameElement> {
+    fn column<T: CacheScalar>(input: &mut &[u8]) -> anyhow::Result<Vec<Option<T>>> {
+        let validity = read_values::<u8>(input)?;
+        let values = read_values::<T>(input)?;
+        Ok(validity
+            .into_iter()
+            .zip(values)
+            .map(|(valid, v)| (valid != 0).then_some(v))
+            .collect())
+    }
+    fn strings(input: &mut &[u8]) -> anyhow::Result<Vec<Option<String>>> {
+        let validity = read_values::<u8>(input)?;
+        validity
+            .into_iter()
+            .map(|valid| Ok((valid != 0).then_some(read_str(input)?)))
+            .collect()
+    }
+
+    let index_len = read_len(input, 8)?;
+    let index = (0..index_len)
+        .map(|_| read_str(input))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let n_columns = read_len(input, 1)?;
+    let mut columns = Vec::with_capacity(n_columns);
+    for _ in 0..n_columns {
+        let name = read_str(input)?;
+        let code = read_u8(input)?;
+        let series = match code {
+            CODE_BOOL => {
+                let values: Vec<Option<bool>> = column::<u8>(input)?
+                    .into_iter()
+                    .map(|v| v.map(|v| v != 0))
+                    .collect();
+                Series::new(&name, values)
+            }
+            CODE_STRING => Series::new(&name, strings(input)?),
+            CODE_CATEGORICAL => {
+                let ordering = if read_u8(input)? == 1 {
+                    CategoricalOrdering::Lexical
+                } else {
+                    CategoricalOrdering::Physical
+                };
+                let n_categories = read_len(input, 8)?;
+                let categories = (0..n_categories)
+                    .map(|_| read_str(input))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let values = Series::new(&name, strings(input)?);
+                with_category_order(&values, &categories, ordering)?
+            }
+            _ => with_numeric_code!(
+                code,
+                T => Series::new(&name, column::<T>(input)?),
+                return Err(anyhow::anyhow!("Unknown column type code {} in cache", code))
+            ),
+        };
+        columns.push(series);
+    }
+    let df = DataFrame::new(columns)?;
+    if df.width() > 0 && df.height() != index.len() {
+        return Err(anyhow::anyhow!("Cached frame does not match the length of its index"));
+    }
+    Ok(IMDataFrameElement::new(df, DataFrameIndex::from(index)))
+}
+
+fn encode_axis_arrays(out: &mut Vec<u8>, arrays: &IMAxisArrays) -> anyhow::Result<()> {
+    let mut keys = arrays.keys();
+    keys.sort();
+    write_u64(out, keys.len() as u64);
+    for key in keys {
+        write_str(out, &key);
+        encode_array(out, &arrays.get_array_shallow(&key)?.get_data()?)?;
+    }
+    Ok(())
+}
+
+fn decode_named_arrays(input: &mut &[u8]) -> anyhow::Result<Vec<(String, ArrayData)>> {
+    let n = read_len(input, 8)?;
+    (0..n)
+        .map(|_| Ok((read_str(input)?, decode_array(input)?)))
+        .collect()
+}
+
+fn encode_uns(out: &mut Vec<u8>, uns: &IMElementCollection) -> anyhow::Result<()> {
+    let entries: Vec<(String, Data)> = {
+        let read_guard = uns.0.read_inner();
+        let mut entries = read_guard
+            .iter()
+            .map(|(key, element)| Ok((key.clone(), element.get_data()?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    };
+    write_u64(out, entries.len() as u64);
+    for (key, data) in entries {
+        write_str(out, &key);
+        match data {
+            Data::ArrayData(array) => {
+                out.push(UNS_ARRAY);
+                encode_array(out, &array)?;
+            }
+            Data::Scalar(scalar) => {
+                out.push(UNS_SCALAR);
+                encode_scalar(out, &scalar)?;
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "uns entry {} is not supported by the cache, only arrays and scalars are",
+                    key
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn encode_scalar(out: &mut Vec<u8>, scalar: &DynScalar) -> anyhow::Result<()> {
+    fn value<T: CacheScalar>(out: &mut Vec<u8>, v: T) {
+        out.push(T::CODE);
+        v.write_to(out);
+    }
+    match scalar {
+        DynScalar::I8(v) => value(out, *v),
+        DynScalar::I16(v) => value(out, *v),
+        DynScalar::I32(v) => value(out, *v),
+        DynScalar::I64(v) => value(out, *v),
+        DynScalar::U8(v) => value(out, *v),
+        DynScalar::U16(v) => value(out, *v),
+        DynScalar::U32(v) => value(out, *v),
+        DynScalar::U64(v) => value(out, *v),
+        DynScalar::F32(v) => value(out, *v),
+        DynScalar::F64(v) => value(out, *v),
+        DynScalar::Bool(v) => {
+            out.push(CODE_BOOL);
+            out.push(*v as u8);
+        }
+        DynScalar::String(v) => {
+            out.push(CODE_STRING);
+            write_str(out, v);
+        }
+        #[allow(unreachable_patterns)]
+        _ => return Err(anyhow::anyhow!("Scalar type is not supported by the cache")),
+    }
+    Ok(())
+}
+
+fn decode_uns(input: &mut &[u8]) -> anyhow::Result<IMElementCollection> {
+    let n = read_len(input, 8)?;
+    let mut entries = HashMap::with_capacity(n);
+    for _ in 0..n {
+        let key = read_str(input)?;
+        let data = match read_u8(input)? {
+            UNS_ARRAY => Data::ArrayData(decode_array(input)?),
+            UNS_SCALAR => {
+                let code = read_u8(input)?;
+                let scalar = match code {
+                    CODE_BOOL => DynScalar::Bool(read_u8(input)? != 0),
+                    CODE_STRING => DynScalar::String(read_str(input)?),
+                    _ => with_numeric_code!(
+                        code,
+                        T => DynScalar::from(T::read_from(input)?),
+                        return Err(anyhow::anyhow!("Unknown scalar type code {} in cache", code))
+                    ),
+                };
+                Data::Scalar(scalar)
+            }
+            tag => return Err(anyhow::anyhow!("Unknown uns entry tag {} in cache", tag)),
+        };
+        entries.insert(key, Element::new(data));
+    }
+    Ok(IMElementCollection(RwSlot::new(entries)))
+}
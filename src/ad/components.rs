@@ -0,0 +1,89 @@
+use std::ops::{BitOr, BitOrAssign};
+
+use crate::{base::DeepClone, IMAnnData};
+
+/// A set of `IMAnnData` components, combined with `|`.
+///
+/// # Example
+///
+/// ```ignore
+/// let copy = adata.deep_clone_components(ComponentSet::X | ComponentSet::OBS);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ComponentSet(u16);
+
+impl ComponentSet {
+    pub const NONE: ComponentSet = ComponentSet(0);
+    pub const X: ComponentSet = ComponentSet(1 << 0);
+    pub const OBS: ComponentSet = ComponentSet(1 << 1);
+    pub const VAR: ComponentSet = ComponentSet(1 << 2);
+    pub const OBSM: ComponentSet = ComponentSet(1 << 3);
+    pub const OBSP: ComponentSet = ComponentSet(1 << 4);
+    pub const VARM: ComponentSet = ComponentSet(1 << 5);
+    pub const VARP: ComponentSet = ComponentSet(1 << 6);
+    pub const UNS: ComponentSet = ComponentSet(1 << 7);
+    pub const LAYERS: ComponentSet = ComponentSet(1 << 8);
+    pub const ALL: ComponentSet = ComponentSet((1 << 9) - 1);
+
+    /// Returns `true` if every component of `other` is part of this set.
+    pub fn contains(self, other: ComponentSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for ComponentSet {
+    type Output = ComponentSet;
+
+    fn bitor(self, rhs: ComponentSet) -> ComponentSet {
+        ComponentSet(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for ComponentSet {
+    fn bitor_assign(&mut self, rhs: ComponentSet) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl IMAnnData {
+    /// Creates a copy in which only the selected components are independent.
+    ///
+    /// Components in `which` are deep-cloned, all others are shallow clones sharing their
+    /// storage with `self`. `deep_clone_components(ComponentSet::ALL)` is equivalent to
+    /// `deep_clone()`, with `ComponentSet::NONE` every component is shared.
+    ///
+    /// # Notes
+    ///
+    /// Shared components stay coupled in both directions: subsetting or mutating them through
+    /// either object affects the other. Subset the copy only after deep-cloning every
+    /// component whose size depends on the subset axis.
+    pub fn deep_clone_components(&self, which: ComponentSet) -> IMAnnData {
+        macro_rules! pick {
+            ($flag:ident, $field:ident) => {
+                if which.contains(ComponentSet::$flag) {
+                    self.$field.deep_clone()
+                } else {
+                    self.$field.clone()
+                }
+            };
+        }
+        IMAnnData {
+            n_obs: self.n_obs.clone(),
+            n_vars: self.n_vars.clone(),
+            x: pick!(X, x),
+            obs: pick!(OBS, obs),
+            obsm: pick!(OBSM, obsm),
+            obsp: pick!(OBSP, obsp),
+            var: pick!(VAR, var),
+            varm: pick!(VARM, varm),
+            varp: pick!(VARP, varp),
+            uns: pick!(UNS, uns),
+            layers: pick!(LAYERS, layers),
+            history: self.history.deep_clone(),
+        }
+    }
+}
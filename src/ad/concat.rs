@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+
+use anndata::{
+    container::{Axis, Dim},
+    data::DataFrameIndex,
+    ArrayData,
+};
+
+use crate::{base::DeepClone, IMAnnData, IMArrayElement, IMAxisArrays, IMDataFrameElement};
+
+/// How `IMAnnData::concat_obs_with_join` treats layers that are not present in every object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConcatJoin {
+    /// Every object must have the same set of layers.
+    #[default]
+    Inner,
+    /// The union of all layer keys is kept, missing pieces are filled with zeros.
+    Outer,
+}
+
+impl IMAnnData {
+    /// Concatenates objects along the observation axis.
+    ///
+    /// Equivalent to `concat_obs_with_join` with `ConcatJoin::Inner`.
+    pub fn concat_obs(adatas: &[&IMAnnData]) -> anyhow::Result<IMAnnData> {
+        Self::concat_obs_with_join(adatas, ConcatJoin::Inner)
+    }
+
+    /// Concatenates objects along the observation axis.
+    ///
+    /// # Arguments
+    ///
+    /// * `adatas` - Objects to concatenate, in order. All must share the same `var_names`.
+    /// * `join` - How layers missing from some of the objects are handled.
+    ///
+    /// # Returns
+    ///
+    /// A new object whose X and layers are the stacked matrices of the inputs. Obs keeps the
+    /// columns present in every object, obsm the keys present in every object. Var, varm,
+    /// varp and uns are taken from the first object, obsp is dropped.
+    ///
+    /// # Notes
+    ///
+    /// With `ConcatJoin::Outer`, a layer missing from an object is filled with an explicit
+    /// block of zeros using the storage format and type of the layer in another object. These
+    /// zeros are indistinguishable from measured zeros in the result.
+    ///
+    /// The histories of the inputs are not merged, the result's history only holds the
+    /// concatenation itself.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `adatas` is empty, the var names differ, the layer sets differ
+    /// under `ConcatJoin::Inner` or the matrices cannot be stacked.
+    pub fn concat_obs_with_join(
+        adatas: &[&IMAnnData],
+        join: ConcatJoin,
+    ) -> anyhow::Result<IMAnnData> {
+        let first = *adatas
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("No objects to concatenate"))?;
+        let var_names = first.var_names();
+        for (i, adata) in adatas.iter().enumerate().skip(1) {
+            if adata.var_names() != var_names {
+                return Err(anyhow::anyhow!(
+                    "Variable names of object {} differ from the first object",
+                    i
+                ));
+            }
+        }
+        let n_vars = first.n_vars();
+
+        let x = ArrayData::vstack(
+            adatas
+                .iter()
+                .map(|adata| adata.x.get_data())
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .into_iter(),
+        )?;
+
+        // Obs keeps the columns shared by every object, in the order of the first
+        let shared_columns: Vec<String> = first
+            .obs
+            .get_data()
+            .get_column_names()
+            .into_iter()
+            .filter(|name| {
+                adatas.iter().all(|adata| {
+                    adata
+                        .obs
+                        .get_data()
+                        .get_column_names()
+                        .contains(name)
+                })
+            })
+            .map(|name| name.to_string())
+            .collect();
+        let mut obs_df = first.obs.get_data().select(&shared_columns)?;
+        let mut obs_names = first.obs_names();
+        for adata in adatas.iter().skip(1) {
+            obs_df.vstack_mut(&adata.obs.get_data().select(&shared_columns)?)?;
+            obs_names.extend(adata.obs_names());
+        }
+        if obs_names.iter().collect::<HashSet<_>>().len() != obs_names.len() {
+            log::warn!("Concatenated observation names are not unique");
+        }
+        let obs_index: DataFrameIndex = obs_names.into();
+        let obs = IMDataFrameElement::new(obs_df, obs_index);
+
+        let mut result = IMAnnData::new(IMArrayElement::new(x), obs, first.var.deep_clone())?;
+        result.varm = first.varm.deep_clone();
+        result.varp = first.varp.deep_clone();
+        result.uns = first.uns.clone();
+
+        for key in layer_keys(adatas, join)? {
+            let template = adatas
+                .iter()
+                .find_map(|adata| adata.layers.get_array_shallow(&key).ok())
+                .ok_or_else(|| anyhow::anyhow!("Layer {} not found", key))?
+                .get_data()?;
+            let pieces = adatas
+                .iter()
+                .map(|adata| match adata.layers.get_array_shallow(&key) {
+                    Ok(layer) => layer.get_data(),
+                    Err(_) => crate::utils::zeros_like(&template, adata.n_obs(), n_vars),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            result.layers.add_array(
+                key,
+                IMArrayElement::new(ArrayData::vstack(pieces.into_iter())?),
+            )?;
+        }
+
+        let obsm = IMAxisArrays::new(Axis::Row, Dim::new(result.n_obs()), None);
+        for key in first.obsm.keys() {
+            if !adatas.iter().all(|adata| adata.obsm.keys().contains(&key)) {
+                continue;
+            }
+            let pieces = adatas
+                .iter()
+                .map(|adata| adata.obsm.get_array_shallow(&key)?.get_data())
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let stacked = ArrayData::vstack(pieces.into_iter())?;
+            obsm.add_array(key, IMArrayElement::new(stacked))?;
+        }
+        result.obsm = obsm;
+        result.record("concat_obs", format!("{} objects, {:?}", adatas.len(), join));
+        Ok(result)
+    }
+}
+
+/// Determines the layer keys of the concatenated object.
+fn layer_keys(adatas: &[&IMAnnData], join: ConcatJoin) -> anyhow::Result<Vec<String>> {
+    let mut keys: Vec<String> = Vec::new();
+    for adata in adatas {
+        for key in adata.layers.keys() {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    if join == ConcatJoin::Inner {
+        for (i, adata) in adatas.iter().enumerate() {
+            let own = adata.layers.keys();
+            let missing: Vec<&str> = keys
+                .iter()
+                .filter(|key| !own.contains(key))
+                .map(|key| key.as_str())
+                .collect();
+            if !missing.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Object {} is missing layers: {} (use ConcatJoin::Outer to fill them with zeros)",
+                    i,
+                    missing.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(keys)
+}
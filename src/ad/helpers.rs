@@ -1,23 +1,30 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt,
     ops::{Deref, DerefMut},
+    sync::Arc,
 };
 
 use anndata::{
-    backend::DataType,
+    backend::{DataType, ScalarType},
     container::{Axis, Dim},
-    data::{DataFrameIndex, SelectInfoElem, Shape},
+    data::{DataFrameIndex, DynArray, DynCscMatrix, DynCsrMatrix, SelectInfoElem, Shape},
     ArrayData, ArrayOp, Data, HasShape, WriteData,
 };
+use nalgebra_sparse::{CscMatrix, CsrMatrix};
+use ndarray::{Array2, Ix2};
+use num::{NumCast, ToPrimitive, Zero};
 use polars::{
     frame::DataFrame,
-    prelude::{IdxCa, NamedFrom},
+    prelude::{AnyValue, DataFrameJoinOps, IdxCa, IntoSeries, JoinArgs, JoinType, NamedFrom},
     series::Series,
 };
+use rand::seq::SliceRandom;
 
 use crate::base::DeepClone;
+use crate::error::DtypeMismatch;
 use crate::base::RwSlot;
+use crate::utils::dispatch_numeric;
 
 impl DeepClone for ArrayData {
     fn deep_clone(&self) -> Self {
@@ -25,11 +32,112 @@ impl DeepClone for ArrayData {
     }
 }
 
-pub struct IMArrayElement(pub RwSlot<ArrayData>);
+/// An array (X, a layer or an obsm/varm entry) held in a shared slot.
+///
+/// Besides the public slot the element carries a private cache of its row and column sums,
+/// so it can no longer be built as a tuple. Use [`IMArrayElement::new`] for fresh data or
+/// [`IMArrayElement::from_slot`] to wrap an existing slot.
+pub struct IMArrayElement(pub RwSlot<ArrayData>, Arc<parking_lot::Mutex<AxisSumCache>>);
+
+/// Lazily computed row and column sums of an array element, shared by its shallow clones.
+///
+/// The sums are valid for the write generation of the slot they were computed at, any write
+/// access to the data invalidates them.
+#[derive(Debug, Default)]
+struct AxisSumCache {
+    generation: u64,
+    rows: Option<Vec<f64>>,
+    cols: Option<Vec<f64>>,
+}
 
 impl IMArrayElement {
     pub fn new(data: ArrayData) -> Self {
-        IMArrayElement(RwSlot::new(data))
+        IMArrayElement::from_slot(RwSlot::new(data))
+    }
+
+    /// Wraps an existing slot, replacing the former tuple constructor `IMArrayElement(slot)`.
+    ///
+    /// The element starts with an empty sum cache, it is not shared with other elements
+    /// wrapping the same slot.
+    pub fn from_slot(slot: RwSlot<ArrayData>) -> Self {
+        IMArrayElement(slot, Arc::default())
+    }
+
+    /// Creates a new `IMArrayElement` for X or a layer, which must be 2-dimensional.
+    ///
+    /// Use [`IMArrayElement::new`] for arrays that may have other dimensionalities, such as
+    /// entries of obsm/varm.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `data` is not 2-dimensional.
+    pub fn new_checked(data: ArrayData) -> anyhow::Result<Self> {
+        let shape = data.shape();
+        if shape.ndim() != 2 {
+            return Err(anyhow::anyhow!(
+                "Expected a 2-dimensional array, found shape {:?}",
+                shape
+            ));
+        }
+        Ok(IMArrayElement::new(data))
+    }
+
+    /// Creates a new `IMArrayElement` like [`IMArrayElement::new_checked`] and additionally
+    /// checks the structure of CSR matrices with [`IMArrayElement::validate_csr`].
+    ///
+    /// Use this for matrices from external or untrusted sources. The check scans all stored
+    /// entries once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `data` is not 2-dimensional or is a CSR matrix with an invalid
+    /// structure.
+    pub fn new_validated(data: ArrayData) -> anyhow::Result<Self> {
+        let element = IMArrayElement::new_checked(data)?;
+        if matches!(element.0.read_inner().deref(), ArrayData::CsrMatrix(_)) {
+            element.validate_csr()?;
+        }
+        Ok(element)
+    }
+
+    /// Checks the internal structure of a CSR matrix.
+    ///
+    /// Matrices built through the checked `nalgebra_sparse` constructors are always valid,
+    /// this catches matrices assembled with the unchecked constructors from external or
+    /// corrupt data, which would silently produce wrong subsets.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error describing the first problem found: the row offsets do not start at
+    /// zero, decrease, or do not end at the number of stored values, a column index is not
+    /// below the number of columns, or the column indices of a row are not strictly
+    /// increasing. Also fails if the element is not a CSR matrix.
+    pub fn validate_csr(&self) -> anyhow::Result<()> {
+        let read_guard = self.0.read_inner();
+        let csr = match read_guard.deref() {
+            ArrayData::CsrMatrix(csr) => csr,
+            other => {
+                return Err(anyhow::anyhow!(
+                    "Expected a CSR matrix, found {:?}",
+                    other.data_type()
+                ))
+            }
+        };
+        macro_rules! check {
+            ($m:expr) => {
+                check_csr_structure($m.ncols(), $m.row_offsets(), $m.col_indices(), $m.values().len())
+            };
+        }
+        match csr {
+            DynCsrMatrix::Bool(m) => check!(m),
+            DynCsrMatrix::String(m) => check!(m),
+            other => dispatch_numeric!(
+                other,
+                DynCsrMatrix,
+                m => check!(m),
+                Err(anyhow::anyhow!("Unsupported CSR matrix type"))
+            ),
+        }
     }
 
     pub fn get_type(&self) -> anyhow::Result<DataType> {
@@ -41,7 +149,11 @@ impl IMArrayElement {
     }
 
     pub fn get_data(&self) -> anyhow::Result<ArrayData> {
-        Ok(self.0.read_inner().clone())
+        self.0
+            .lock_read()
+            .as_ref()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Array element is empty"))
     }
 
     pub fn set_data(&self, data: ArrayData) -> anyhow::Result<()> {
@@ -51,6 +163,38 @@ impl IMArrayElement {
         Ok(())
     }
 
+    /// Moves the data out of the element, leaving its slot empty.
+    ///
+    /// Use [`IMArrayElement::put`] to insert the (processed) data again. Until then the
+    /// element is empty: every shallow handle, and the object holding it, sees an empty slot,
+    /// `get_data` returns an error and methods that expect data may panic.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the slot is already empty.
+    pub fn take(&self) -> anyhow::Result<ArrayData> {
+        self.0
+            .extract()
+            .ok_or_else(|| anyhow::anyhow!("Array element is empty, its data was already taken"))
+    }
+
+    /// Inserts data into an element emptied by [`IMArrayElement::take`].
+    ///
+    /// The shape is not checked against the previous contents, the caller is responsible for
+    /// putting back data that fits the object holding the element.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the slot is occupied, the data is not inserted in that case.
+    pub fn put(&self, data: ArrayData) -> anyhow::Result<()> {
+        let mut write_guard = self.0.lock_write();
+        if write_guard.is_some() {
+            return Err(anyhow::anyhow!("Array element is occupied, take its data first"));
+        }
+        *write_guard = Some(data);
+        Ok(())
+    }
+
     pub fn subset_inplace(&self, s: &[&SelectInfoElem]) -> anyhow::Result<()> {
         let mut write_guard = self.0.write_inner();
         let d = write_guard.deref_mut();
@@ -72,25 +216,627 @@ impl IMArrayElement {
     pub fn deep_clone_content(&self) -> anyhow::Result<ArrayData> {
         Ok(self.0.read_inner().clone())
     }
+
+    /// Number of handles sharing the underlying data, including `self`.
+    pub fn share_count(&self) -> usize {
+        self.0.ref_count()
+    }
+
+    /// Converts the array into a dense `f64` matrix.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the array is not a numeric 2-dimensional array or the dense
+    /// matrix would exceed the dense size limit.
+    pub fn to_dense_f64(&self) -> anyhow::Result<Array2<f64>> {
+        self.to_dense::<f64>()
+    }
+
+    /// Converts the array into a dense `f32` matrix, halving the footprint of `to_dense_f64`.
+    ///
+    /// # Errors
+    ///
+    /// See [`IMArrayElement::to_dense_f64`].
+    pub fn to_dense_f32(&self) -> anyhow::Result<Array2<f32>> {
+        self.to_dense::<f32>()
+    }
+
+    /// Converts the array into a dense matrix of type `T`.
+    ///
+    /// Values are cast directly from the stored type, sparse matrices are never densified
+    /// to an intermediate type.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the array is not a numeric 2-dimensional array, the dense matrix
+    /// would exceed the dense size limit or a value cannot be represented as `T`.
+    pub fn to_dense<T: NumCast + Zero + Copy>(&self) -> anyhow::Result<Array2<T>> {
+        fn cast<T: NumCast, V: ToPrimitive + Copy>(v: V) -> anyhow::Result<T> {
+            T::from(v).ok_or_else(|| anyhow::anyhow!("Value cannot be represented in the target type"))
+        }
+
+        let read_guard = self.0.read_inner();
+        let data = read_guard.deref();
+        let shape = data.shape();
+        if shape.ndim() != 2 {
+            return Err(anyhow::anyhow!("Expected a 2-dimensional array, found {:?}", shape));
+        }
+        crate::utils::check_dense_size(shape[0], shape[1])?;
+        let mut out = Array2::<T>::zeros((shape[0], shape[1]));
+        match data {
+            ArrayData::Array(arr) => dispatch_numeric!(
+                arr,
+                DynArray,
+                a => {
+                    let a = a.view().into_dimensionality::<Ix2>()?;
+                    for (target, &v) in out.iter_mut().zip(a.iter()) {
+                        *target = cast(v)?;
+                    }
+                },
+                return Err(anyhow::anyhow!("Unsupported dense array type"))
+            ),
+            ArrayData::CsrMatrix(csr) => dispatch_numeric!(
+                csr,
+                DynCsrMatrix,
+                m => {
+                    for (i, row) in m.row_iter().enumerate() {
+                        for (&j, &v) in row.col_indices().iter().zip(row.values()) {
+                            out[[i, j]] = cast(v)?;
+                        }
+                    }
+                },
+                return Err(anyhow::anyhow!("Unsupported CSR matrix type"))
+            ),
+            ArrayData::CscMatrix(csc) => dispatch_numeric!(
+                csc,
+                DynCscMatrix,
+                m => {
+                    for (j, col) in m.col_iter().enumerate() {
+                        for (&i, &v) in col.row_indices().iter().zip(col.values()) {
+                            out[[i, j]] = cast(v)?;
+                        }
+                    }
+                },
+                return Err(anyhow::anyhow!("Unsupported CSC matrix type"))
+            ),
+            _ => return Err(anyhow::anyhow!("Unsupported array type for dense conversion")),
+        }
+        Ok(out)
+    }
+
+    /// Applies `f` to every stored value in place.
+    ///
+    /// For sparse matrices only the stored (nonzero) values are mapped, dense arrays map every
+    /// value. Results are cast back to the stored type, so integer arrays truncate.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the array is sparse and `f(0.0) != 0.0`, since the implicit zeros
+    /// would have to change as well, or if the array is not numeric. The data is unchanged
+    /// in that case.
+    pub fn apply_values_inplace<F>(&self, f: F) -> anyhow::Result<()>
+    where
+        F: Fn(f64) -> f64,
+    {
+        let mut write_guard = self.0.write_inner();
+        let is_sparse = matches!(
+            write_guard.deref(),
+            ArrayData::CsrMatrix(_) | ArrayData::CscMatrix(_)
+        );
+        if is_sparse && f(0.0) != 0.0 {
+            return Err(anyhow::anyhow!(
+                "Function does not map zero to zero and cannot be applied to a sparse matrix, \
+                 convert it to a dense array first"
+            ));
+        }
+        let found = format!("{:?}", write_guard.data_type());
+        let mismatch = || -> anyhow::Error {
+            DtypeMismatch {
+                operation: "Value mapping",
+                found: found.clone(),
+            }
+            .into()
+        };
+        match write_guard.deref_mut() {
+            ArrayData::Array(arr) => dispatch_numeric!(
+                arr,
+                DynArray,
+                a => a.iter_mut().for_each(|v| *v = f(*v as f64) as _),
+                return Err(mismatch())
+            ),
+            ArrayData::CsrMatrix(csr) => dispatch_numeric!(
+                csr,
+                DynCsrMatrix,
+                m => m.values_mut().iter_mut().for_each(|v| *v = f(*v as f64) as _),
+                return Err(mismatch())
+            ),
+            ArrayData::CscMatrix(csc) => dispatch_numeric!(
+                csc,
+                DynCscMatrix,
+                m => m.values_mut().iter_mut().for_each(|v| *v = f(*v as f64) as _),
+                return Err(mismatch())
+            ),
+            _ => return Err(mismatch()),
+        }
+        Ok(())
+    }
+
+    /// Multiplies every value by `factor` in place.
+    ///
+    /// Works on all numeric dense and sparse types, results are cast back to the stored type.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`DtypeMismatch`] if the array is not numeric.
+    pub fn scale_inplace(&self, factor: f64) -> anyhow::Result<()> {
+        self.apply_values_inplace(|v| v * factor)
+            .map_err(|e| rename_mismatch(e, "Scaling"))
+    }
+
+    /// Replaces every value by its square root in place.
+    ///
+    /// Zeros stay zero, so sparse matrices keep their sparsity. Results are cast back to the
+    /// stored type, so integer arrays truncate and should be converted to floats first.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`DtypeMismatch`] if the array is not numeric.
+    pub fn sqrt_inplace(&self) -> anyhow::Result<()> {
+        self.apply_values_inplace(f64::sqrt)
+            .map_err(|e| rename_mismatch(e, "Square root"))
+    }
+
+    /// Applies the Anscombe transform `2 * sqrt(x + 3/8)` to every value in place.
+    ///
+    /// # Notes
+    ///
+    /// The transform maps zero to `2 * sqrt(3/8)`, so every implicit zero of a sparse matrix
+    /// would have to be stored. Sparse input is rejected instead of being densified silently,
+    /// use [`IMArrayElement::sqrt_inplace`] to keep sparsity.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the array is sparse and a [`DtypeMismatch`] if it is not numeric.
+    pub fn anscombe_inplace(&self) -> anyhow::Result<()> {
+        self.apply_values_inplace(|v| 2.0 * (v + 3.0 / 8.0).sqrt())
+            .map_err(|e| rename_mismatch(e, "Anscombe transform"))
+    }
+
+    /// Returns the number of stored entries of a sparse matrix, or of nonzero values of a
+    /// dense array.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`DtypeMismatch`] if the array is not numeric.
+    pub fn nnz(&self) -> anyhow::Result<usize> {
+        data_nnz(self.0.read_inner().deref())
+    }
+
+    /// Returns `true` if both elements hold the same logical values.
+    ///
+    /// Unlike comparing the `ArrayData`, this ignores the storage: explicitly stored zeros
+    /// compare equal to implicit ones, and dense and sparse matrices with the same values are
+    /// equal. NaNs at the same position are considered equal. Non-numeric arrays are compared
+    /// exactly.
+    ///
+    /// # Notes
+    ///
+    /// Numeric values are compared as `f64`, so 64-bit integers beyond 2^53 can compare equal
+    /// to their neighbours.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either element is empty.
+    pub fn values_equal(&self, other: &IMArrayElement) -> anyhow::Result<bool> {
+        if self.0.ptr_eq(&other.0) {
+            return Ok(true);
+        }
+        let (a, b) = (self.get_data()?, other.get_data()?);
+        Ok(crate::utils::arrays_close(&a, &b, 0.0, 0.0))
+    }
+
+    /// Removes explicitly stored zeros from a sparse matrix.
+    ///
+    /// Operations that map stored values to zero leave them in the sparse structure, this
+    /// drops them. The buffers keep their previous capacity unless `shrink` is set, in which
+    /// case [`IMArrayElement::shrink_to_fit`] is applied afterwards. Dense arrays are left
+    /// unchanged.
+    ///
+    /// # Returns
+    ///
+    /// The number of removed entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`DtypeMismatch`] if the matrix is not numeric.
+    pub fn eliminate_zeros(&self, shrink: bool) -> anyhow::Result<usize> {
+        let removed = {
+            let mut write_guard = self.0.write_inner();
+            let found = format!("{:?}", write_guard.data_type());
+            let mismatch = || -> anyhow::Error {
+                DtypeMismatch {
+                    operation: "Eliminating zeros",
+                    found: found.clone(),
+                }
+                .into()
+            };
+            match write_guard.deref_mut() {
+                ArrayData::CsrMatrix(csr) => {
+                    dispatch_numeric!(csr, DynCsrMatrix, m => prune_csr(m)?, return Err(mismatch()))
+                }
+                ArrayData::CscMatrix(csc) => {
+                    dispatch_numeric!(csc, DynCscMatrix, m => prune_csc(m)?, return Err(mismatch()))
+                }
+                _ => 0,
+            }
+        };
+        if shrink {
+            self.shrink_to_fit()?;
+        }
+        Ok(removed)
+    }
+
+    /// Releases unused capacity of the index and value buffers of a sparse matrix.
+    ///
+    /// The buffers are copied into exactly sized ones, the matrix is only replaced once the
+    /// copy succeeded. Dense arrays are left unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`DtypeMismatch`] if the matrix is not numeric.
+    pub fn shrink_to_fit(&self) -> anyhow::Result<()> {
+        let mut write_guard = self.0.write_inner();
+        let found = format!("{:?}", write_guard.data_type());
+        let mismatch = || -> anyhow::Error {
+            DtypeMismatch {
+                operation: "Shrinking buffers",
+                found: found.clone(),
+            }
+            .into()
+        };
+        macro_rules! shrink {
+            ($m:expr, $matrix:ident) => {{
+                let values = $m.values().to_vec();
+                *$m = $matrix::try_from_pattern_and_values($m.pattern().clone(), values)?;
+            }};
+        }
+        match write_guard.deref_mut() {
+            ArrayData::CsrMatrix(csr) => dispatch_numeric!(
+                csr,
+                DynCsrMatrix,
+                m => shrink!(m, CsrMatrix),
+                return Err(mismatch())
+            ),
+            ArrayData::CscMatrix(csc) => dispatch_numeric!(
+                csc,
+                DynCscMatrix,
+                m => shrink!(m, CscMatrix),
+                return Err(mismatch())
+            ),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Returns how many entries the value buffer of a sparse matrix can hold without
+    /// reallocating, or `None` for dense arrays.
+    ///
+    /// `nalgebra_sparse` only hands out its buffers by value, so the matrix is split into its
+    /// pattern and values under the write lock and reassembled unchanged. This only moves the
+    /// buffers, nothing is copied or validated again, and cached sums stay valid.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`DtypeMismatch`] if the matrix is not numeric, or an error if the element
+    /// is empty.
+    pub fn sparse_capacity(&self) -> anyhow::Result<Option<usize>> {
+        let mut lock = self.0.lock_write_unchanged();
+        let data = lock
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Array element is empty"))?;
+        let found = format!("{:?}", data.data_type());
+        let mismatch = || -> anyhow::Error {
+            DtypeMismatch {
+                operation: "Reading capacity",
+                found: found.clone(),
+            }
+            .into()
+        };
+        macro_rules! capacity {
+            ($m:expr, $matrix:ident) => {{
+                let (nrows, ncols) = ($m.nrows(), $m.ncols());
+                let (pattern, values) =
+                    std::mem::replace($m, $matrix::zeros(nrows, ncols)).into_pattern_and_values();
+                let capacity = values.capacity();
+                // Only the number of values is checked, and it has not changed
+                *$m = $matrix::try_from_pattern_and_values(pattern, values)
+                    .expect("reassembling an unchanged matrix");
+                Some(capacity)
+            }};
+        }
+        Ok(match data {
+            ArrayData::CsrMatrix(csr) => dispatch_numeric!(
+                csr,
+                DynCsrMatrix,
+                m => capacity!(m, CsrMatrix),
+                return Err(mismatch())
+            ),
+            ArrayData::CscMatrix(csc) => dispatch_numeric!(
+                csc,
+                DynCscMatrix,
+                m => capacity!(m, CscMatrix),
+                return Err(mismatch())
+            ),
+            _ => None,
+        })
+    }
+
+    /// Returns the sum of every row of a 2-dimensional numeric array.
+    ///
+    /// The sums are computed on first use and cached. Any write access to the data, through
+    /// this element, a shallow clone or the raw slot, invalidates the cache.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the element is empty or not 2-dimensional, or a [`DtypeMismatch`]
+    /// if it is not numeric.
+    pub fn row_sums(&self) -> anyhow::Result<Vec<f64>> {
+        self.cached_sums(true)
+    }
+
+    /// Returns the sum of every column of a 2-dimensional numeric array.
+    ///
+    /// Cached like [`IMArrayElement::row_sums`].
+    ///
+    /// # Errors
+    ///
+    /// See [`IMArrayElement::row_sums`].
+    pub fn col_sums(&self) -> anyhow::Result<Vec<f64>> {
+        self.cached_sums(false)
+    }
+
+    fn cached_sums(&self, rows: bool) -> anyhow::Result<Vec<f64>> {
+        let read_guard = self.0.lock_read();
+        let data = read_guard
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Array element is empty"))?;
+        // Writers bump the generation while holding the write lock, so it is stable here
+        let generation = self.0.generation();
+        {
+            let cache = self.1.lock();
+            let cached = if rows { &cache.rows } else { &cache.cols };
+            if cache.generation == generation {
+                if let Some(sums) = cached {
+                    return Ok(sums.clone());
+                }
+            }
+        }
+        // Summed without holding the cache lock, so readers of the other axis or of other
+        // shallow clones are not held up. Concurrent readers may compute the same sums.
+        let sums = axis_sums(data, rows)?;
+        let mut cache = self.1.lock();
+        if cache.generation != generation {
+            *cache = AxisSumCache {
+                generation,
+                ..AxisSumCache::default()
+            };
+        }
+        let slot = if rows { &mut cache.rows } else { &mut cache.cols };
+        *slot = Some(sums.clone());
+        Ok(sums)
+    }
+
+    /// Computes summary statistics over all values, including the implicit zeros of sparse
+    /// matrices.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`DtypeMismatch`] if the array is not numeric.
+    pub fn stats(&self) -> anyhow::Result<ArrayStats> {
+        let read_guard = self.0.read_inner();
+        let data = read_guard.deref();
+        let mismatch = || -> anyhow::Error {
+            DtypeMismatch {
+                operation: "Computing statistics",
+                found: format!("{:?}", data.data_type()),
+            }
+            .into()
+        };
+        let shape = data.shape();
+        let total: usize = (0..shape.ndim()).map(|i| shape[i]).product();
+        let values: Vec<f64> = match data {
+            ArrayData::Array(arr) => dispatch_numeric!(
+                arr,
+                DynArray,
+                a => a.iter().map(|&v| v as f64).collect(),
+                return Err(mismatch())
+            ),
+            ArrayData::CsrMatrix(csr) => dispatch_numeric!(
+                csr,
+                DynCsrMatrix,
+                m => m.values().iter().map(|&v| v as f64).collect(),
+                return Err(mismatch())
+            ),
+            ArrayData::CscMatrix(csc) => dispatch_numeric!(
+                csc,
+                DynCscMatrix,
+                m => m.values().iter().map(|&v| v as f64).collect(),
+                return Err(mismatch())
+            ),
+            _ => return Err(mismatch()),
+        };
+        Ok(ArrayStats::from_values(&values, total))
+    }
+
+    /// Returns `true` if any stored value is NaN or infinite.
+    ///
+    /// Only the stored values are scanned, implicit zeros of sparse matrices are finite.
+    /// Integer arrays never contain non-finite values.
+    pub fn has_non_finite(&self) -> anyhow::Result<bool> {
+        Ok(self.scan_non_finite(true)? > 0)
+    }
+
+    /// Returns the number of stored values that are NaN or infinite.
+    pub fn count_non_finite(&self) -> anyhow::Result<usize> {
+        self.scan_non_finite(false)
+    }
+
+    fn scan_non_finite(&self, stop_at_first: bool) -> anyhow::Result<usize> {
+        macro_rules! scan {
+            ($values:expr) => {{
+                let mut values = $values;
+                if stop_at_first {
+                    values.any(|v| !v.is_finite()) as usize
+                } else {
+                    values.filter(|v| !v.is_finite()).count()
+                }
+            }};
+        }
+
+        let read_guard = self.0.read_inner();
+        let count = match read_guard.deref() {
+            ArrayData::Array(DynArray::F32(arr)) => scan!(arr.iter()),
+            ArrayData::Array(DynArray::F64(arr)) => scan!(arr.iter()),
+            ArrayData::CsrMatrix(DynCsrMatrix::F32(m)) => scan!(m.values().iter()),
+            ArrayData::CsrMatrix(DynCsrMatrix::F64(m)) => scan!(m.values().iter()),
+            ArrayData::CscMatrix(DynCscMatrix::F32(m)) => scan!(m.values().iter()),
+            ArrayData::CscMatrix(DynCscMatrix::F64(m)) => scan!(m.values().iter()),
+            ArrayData::DataFrame(_) => {
+                return Err(anyhow::anyhow!("Cannot scan a DataFrame for non-finite values"))
+            }
+            _ => 0,
+        };
+        Ok(count)
+    }
+}
+
+/// A layer together with its metadata, see [`crate::IMAnnData::layer_entry`].
+///
+/// The metadata is read under a single read lock and not updated afterwards.
+#[derive(Clone)]
+pub struct LayerEntry {
+    /// Shallow handle to the layer, sharing its storage.
+    pub element: IMArrayElement,
+    pub shape: Vec<usize>,
+    pub dtype: DataType,
+    /// Number of stored entries (sparse) or nonzero values (dense), `None` if not numeric.
+    pub nnz: Option<usize>,
+}
+
+impl LayerEntry {
+    /// Reads the metadata of `element` under one read lock.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the element is empty.
+    pub(crate) fn read(element: IMArrayElement) -> anyhow::Result<Self> {
+        let (shape, dtype, nnz) = {
+            let read_guard = element.0.lock_read();
+            let data = read_guard
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Array element is empty"))?;
+            let shape = data.shape();
+            (
+                (0..shape.ndim()).map(|i| shape[i]).collect(),
+                data.data_type(),
+                data_nnz(data).ok(),
+            )
+        };
+        Ok(LayerEntry {
+            element,
+            shape,
+            dtype,
+            nnz,
+        })
+    }
+}
+
+/// Summary statistics of a numeric array, see [`IMArrayElement::stats`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArrayStats {
+    pub sum: f64,
+    /// Mean over all values, NaN for empty arrays.
+    pub mean: f64,
+    /// Smallest value, NaN for empty arrays.
+    pub min: f64,
+    /// Largest value, NaN for empty arrays.
+    pub max: f64,
+}
+
+impl ArrayStats {
+    /// Computes the statistics of `total` values, of which all but `values` are zero.
+    fn from_values(values: &[f64], total: usize) -> Self {
+        if total == 0 {
+            return ArrayStats {
+                sum: 0.0,
+                mean: f64::NAN,
+                min: f64::NAN,
+                max: f64::NAN,
+            };
+        }
+        let sum: f64 = values.iter().sum();
+        // Sparse matrices hold fewer values than elements, the rest are zeros
+        let fold_start = if values.len() < total { 0.0 } else { values[0] };
+        ArrayStats {
+            sum,
+            mean: sum / total as f64,
+            min: values.iter().fold(fold_start, |acc, &v| acc.min(v)),
+            max: values.iter().fold(fold_start, |acc, &v| acc.max(v)),
+        }
+    }
 }
 
 impl DeepClone for IMArrayElement {
     fn deep_clone(&self) -> Self {
-        IMArrayElement(self.0.deep_clone())
+        IMArrayElement::from_slot(self.0.deep_clone())
     }
 }
 
 impl Clone for IMArrayElement {
     fn clone(&self) -> Self {
-        IMArrayElement(self.0.clone())
+        IMArrayElement(self.0.clone(), Arc::clone(&self.1))
     }
 }
 
 pub struct IMDataFrameElement(RwSlot<InnerIMDataFrame>);
 
+/// Function computing a derived column from the frame, see
+/// [`IMDataFrameElement::register_derived`].
+pub type DeriveFn = Arc<dyn Fn(&DataFrame) -> Series + Send + Sync>;
+
+/// Boolean operation combining several columns, see
+/// [`IMDataFrameElement::combine_bool_columns`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoolOp {
+    /// `true` where all inputs are `true`.
+    And,
+    /// `true` where any input is `true`.
+    Or,
+}
+
+/// Temporary column holding the index names while `apply_join` runs.
+const JOIN_KEY_COLUMN: &str = "__index_key";
+
 pub struct InnerIMDataFrame {
     df: DataFrame,
     pub index: DataFrameIndex,
+    /// Registered derived columns with their cached values.
+    derived: HashMap<String, (DeriveFn, Option<Series>)>,
+}
+
+impl InnerIMDataFrame {
+    fn new(df: DataFrame, index: DataFrameIndex) -> Self {
+        InnerIMDataFrame {
+            df,
+            index,
+            derived: HashMap::new(),
+        }
+    }
+
+    /// Drops the cached values of all derived columns after the frame changed.
+    fn invalidate_derived(&mut self) {
+        self.derived.values_mut().for_each(|(_, cache)| *cache = None);
+    }
 }
 
 impl DeepClone for InnerIMDataFrame {
@@ -104,6 +850,7 @@ impl Clone for InnerIMDataFrame {
         InnerIMDataFrame {
             df: self.df.clone(),
             index: self.index.clone(),
+            derived: self.derived.clone(),
         }
     }
 }
@@ -117,25 +864,69 @@ impl Clone for IMDataFrameElement {
 
 impl IMDataFrameElement {
     pub fn new(df: DataFrame, index: DataFrameIndex) -> Self {
-        if df.height() == 0 {
+        // Frames without columns carry no height, build one from the index instead
+        if df.width() == 0 {
             let tmp_df =
                 DataFrame::new(vec![Series::new("index", &index.clone().into_vec())]).unwrap();
-            return IMDataFrameElement(RwSlot::new(InnerIMDataFrame { df: tmp_df, index }));
+            return IMDataFrameElement(RwSlot::new(InnerIMDataFrame::new(tmp_df, index)));
         }
         if df.height() != index.len() {
             panic!("Length of index does not match length of DataFrame");
         }
-        IMDataFrameElement(RwSlot::new(InnerIMDataFrame { df, index }))
+        IMDataFrameElement(RwSlot::new(InnerIMDataFrame::new(df, index)))
     }
 
     pub fn get_data(&self) -> DataFrame {
         self.0.read_inner().df.clone()
     }
 
+    /// Runs `f` on the frame while holding the read lock, without cloning it.
+    ///
+    /// Use this instead of `get_data` for read-only computations on wide frames. Derived
+    /// columns are not part of the borrowed frame.
+    ///
+    /// # Notes
+    ///
+    /// `f` must not write to this frame, the write lock would deadlock.
+    pub fn with_df<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&DataFrame) -> R,
+    {
+        self.0.with_read(|inner| f(&inner.df))
+    }
+
+    /// Empties the slot, releasing the frame, see [`crate::IMAnnData::close`].
+    pub(crate) fn release(&self) {
+        self.0.drop();
+    }
+
     pub fn get_index(&self) -> DataFrameIndex {
         self.0.read_inner().index.clone()
     }
 
+    /// Returns the number of null values of every column, in column order.
+    ///
+    /// Only the column metadata is read under the lock, the frame is not cloned.
+    pub fn null_counts(&self) -> Vec<(String, usize)> {
+        self.0
+            .read_inner()
+            .df
+            .get_columns()
+            .iter()
+            .map(|series| (series.name().to_string(), series.null_count()))
+            .collect()
+    }
+
+    /// Returns the number of rows without cloning the frame.
+    pub fn height(&self) -> usize {
+        self.0.read_inner().df.height()
+    }
+
+    /// Returns the number of columns without cloning the frame.
+    pub fn width(&self) -> usize {
+        self.0.read_inner().df.width()
+    }
+
     pub fn set_both(&self, df: DataFrame, index: DataFrameIndex) -> anyhow::Result<()> {
         let mut write_guard = self.0.lock_write();
         let d = write_guard.as_mut();
@@ -149,6 +940,7 @@ impl IMDataFrameElement {
 
                 data.df = df;
                 data.index = index;
+                data.invalidate_derived();
                 Ok(())
             }
             None => Err(anyhow::anyhow!("DataFrame is not initialized")),
@@ -166,6 +958,7 @@ impl IMDataFrameElement {
                     ));
                 }
                 data.df = df;
+                data.invalidate_derived();
                 Ok(())
             }
             None => Err(anyhow::anyhow!("DataFrame is not initialized")),
@@ -190,6 +983,45 @@ impl IMDataFrameElement {
         }
     }
 
+    /// Renames index entries according to `mapping`, leaving unmapped names unchanged.
+    ///
+    /// The `index` column of the DataFrame, if present, is updated together with the index.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the renamed index contains duplicate names, nothing is changed in
+    /// that case.
+    pub fn rename_index(&self, mapping: &HashMap<String, String>) -> anyhow::Result<()> {
+        let mut write_guard = self.0.write_inner();
+        let names: Vec<String> = write_guard
+            .index
+            .clone()
+            .into_vec()
+            .into_iter()
+            .map(|name| mapping.get(&name).cloned().unwrap_or(name))
+            .collect();
+        let mut seen = std::collections::HashSet::new();
+        let duplicates: std::collections::BTreeSet<&str> = names
+            .iter()
+            .filter(|name| !seen.insert(name.as_str()))
+            .map(|name| name.as_str())
+            .collect();
+        if !duplicates.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Renaming introduces duplicate names: {}",
+                duplicates.into_iter().collect::<Vec<_>>().join(", ")
+            ));
+        }
+        if write_guard.df.column("index").is_ok() {
+            write_guard
+                .df
+                .replace("index", Series::new("index", &names))?;
+        }
+        write_guard.index = DataFrameIndex::from(names);
+        write_guard.invalidate_derived();
+        Ok(())
+    }
+
     pub fn attach_column_to_df(&self, column: Series) -> anyhow::Result<()> {
         let mut write_guard = self.0.lock_write();
         let d = write_guard.as_mut();
@@ -201,6 +1033,201 @@ impl IMDataFrameElement {
                     ));
                 }
                 data.df.with_column(column)?;
+                data.invalidate_derived();
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("DataFrame is not initialized")),
+        }
+    }
+
+    /// Combines several boolean columns into a new column.
+    ///
+    /// An input name prefixed with `!` is negated, e.g. `["low_mito", "high_genes",
+    /// "!doublet"]` with [`BoolOp::And`]. Nulls follow Kleene logic. An existing column named
+    /// `out` is replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `inputs` - Names of the boolean columns, optionally prefixed with `!`.
+    /// * `op` - Operation applied across the inputs.
+    /// * `out` - Name of the resulting column.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `inputs` is empty or an input is missing or not boolean.
+    pub fn combine_bool_columns(&self, inputs: &[&str], op: BoolOp, out: &str) -> anyhow::Result<()> {
+        let mut combined: Option<polars::prelude::BooleanChunked> = None;
+        for &input in inputs {
+            let (name, negate) = match input.strip_prefix('!') {
+                Some(name) => (name, true),
+                None => (input, false),
+            };
+            let series = self.get_column_from_df(name)?;
+            if series.dtype() != &polars::datatypes::DataType::Boolean {
+                return Err(anyhow::anyhow!(
+                    "Column {} must be boolean, found {}",
+                    name,
+                    series.dtype()
+                ));
+            }
+            let values = if negate { !series.bool()? } else { series.bool()?.clone() };
+            combined = Some(match (combined, op) {
+                (None, _) => values,
+                (Some(acc), BoolOp::And) => &acc & &values,
+                (Some(acc), BoolOp::Or) => &acc | &values,
+            });
+        }
+        let combined = combined.ok_or_else(|| anyhow::anyhow!("No input columns given"))?;
+        self.attach_column_to_df(combined.with_name(out).into_series())
+    }
+
+    /// Applies a join-like operation to the frame and verifies that it keeps the row order.
+    ///
+    /// Row `i` of obs or var must describe row `i` (or column `i`) of X, so an operation that
+    /// reorders rows corrupts the object. Before `f` runs, a temporary key column holding
+    /// the index names is added to the frame. Afterwards this column must still match the
+    /// stored index row by row. The key column is removed before the result is stored.
+    ///
+    /// # Arguments
+    ///
+    /// * `strict` - If `true`, any reordering is an error. Otherwise a result whose rows are a
+    ///   permutation of the index is put back in index order.
+    /// * `f` - The operation, it must keep the key column and produce one row per index entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `f` fails, drops the key column, adds or removes rows, or reorders
+    /// rows in strict mode. The frame is unchanged in that case.
+    pub fn apply_join<F>(&self, strict: bool, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(DataFrame) -> anyhow::Result<DataFrame>,
+    {
+        let mut write_guard = self.0.lock_write();
+        let data = write_guard
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("DataFrame is not initialized"))?;
+        let names = data.index.clone().into_vec();
+        if data.df.get_column_names().contains(&JOIN_KEY_COLUMN) {
+            return Err(anyhow::anyhow!(
+                "Column {} is reserved for joins",
+                JOIN_KEY_COLUMN
+            ));
+        }
+        let mut input = data.df.clone();
+        input.with_column(Series::new(JOIN_KEY_COLUMN, &names))?;
+        let mut joined = f(input)?;
+        let keys: Vec<Option<String>> = joined
+            .column(JOIN_KEY_COLUMN)
+            .map_err(|_| anyhow::anyhow!("Join dropped the index key column"))?
+            .cast(&polars::datatypes::DataType::String)?
+            .str()?
+            .into_iter()
+            .map(|key| key.map(str::to_string))
+            .collect();
+        if keys.len() != names.len() {
+            return Err(anyhow::anyhow!(
+                "Join changed the number of rows from {} to {}",
+                names.len(),
+                keys.len()
+            ));
+        }
+        let aligned = keys
+            .iter()
+            .zip(&names)
+            .all(|(key, name)| key.as_deref() == Some(name.as_str()));
+        if !aligned {
+            if strict {
+                let row = keys
+                    .iter()
+                    .zip(&names)
+                    .position(|(key, name)| key.as_deref() != Some(name.as_str()))
+                    .unwrap_or(0);
+                return Err(anyhow::anyhow!(
+                    "Join reordered rows: row {} holds {:?} but the index has {}",
+                    row,
+                    keys[row],
+                    names[row]
+                ));
+            }
+            let mut position: HashMap<&str, usize> = HashMap::with_capacity(keys.len());
+            for (i, key) in keys.iter().enumerate() {
+                if let Some(key) = key {
+                    position.insert(key.as_str(), i);
+                }
+            }
+            let order = names
+                .iter()
+                .map(|name| position.get(name.as_str()).map(|&i| i as u32))
+                .collect::<Option<Vec<u32>>>()
+                .filter(|_| position.len() == names.len())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Join result rows are not a permutation of the index")
+                })?;
+            joined = joined.take(&IdxCa::new("idx", &order))?;
+        }
+        joined.drop_in_place(JOIN_KEY_COLUMN)?;
+        data.df = joined;
+        data.invalidate_derived();
+        Ok(())
+    }
+
+    /// Left-joins `other` onto the frame, matching `other[on]` against the index names.
+    ///
+    /// Rows of the frame without a match get nulls in the new columns. Runs through
+    /// [`IMDataFrameElement::apply_join`], so the row order is verified afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - Frame with the columns to add.
+    /// * `on` - Column of `other` holding the index names.
+    /// * `strict` - Whether a reordering join is an error, see `apply_join`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `on` is missing, `other` has duplicate keys, or the join fails.
+    pub fn join_on_index(&self, other: &DataFrame, on: &str, strict: bool) -> anyhow::Result<()> {
+        let mut right = other.clone();
+        let key = right.column(on)?.cast(&polars::datatypes::DataType::String)?;
+        right.replace(on, key)?;
+        self.apply_join(strict, |df| {
+            Ok(df.join(
+                &right,
+                [JOIN_KEY_COLUMN],
+                [on],
+                JoinArgs::new(JoinType::Left),
+            )?)
+        })
+    }
+
+    /// Attaches several columns under a single write lock.
+    ///
+    /// The columns are attached to a copy of the frame which only replaces the original once
+    /// all columns were attached, so a failure leaves the frame unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any column length does not match the height of the frame or
+    /// polars rejects a column.
+    pub fn attach_columns(&self, columns: Vec<Series>) -> anyhow::Result<()> {
+        let mut write_guard = self.0.lock_write();
+        let d = write_guard.as_mut();
+        match d {
+            Some(data) => {
+                let height = data.df.height();
+                if let Some(column) = columns.iter().find(|c| c.len() != height) {
+                    return Err(anyhow::anyhow!(
+                        "Length of column {} ({}) does not match length of DataFrame ({})",
+                        column.name(),
+                        column.len(),
+                        height
+                    ));
+                }
+                let mut df = data.df.clone();
+                for column in columns {
+                    df.with_column(column)?;
+                }
+                data.df = df;
+                data.invalidate_derived();
                 Ok(())
             }
             None => Err(anyhow::anyhow!("DataFrame is not initialized")),
@@ -213,36 +1240,489 @@ impl IMDataFrameElement {
         match d {
             Some(data) => {
                 let _ = data.df.drop_in_place(column_name)?;
+                data.invalidate_derived();
                 Ok(())
             }
             None => Err(anyhow::anyhow!("DataFrame is not initialized")),
         }
     }
 
-    pub fn get_column_from_df(&self, column_name: &str) -> anyhow::Result<Series> {
-        let read_guard = self.0.lock_read();
-        let d = read_guard.as_ref();
+    /// Removes several columns under a single write lock.
+    ///
+    /// All present columns are removed; names that do not exist are collected and reported
+    /// together in the returned error. The `index` column is protected and cannot be removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `names` contains `index` (nothing is removed in that case) or if
+    /// any of the columns was not found.
+    pub fn remove_columns(&self, names: &[&str]) -> anyhow::Result<()> {
+        if names.contains(&"index") {
+            return Err(anyhow::anyhow!("The index column cannot be removed"));
+        }
+        let mut write_guard = self.0.lock_write();
+        let d = write_guard.as_mut();
         match d {
-            Some(data) => match data.df.column(column_name) {
-                Ok(series) => Ok(series.clone()),
-                Err(e) => Err(anyhow::anyhow!("Column not found: {}", e)),
-            },
+            Some(data) => {
+                let mut missing = Vec::new();
+                for &name in names {
+                    if data.df.drop_in_place(name).is_err() {
+                        missing.push(name);
+                    }
+                }
+                data.invalidate_derived();
+                if !missing.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "Columns not found: {}",
+                        missing.join(", ")
+                    ));
+                }
+                Ok(())
+            }
             None => Err(anyhow::anyhow!("DataFrame is not initialized")),
         }
     }
 
+    /// Returns the names of all columns starting with `prefix`, in column order.
+    pub fn columns_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.0
+            .read_inner()
+            .df
+            .get_column_names()
+            .into_iter()
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| name.to_string())
+            .collect()
+    }
+
+    /// Returns a frame with the `index` column followed by the given columns.
+    ///
+    /// The `index` column is only included if the frame has one, listing it in `names` does
+    /// not duplicate it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error listing the names that are not columns of the frame.
+    pub fn select_columns(&self, names: &[&str]) -> anyhow::Result<DataFrame> {
+        let read_guard = self.0.read_inner();
+        let existing = read_guard.df.get_column_names();
+        let missing: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|name| !existing.contains(name))
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!("Columns not found: {}", missing.join(", ")));
+        }
+        let mut selected: Vec<&str> = Vec::with_capacity(names.len() + 1);
+        if existing.contains(&"index") {
+            selected.push("index");
+        }
+        selected.extend(names.iter().copied().filter(|&name| name != "index"));
+        Ok(read_guard.df.select(selected)?)
+    }
+
+    /// Reorders the columns of the frame to match `order`.
+    ///
+    /// `order` has to name every column exactly once. The `index` column may be listed to
+    /// place it explicitly; if it is left out, it is kept as the first column.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error listing the missing, unknown or duplicated names if `order` does not
+    /// match the columns of the frame. Nothing is changed in that case.
+    pub fn reorder_columns(&self, order: &[&str]) -> anyhow::Result<()> {
+        let mut write_guard = self.0.lock_write();
+        let data = write_guard
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("DataFrame is not initialized"))?;
+        let existing = data.df.get_column_names();
+        let mut full_order: Vec<&str> = Vec::with_capacity(existing.len());
+        if existing.contains(&"index") && !order.contains(&"index") {
+            full_order.push("index");
+        }
+        full_order.extend_from_slice(order);
+
+        let mut problems = Vec::new();
+        let missing: Vec<&str> = existing
+            .iter()
+            .copied()
+            .filter(|name| !full_order.contains(name))
+            .collect();
+        if !missing.is_empty() {
+            problems.push(format!("missing columns: {}", missing.join(", ")));
+        }
+        let unknown: Vec<&str> = order
+            .iter()
+            .copied()
+            .filter(|name| !existing.contains(name))
+            .collect();
+        if !unknown.is_empty() {
+            problems.push(format!("unknown columns: {}", unknown.join(", ")));
+        }
+        let mut seen = std::collections::HashSet::new();
+        let duplicated: Vec<&str> = order
+            .iter()
+            .copied()
+            .filter(|name| !seen.insert(*name))
+            .collect();
+        if !duplicated.is_empty() {
+            problems.push(format!("duplicated columns: {}", duplicated.join(", ")));
+        }
+        if !problems.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Column order does not match the frame: {}",
+                problems.join("; ")
+            ));
+        }
+        let reordered = data.df.select(full_order)?;
+        data.df = reordered;
+        Ok(())
+    }
+
+    /// Returns a column of the frame.
+    ///
+    /// If the frame has no column of that name but a derived column was registered under it,
+    /// the derived column is computed, cached and returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if neither a column nor a derived column of that name exists, or the
+    /// derived column has the wrong length.
+    pub fn get_column_from_df(&self, column_name: &str) -> anyhow::Result<Series> {
+        {
+            let read_guard = self.0.lock_read();
+            let d = read_guard.as_ref();
+            match d {
+                Some(data) => match data.df.column(column_name) {
+                    Ok(series) => return Ok(series.clone()),
+                    Err(e) => match data.derived.get(column_name) {
+                        Some((_, Some(cached))) => return Ok(cached.clone()),
+                        Some((_, None)) => {}
+                        None => return Err(anyhow::anyhow!("Column not found: {}", e)),
+                    },
+                },
+                None => return Err(anyhow::anyhow!("DataFrame is not initialized")),
+            }
+        }
+        self.materialize_derived(column_name)
+    }
+
+    /// Registers a column computed on demand from the frame.
+    ///
+    /// `get_column_from_df(name)` evaluates `f` on first access and caches the result until
+    /// the frame is modified through this element or [`IMDataFrameElement::invalidate_derived`]
+    /// is called. Derived columns are not part of [`IMDataFrameElement::get_data`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the frame already has a column called `name`.
+    pub fn register_derived(&self, name: String, f: DeriveFn) -> anyhow::Result<()> {
+        let mut write_guard = self.0.write_inner();
+        if write_guard.df.column(&name).is_ok() {
+            return Err(anyhow::anyhow!(
+                "Cannot register derived column {}, a column of that name exists",
+                name
+            ));
+        }
+        write_guard.derived.insert(name, (f, None));
+        Ok(())
+    }
+
+    /// Drops the cached values of all derived columns, they are recomputed on next access.
+    pub fn invalidate_derived(&self) {
+        self.0.write_inner().invalidate_derived();
+    }
+
+    fn materialize_derived(&self, column_name: &str) -> anyhow::Result<Series> {
+        let mut write_guard = self.0.write_inner();
+        let data = write_guard.deref_mut();
+        let height = data.df.height();
+        let (f, cache) = data
+            .derived
+            .get_mut(column_name)
+            .ok_or_else(|| anyhow::anyhow!("Column not found: {}", column_name))?;
+        if let Some(cached) = cache {
+            return Ok(cached.clone());
+        }
+        let mut series = f(&data.df);
+        if series.len() != height {
+            return Err(anyhow::anyhow!(
+                "Derived column {} has length {}, expected {}",
+                column_name,
+                series.len(),
+                height
+            ));
+        }
+        series.rename(column_name);
+        *cache = Some(series.clone());
+        Ok(series)
+    }
+
+    /// Counts the occurrences of each value of a string or categorical column.
+    ///
+    /// Missing values are counted under `"null"`. The result is sorted by descending count,
+    /// ties are ordered by value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the column is not found or is not a string or categorical column.
+    pub fn value_counts(&self, column: &str) -> anyhow::Result<Vec<(String, u32)>> {
+        let read_guard = self.0.read_inner();
+        let series = read_guard
+            .df
+            .column(column)
+            .map_err(|e| anyhow::anyhow!("Column not found: {}", e))?;
+        match series.dtype() {
+            polars::datatypes::DataType::String
+            | polars::datatypes::DataType::Categorical(_, _)
+            | polars::datatypes::DataType::Enum(_, _) => {}
+            dtype => {
+                return Err(anyhow::anyhow!(
+                    "value_counts requires a string or categorical column, {} has type {}",
+                    column,
+                    dtype
+                ))
+            }
+        }
+        let labels = series.cast(&polars::datatypes::DataType::String)?;
+        let frame = DataFrame::new(vec![labels])?;
+        let group_by = frame.group_by([column])?;
+        let keys = group_by.keys();
+        let counts = group_by.get_groups().group_count();
+        let mut result: Vec<(String, u32)> = keys[0]
+            .str()?
+            .into_iter()
+            .zip(counts.into_no_null_iter())
+            .map(|(key, count)| (key.unwrap_or("null").to_string(), count as u32))
+            .collect();
+        result.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(result)
+    }
+
+    /// Casts a column to another polars data type in place.
+    ///
+    /// The cast is strict: values that cannot be converted produce an error instead of
+    /// becoming null, and the column is left unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the column is not found or any value fails to convert.
+    pub fn cast_column(&self, name: &str, dtype: polars::datatypes::DataType) -> anyhow::Result<()> {
+        let series = self.get_column_from_df(name)?;
+        let cast = series.strict_cast(&dtype).map_err(|e| {
+            anyhow::anyhow!(
+                "Cannot cast column {} from {} to {}: {}",
+                name,
+                series.dtype(),
+                dtype,
+                e
+            )
+        })?;
+        self.set_column_in_df(name, cast)
+    }
+
+    /// Sets the order of the categories of a categorical column.
+    ///
+    /// Only the category order changes, the values of the column stay the same.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - Name of the categorical column.
+    /// * `order` - The existing categories in their new order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the column is not found or not categorical, or if `order` does not
+    /// contain every existing category exactly once.
+    pub fn reorder_categories(&self, column: &str, order: &[String]) -> anyhow::Result<()> {
+        let series = self.get_column_from_df(column)?;
+        let ordering = match series.dtype() {
+            polars::datatypes::DataType::Categorical(_, ordering) => *ordering,
+            dtype => {
+                return Err(anyhow::anyhow!(
+                    "reorder_categories requires a categorical column, {} has type {}",
+                    column,
+                    dtype
+                ))
+            }
+        };
+        let current: Vec<String> = series
+            .categorical()?
+            .get_rev_map()
+            .get_categories()
+            .values_iter()
+            .map(|c| c.to_string())
+            .collect();
+        let mut expected = current.clone();
+        let mut requested = order.to_vec();
+        expected.sort();
+        requested.sort();
+        if expected != requested {
+            return Err(anyhow::anyhow!(
+                "New order [{}] does not match the categories [{}] of {}",
+                order.join(", "),
+                current.join(", "),
+                column
+            ));
+        }
+
+        let reordered = with_category_order(&series, order, ordering)?;
+        self.set_column_in_df(column, reordered)
+    }
+
+    /// Drops the categories that no value of a categorical column refers to, in all
+    /// categorical columns. The remaining categories keep their order, other columns are
+    /// not touched.
+    pub fn remove_unused_categories(&self) -> anyhow::Result<()> {
+        let mut write_guard = self.0.write_inner();
+        let mut pruned = Vec::new();
+        for series in write_guard.df.get_columns() {
+            let polars::datatypes::DataType::Categorical(_, ordering) = series.dtype() else {
+                continue;
+            };
+            let categorical = series.categorical()?;
+            let present: std::collections::HashSet<&str> =
+                categorical.iter_str().flatten().collect();
+            let categories: Vec<String> = categorical
+                .get_rev_map()
+                .get_categories()
+                .values_iter()
+                .filter(|c| present.contains(c))
+                .map(|c| c.to_string())
+                .collect();
+            if categories.len() < categorical.get_rev_map().len() {
+                pruned.push(with_category_order(series, &categories, *ordering)?);
+            }
+        }
+        if pruned.is_empty() {
+            return Ok(());
+        }
+        for series in pruned {
+            let name = series.name().to_string();
+            write_guard.df.replace(&name, series)?;
+        }
+        write_guard.invalidate_derived();
+        Ok(())
+    }
+
+    /// Returns the values of row `i` keyed by column name, plus the index entry under `index`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `i` is out of bounds.
+    pub fn get_row(&self, i: usize) -> anyhow::Result<HashMap<String, AnyValue<'static>>> {
+        let read_guard = self.0.read_inner();
+        let height = read_guard.index.len();
+        if i >= height {
+            return Err(anyhow::anyhow!("Row index out of bounds: {} >= {}", i, height));
+        }
+        let mut row = HashMap::new();
+        for series in read_guard.df.get_columns() {
+            row.insert(series.name().to_string(), series.get(i)?.into_static()?);
+        }
+        // Select only entry `i` instead of materializing all names
+        let name = read_guard
+            .index
+            .select(&SelectInfoElem::Index(vec![i]))
+            .into_vec()
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("Row index out of bounds: {} >= {}", i, height))?;
+        row.insert("index".to_string(), AnyValue::StringOwned(name.into()));
+        Ok(row)
+    }
+
+    /// Converts every row into a user-defined record type.
+    ///
+    /// Each row is turned into a map of column name to value, with the index entry under
+    /// `index` as in [`IMDataFrameElement::get_row`], and then deserialized into `T`.
+    /// Categorical columns are passed as strings and missing values as `null`, so fields
+    /// reading nullable columns should be `Option`s.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the row and the available columns if a row cannot be
+    /// deserialized, for example because `T` expects a column that does not exist, and an
+    /// error if a column has a type without a serde representation (lists, structs, ...).
+    pub fn deserialize_rows<T: serde::de::DeserializeOwned>(&self) -> anyhow::Result<Vec<T>> {
+        let read_guard = self.0.read_inner();
+        let names = read_guard.index.clone().into_vec();
+        let columns = read_guard
+            .df
+            .get_columns()
+            .iter()
+            .map(|series| match series.dtype() {
+                polars::datatypes::DataType::Categorical(_, _) => {
+                    Ok(series.cast(&polars::datatypes::DataType::String)?)
+                }
+                _ => Ok(series.clone()),
+            })
+            .collect::<anyhow::Result<Vec<Series>>>()?;
+        let mut records = Vec::with_capacity(names.len());
+        for (i, name) in names.into_iter().enumerate() {
+            let mut row = serde_json::Map::with_capacity(columns.len() + 1);
+            for series in &columns {
+                row.insert(series.name().to_string(), any_value_to_json(series.get(i)?, series.name())?);
+            }
+            row.insert("index".to_string(), serde_json::Value::String(name.clone()));
+            let record = serde_json::from_value(serde_json::Value::Object(row)).map_err(|e| {
+                anyhow::anyhow!(
+                    "Row {} ({}) could not be deserialized: {} (available columns: index, {})",
+                    i,
+                    name,
+                    e,
+                    columns.iter().map(|s| s.name()).collect::<Vec<_>>().join(", ")
+                )
+            })?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
     pub fn set_column_in_df(&self, column_name: &str, column: Series) -> anyhow::Result<()> {
         let mut write_guard = self.0.lock_write();
         let d = write_guard.as_mut();
         match d {
             Some(data) => {
                 data.df.replace(column_name, column)?;
+                data.invalidate_derived();
                 Ok(())
             }
             None => Err(anyhow::anyhow!("DataFrame is not initialized")),
         }
     }
 
+    /// Randomly permutes the values of one column in place.
+    ///
+    /// The permutation is reproducible for a given `seed`. All other columns and the index
+    /// are left unchanged, which makes this suitable for building permuted labels in
+    /// significance tests.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the column to shuffle.
+    /// * `seed` - Seed of the random number generator.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the column does not exist or the frame is not initialized.
+    pub fn permute_column(&self, name: &str, seed: u64) -> anyhow::Result<()> {
+        let mut write_guard = self.0.lock_write();
+        let data = write_guard
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("DataFrame is not initialized"))?;
+        let column = data
+            .df
+            .column(name)
+            .map_err(|e| anyhow::anyhow!("Column not found: {}", e))?;
+        let mut order: Vec<u32> = (0..column.len() as u32).collect();
+        order.shuffle(&mut crate::utils::rng_from_seed(seed));
+        let permuted = column.take(&IdxCa::new("idx", &order))?;
+        data.df.replace(name, permuted)?;
+        data.invalidate_derived();
+        Ok(())
+    }
+
     pub fn subset_inplace(&self, s: &SelectInfoElem) -> anyhow::Result<()> {
         let read_guard = self.0.lock_read();
         let d = read_guard.as_ref().unwrap();
@@ -256,6 +1736,39 @@ impl IMDataFrameElement {
         self.set_both(df_subset, DataFrameIndex::from(ind_subset))
     }
 
+    /// Subsets the rows in place, gathering one column at a time.
+    ///
+    /// Unlike [`IMDataFrameElement::subset_inplace`], which builds the subset under a read
+    /// lock and stores it under a separate write lock, the columns are gathered and swapped
+    /// in under a single write lock, so no other handle can change the frame in between. The
+    /// gather borrows the frame, which is only replaced once every column succeeded, so the
+    /// original frame and the subset are held together until then.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the selection is out of bounds or a column cannot be gathered. The
+    /// frame is unchanged in that case.
+    pub fn subset_inplace_low_mem(&self, s: &SelectInfoElem) -> anyhow::Result<()> {
+        let mut write_guard = self.0.lock_write();
+        let d = write_guard
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("DataFrame is not initialized"))?;
+        let indices = crate::utils::select_info_elem_to_indices(s, d.index.len())?;
+        let indices_u32: Vec<u32> = indices.iter().map(|&i| i as u32).collect();
+        let idx = IdxCa::new("idx", &indices_u32);
+        let ind = d.index.clone().into_vec();
+        let ind_subset: Vec<String> = indices.iter().map(|&i| ind[i].clone()).collect();
+        drop(ind);
+        let mut gathered = Vec::with_capacity(d.df.width());
+        for column in d.df.get_columns() {
+            gathered.push(column.take(&idx)?);
+        }
+        d.df = DataFrame::new(gathered)?;
+        d.index = DataFrameIndex::from(ind_subset);
+        d.invalidate_derived();
+        Ok(())
+    }
+
     pub fn subset(&self, s: &SelectInfoElem) -> anyhow::Result<Self> {
         let read_guard = self.0.lock_read();
         let d = read_guard.as_ref().unwrap();
@@ -265,10 +1778,242 @@ impl IMDataFrameElement {
         let ind = d.index.clone().into_vec();
         let ind_subset: Vec<String> = indices.iter().map(|&i| ind[i].clone()).collect();
         let df_subset = d.df.take(&idx)?;
-        Ok(Self::new(df_subset, DataFrameIndex::from(ind_subset)))
+        let subset = Self::new(df_subset, DataFrameIndex::from(ind_subset));
+        // Registrations carry over, their values are recomputed on the subset
+        subset.0.write_inner().derived = d
+            .derived
+            .iter()
+            .map(|(name, (f, _))| (name.clone(), (f.clone(), None)))
+            .collect();
+        Ok(subset)
+    }
+
+    /// Exchanges the contents with `other`, shallow clones of both elements observe the swap.
+    pub(crate) fn swap_contents(&self, other: &Self) {
+        self.0.swap(&other.0);
+    }
+
+    pub(crate) fn slot(&self) -> &RwSlot<InnerIMDataFrame> {
+        &self.0
+    }
+
+    pub(crate) fn from_inner(inner: InnerIMDataFrame) -> Self {
+        IMDataFrameElement(RwSlot::new(inner))
     }
 }
 
+macro_rules! impl_prune {
+    ($name:ident, $matrix:ident, $from:ident) => {
+        /// Drops explicitly stored zeros, keeping the capacity of the buffers.
+        fn $name<T: Copy + PartialEq + fmt::Debug + Zero + 'static>(
+            m: &mut $matrix<T>,
+        ) -> anyhow::Result<usize> {
+            let (nrows, ncols) = (m.nrows(), m.ncols());
+            let nnz = m.nnz();
+            // Built from borrowed buffers, so `m` is untouched if reassembling fails
+            let (offsets, indices, values) =
+                (m.pattern().major_offsets(), m.pattern().minor_indices(), m.values());
+            let mut new_offsets = Vec::with_capacity(offsets.len());
+            let mut new_indices = Vec::with_capacity(nnz);
+            let mut new_values = Vec::with_capacity(nnz);
+            new_offsets.push(0);
+            for lane in offsets.windows(2) {
+                for k in lane[0]..lane[1] {
+                    if !values[k].is_zero() {
+                        new_indices.push(indices[k]);
+                        new_values.push(values[k]);
+                    }
+                }
+                new_offsets.push(new_values.len());
+            }
+            let removed = nnz - new_values.len();
+            *m = $matrix::$from(nrows, ncols, new_offsets, new_indices, new_values)?;
+            Ok(removed)
+        }
+    };
+}
+
+impl_prune!(prune_csr, CsrMatrix, try_from_csr_data);
+impl_prune!(prune_csc, CscMatrix, try_from_csc_data);
+
+/// Sums a 2-dimensional numeric array along its rows (`rows == true`) or columns.
+fn axis_sums(data: &ArrayData, rows: bool) -> anyhow::Result<Vec<f64>> {
+    let shape = data.shape();
+    if shape.ndim() != 2 {
+        return Err(anyhow::anyhow!(
+            "Expected a 2-dimensional array, found shape {:?}",
+            shape
+        ));
+    }
+    let mut sums = vec![0.0; if rows { shape[0] } else { shape[1] }];
+    let mismatch = || -> anyhow::Error {
+        DtypeMismatch {
+            operation: "Summing",
+            found: format!("{:?}", data.data_type()),
+        }
+        .into()
+    };
+    macro_rules! accumulate {
+        ($entries:expr) => {
+            for (i, j, &v) in $entries {
+                sums[if rows { i } else { j }] += v as f64;
+            }
+        };
+    }
+    match data {
+        ArrayData::Array(arr) => dispatch_numeric!(
+            arr,
+            DynArray,
+            a => accumulate!(a
+                .view()
+                .into_dimensionality::<Ix2>()?
+                .indexed_iter()
+                .map(|((i, j), v)| (i, j, v))),
+            return Err(mismatch())
+        ),
+        ArrayData::CsrMatrix(csr) => dispatch_numeric!(
+            csr,
+            DynCsrMatrix,
+            m => accumulate!(m.triplet_iter()),
+            return Err(mismatch())
+        ),
+        ArrayData::CscMatrix(csc) => dispatch_numeric!(
+            csc,
+            DynCscMatrix,
+            m => accumulate!(m.triplet_iter()),
+            return Err(mismatch())
+        ),
+        _ => return Err(mismatch()),
+    }
+    Ok(sums)
+}
+
+/// Checks row offsets and column indices of a CSR matrix, see [`IMArrayElement::validate_csr`].
+fn check_csr_structure(
+    ncols: usize,
+    offsets: &[usize],
+    indices: &[usize],
+    n_values: usize,
+) -> anyhow::Result<()> {
+    if offsets.first() != Some(&0) {
+        return Err(anyhow::anyhow!("Row offsets must start at 0, found {:?}", offsets.first()));
+    }
+    if let Some(row) = offsets.windows(2).position(|w| w[0] > w[1]) {
+        return Err(anyhow::anyhow!(
+            "Row offsets decrease at row {}: {} > {}",
+            row,
+            offsets[row],
+            offsets[row + 1]
+        ));
+    }
+    let end = offsets[offsets.len() - 1];
+    if end != indices.len() || indices.len() != n_values {
+        return Err(anyhow::anyhow!(
+            "Row offsets end at {} but there are {} column indices and {} values",
+            end,
+            indices.len(),
+            n_values
+        ));
+    }
+    for (row, w) in offsets.windows(2).enumerate() {
+        let cols = &indices[w[0]..w[1]];
+        if let Some(&col) = cols.iter().find(|&&col| col >= ncols) {
+            return Err(anyhow::anyhow!(
+                "Column index {} in row {} is out of bounds for {} columns",
+                col,
+                row,
+                ncols
+            ));
+        }
+        if !cols.windows(2).all(|c| c[0] < c[1]) {
+            return Err(anyhow::anyhow!(
+                "Column indices of row {} are not strictly increasing",
+                row
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Counts the stored entries of a sparse matrix, or the nonzero values of a dense array.
+fn data_nnz(data: &ArrayData) -> anyhow::Result<usize> {
+    let mismatch = || -> anyhow::Error {
+        DtypeMismatch {
+            operation: "Counting nonzeros",
+            found: format!("{:?}", data.data_type()),
+        }
+        .into()
+    };
+    match data {
+        ArrayData::Array(arr) => dispatch_numeric!(
+            arr,
+            DynArray,
+            a => Ok(a.iter().filter(|&&v| v as f64 != 0.0).count()),
+            Err(mismatch())
+        ),
+        ArrayData::CsrMatrix(csr) => dispatch_numeric!(csr, DynCsrMatrix, m => Ok(m.nnz()), Err(mismatch())),
+        ArrayData::CscMatrix(csc) => dispatch_numeric!(csc, DynCscMatrix, m => Ok(m.nnz()), Err(mismatch())),
+        _ => Err(mismatch()),
+    }
+}
+
+/// Reports a [`DtypeMismatch`] of `apply_values_inplace` under the name of the calling operation.
+fn rename_mismatch(e: anyhow::Error, operation: &'static str) -> anyhow::Error {
+    match e.downcast::<DtypeMismatch>() {
+        Ok(mismatch) => DtypeMismatch {
+            operation,
+            ..mismatch
+        }
+        .into(),
+        Err(e) => e,
+    }
+}
+
+/// Converts a single frame value into its JSON representation for `deserialize_rows`.
+fn any_value_to_json(value: AnyValue, column: &str) -> anyhow::Result<serde_json::Value> {
+    use serde_json::Value;
+    Ok(match value {
+        AnyValue::Null => Value::Null,
+        AnyValue::Boolean(v) => Value::Bool(v),
+        AnyValue::String(v) => Value::String(v.to_string()),
+        AnyValue::StringOwned(v) => Value::String(v.to_string()),
+        AnyValue::Int8(v) => Value::from(v),
+        AnyValue::Int16(v) => Value::from(v),
+        AnyValue::Int32(v) => Value::from(v),
+        AnyValue::Int64(v) => Value::from(v),
+        AnyValue::UInt8(v) => Value::from(v),
+        AnyValue::UInt16(v) => Value::from(v),
+        AnyValue::UInt32(v) => Value::from(v),
+        AnyValue::UInt64(v) => Value::from(v),
+        // JSON has no NaN or infinity, non-finite floats become null
+        AnyValue::Float32(v) => serde_json::Number::from_f64(v as f64).map_or(Value::Null, Value::Number),
+        AnyValue::Float64(v) => serde_json::Number::from_f64(v).map_or(Value::Null, Value::Number),
+        other => {
+            return Err(anyhow::anyhow!(
+                "Column {} has type {} which cannot be deserialized",
+                column,
+                other.dtype()
+            ))
+        }
+    })
+}
+
+/// Rebuilds a categorical column with exactly the categories in `order`, in that order.
+pub(crate) fn with_category_order(
+    series: &Series,
+    order: &[String],
+    ordering: polars::datatypes::CategoricalOrdering,
+) -> anyhow::Result<Series> {
+    // Categories are numbered by first appearance, so prepending them in the requested
+    // order fixes their order; the prefix is sliced off again afterwards
+    let values = series.cast(&polars::datatypes::DataType::String)?;
+    let mut combined = Series::new(series.name(), order);
+    combined.append(&values)?;
+    Ok(combined
+        .cast(&polars::datatypes::DataType::Categorical(None, ordering))?
+        .slice(order.len() as i64, values.len()))
+}
+
 impl DeepClone for IMDataFrameElement {
     fn deep_clone(&self) -> Self {
         IMDataFrameElement(self.0.deep_clone())
@@ -379,6 +2124,12 @@ impl IMAxisArrays {
 
         // Get the shape of the input element
         let shape = element.get_shape()?;
+        if imarray.axis != Axis::Row && shape.ndim() != 2 {
+            return Err(anyhow::anyhow!(
+                "Data shape {:?} is not 2-dimensional",
+                shape
+            ));
+        }
         let dim1 = imarray.dim1.get();
         let dim2 = imarray.dim2.clone().unwrap_or(Dim::new(0)).get();
 
@@ -420,14 +2171,165 @@ impl IMAxisArrays {
         Ok(())
     }
 
+    /// Adds a pairwise matrix (obsp/varp) after validating that it is a usable graph.
+    ///
+    /// In addition to the checks of `add_array`, the matrix must be square and numeric, and
+    /// can be required to be symmetric.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Key under which the matrix is stored.
+    /// * `element` - The matrix to add.
+    /// * `symmetry_tol` - If set, `|a_ij - a_ji|` must not exceed this tolerance for any entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if this collection is not pairwise, the matrix is not square, not
+    /// numeric, not symmetric within the tolerance, or if `add_array` rejects it.
+    pub fn add_pairwise_checked(
+        &self,
+        key: String,
+        element: IMArrayElement,
+        symmetry_tol: Option<f64>,
+    ) -> anyhow::Result<()> {
+        if self.0.read_inner().axis != Axis::Pairwise {
+            return Err(anyhow::anyhow!(
+                "Cannot add {} as a pairwise matrix to a non-pairwise collection",
+                key
+            ));
+        }
+        let data = element.get_data()?;
+        let shape = data.shape();
+        if shape.ndim() != 2 || shape[0] != shape[1] {
+            return Err(anyhow::anyhow!(
+                "Pairwise matrix {} must be square, found shape {:?}",
+                key,
+                shape
+            ));
+        }
+        let dtype = data.data_type();
+        match dtype {
+            DataType::Array(t) | DataType::CsrMatrix(t) | DataType::CscMatrix(t)
+                if !matches!(t, ScalarType::Bool | ScalarType::String) => {}
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Pairwise matrix {} must be numeric, found {:?}",
+                    key,
+                    dtype
+                ))
+            }
+        }
+        if let Some(tol) = symmetry_tol {
+            let csr = crate::utils::to_csr_f64(&data)?;
+            let diff = &csr - &csr.transpose();
+            let max_diff = diff.values().iter().fold(0.0f64, |acc, v| acc.max(v.abs()));
+            if max_diff > tol {
+                return Err(anyhow::anyhow!(
+                    "Pairwise matrix {} is not symmetric: max |a_ij - a_ji| is {} > {}",
+                    key,
+                    max_diff,
+                    tol
+                ));
+            }
+        }
+        self.add_array(key, element)
+    }
+
+    /// Adds a row-aligned array whose rows are labelled by name, reordering them to match.
+    ///
+    /// Embeddings computed elsewhere, e.g. on another modality, can list the observations in a
+    /// different order. The rows are matched by name and permuted into the order of
+    /// `self_names` before the array is added, so row `i` always belongs to `self_names[i]`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Key under which the array is stored.
+    /// * `element` - The array, with one row per entry of `obs_names`.
+    /// * `obs_names` - Names of the rows of `element`, in its row order.
+    /// * `self_names` - Names along the axis of this collection, e.g. the obs names of the
+    ///   object owning obsm.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if this collection is not row-aligned, if the number of rows of
+    /// `element` differs from `obs_names`, if either name list contains duplicates, if the two
+    /// name lists are not the same set of names, or if `add_array` rejects the reordered array.
+    /// Nothing is added in that case.
+    pub fn add_array_aligned(
+        &self,
+        key: String,
+        element: IMArrayElement,
+        obs_names: &[String],
+        self_names: &[String],
+    ) -> anyhow::Result<()> {
+        if self.0.read_inner().axis != Axis::Row {
+            return Err(anyhow::anyhow!(
+                "Cannot align {} by name in a collection that is not row-aligned",
+                key
+            ));
+        }
+        let n_rows = element.get_shape()?[0];
+        if n_rows != obs_names.len() {
+            return Err(anyhow::anyhow!(
+                "Array {} has {} rows but {} names were given",
+                key,
+                n_rows,
+                obs_names.len()
+            ));
+        }
+        if obs_names.len() != self_names.len() {
+            return Err(anyhow::anyhow!(
+                "Array {} has {} rows, expected {}",
+                key,
+                obs_names.len(),
+                self_names.len()
+            ));
+        }
+        let mut positions = HashMap::with_capacity(obs_names.len());
+        for (i, name) in obs_names.iter().enumerate() {
+            if positions.insert(name.as_str(), i).is_some() {
+                return Err(anyhow::anyhow!("Duplicate name {} in rows of {}", name, key));
+            }
+        }
+        // Every row must be taken exactly once, duplicated `self_names` would repeat one row
+        // and drop another
+        let mut seen = vec![false; obs_names.len()];
+        let order = self_names
+            .iter()
+            .map(|name| {
+                let pos = positions
+                    .get(name.as_str())
+                    .copied()
+                    .ok_or_else(|| anyhow::anyhow!("Name {} is missing from rows of {}", name, key))?;
+                if std::mem::replace(&mut seen[pos], true) {
+                    return Err(anyhow::anyhow!(
+                        "Name {} occurs more than once in the names to align {} to",
+                        name,
+                        key
+                    ));
+                }
+                Ok(pos)
+            })
+            .collect::<anyhow::Result<Vec<usize>>>()?;
+        if order.iter().enumerate().all(|(i, &pos)| i == pos) {
+            return self.add_array(key, element);
+        }
+        let aligned = element.subset(&[&SelectInfoElem::Index(order), &SelectInfoElem::full()])?;
+        self.add_array(key, aligned)
+    }
+
     // Get an array element (returns a deep clone to avoid holding the read lock)
     pub fn get_array(&self, key: &str) -> anyhow::Result<IMArrayElement> {
         let read_guard = self.0.read_inner();
-        read_guard
+        let element = read_guard
             .data
             .get(key)
-            .map(|element| element.deep_clone())
-            .ok_or_else(|| anyhow::anyhow!("Key not found"))
+            .ok_or_else(|| anyhow::anyhow!("Key not found"))?;
+        let data = element.0.lock_read();
+        match data.as_ref() {
+            Some(data) => Ok(IMArrayElement::new(data.deep_clone())),
+            None => Err(anyhow::anyhow!("Array {} has been extracted and is empty", key)),
+        }
     }
 
     // New method: Get an array element (returns a shallow clone)
@@ -440,6 +2342,39 @@ impl IMAxisArrays {
             .ok_or_else(|| anyhow::anyhow!("Key not found"))
     }
 
+    /// Returns the name, shape and data type of every array, sorted by name.
+    ///
+    /// All arrays are inspected under one read lock of the collection, empty slots (for
+    /// example extracted arrays) are skipped.
+    pub(crate) fn infos(&self) -> Vec<(String, Vec<usize>, DataType)> {
+        let read_guard = self.0.read_inner();
+        let mut infos: Vec<(String, Vec<usize>, DataType)> = read_guard
+            .data
+            .iter()
+            .filter_map(|(key, element)| {
+                let guard = element.0.lock_read();
+                let data = guard.as_ref()?;
+                let shape = data.shape();
+                let dims = (0..shape.ndim()).map(|i| shape[i]).collect();
+                Some((key.clone(), dims, data.data_type()))
+            })
+            .collect();
+        infos.sort_by(|a, b| a.0.cmp(&b.0));
+        infos
+    }
+
+    /// Empties the collection and the slots of all its entries, see [`crate::IMAnnData::close`].
+    pub(crate) fn release(&self) {
+        if let Some(inner) = self.0.extract() {
+            inner.data.values().for_each(|element| element.0.drop());
+        }
+    }
+
+    /// Returns a shallow clone of the element at `key`, or `None` if there is no such key.
+    pub fn try_get(&self, key: &str) -> Option<IMArrayElement> {
+        self.0.read_inner().data.get(key).cloned()
+    }
+
     // Remove an array element
     pub fn remove_array(&self, key: &str) -> anyhow::Result<IMArrayElement> {
         let mut write_guard = self.0.write_inner();
@@ -466,6 +2401,40 @@ impl IMAxisArrays {
         let read_guard = self.0.read_inner();
         read_guard.data.keys().cloned().collect()
     }
+    /// Removes every entry whose key is not in `keys`, under a single write lock.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error listing the requested keys that do not exist, nothing is removed in
+    /// that case.
+    pub fn retain(&self, keys: &[&str]) -> anyhow::Result<()> {
+        let mut write_guard = self.0.write_inner();
+        let missing: Vec<&str> = keys
+            .iter()
+            .copied()
+            .filter(|key| !write_guard.data.contains_key(*key))
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!("Keys not found: {}", missing.join(", ")));
+        }
+        write_guard.data.retain(|key, _| keys.contains(&key.as_str()));
+        Ok(())
+    }
+
+    /// Returns the sorted keys whose array slot has been emptied, e.g. by `extract`.
+    ///
+    /// Accessing such an entry through the element panics, this allows finding them first.
+    pub fn empty_slot_keys(&self) -> Vec<String> {
+        let read_guard = self.0.read_inner();
+        let mut keys: Vec<String> = read_guard
+            .data
+            .iter()
+            .filter(|(_, element)| element.0.is_none())
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
 
     // Get the axis
     pub fn axis(&self) -> Axis {
@@ -514,6 +2483,16 @@ impl IMAxisArrays {
     }
 
     pub fn subset(&self, s: &[&SelectInfoElem]) -> anyhow::Result<Self> {
+        self.subset_except(s, &HashSet::new())
+    }
+
+    /// Subsets like [`IMAxisArrays::subset`], leaving the entries named in `skip` out of the
+    /// result.
+    pub(crate) fn subset_except(
+        &self,
+        s: &[&SelectInfoElem],
+        skip: &HashSet<String>,
+    ) -> anyhow::Result<Self> {
         let read_guard = self.0.read_inner();
         let imarray = read_guard.deref();
         let dim1_indices = crate::utils::select_info_elem_to_indices(s[0], imarray.dim1.get())?;
@@ -532,7 +2511,7 @@ impl IMAxisArrays {
             new_dim2 = Some(Dim::new(dim2_indices.len()));
         }
         let mut new_data = HashMap::new();
-        for (key, element) in &imarray.data {
+        for (key, element) in imarray.data.iter().filter(|(key, _)| !skip.contains(*key)) {
             new_data.insert(key.clone(), element.subset(s)?);
         }
 
@@ -544,6 +2523,237 @@ impl IMAxisArrays {
         ))
     }
 
+    /// Truncates the array stored under `key` to its first `n` columns in place.
+    ///
+    /// Only supported on `Axis::Row` collections such as obsm/varm, the row dimension is left untouched.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key is missing, the array is not 2-dimensional or `n` exceeds
+    /// its number of columns.
+    pub fn subset_array_cols(&self, key: &str, n: usize) -> anyhow::Result<()> {
+        let read_guard = self.0.read_inner();
+        if read_guard.axis != Axis::Row {
+            return Err(anyhow::anyhow!(
+                "Column truncation is only supported on row axis arrays, found {:?}",
+                read_guard.axis
+            ));
+        }
+        let element = read_guard
+            .data
+            .get(key)
+            .ok_or_else(|| anyhow::anyhow!("Key not found"))?;
+        let shape = element.get_shape()?;
+        if shape.ndim() != 2 {
+            return Err(anyhow::anyhow!(
+                "Array {} is not 2-dimensional: {:?}",
+                key,
+                shape
+            ));
+        }
+        if n > shape[1] {
+            return Err(anyhow::anyhow!(
+                "Cannot keep {} columns of array {} with {} columns",
+                n,
+                key,
+                shape[1]
+            ));
+        }
+        let col_sel = SelectInfoElem::Slice(ndarray::Slice::from(0..n));
+        element.subset_inplace(&[&SelectInfoElem::full(), &col_sel])
+    }
+
+    /// Returns the neighbors of every row of the pairwise matrix stored under `key`.
+    ///
+    /// The neighbors of a row are the column indices of its stored nonzero entries. With `k`
+    /// set, only the `k` entries with the largest weights are kept, ordered by descending
+    /// weight; otherwise neighbors are returned in ascending column order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the collection is not pairwise, the key is missing or the matrix is
+    /// not square or not numeric.
+    pub fn neighbors(&self, key: &str, k: Option<usize>) -> anyhow::Result<Vec<Vec<usize>>> {
+        let read_guard = self.0.read_inner();
+        if read_guard.axis != Axis::Pairwise {
+            return Err(anyhow::anyhow!(
+                "Neighbors require a pairwise axis array, found {:?}",
+                read_guard.axis
+            ));
+        }
+        let element = read_guard
+            .data
+            .get(key)
+            .ok_or_else(|| anyhow::anyhow!("Key not found"))?;
+        let csr = crate::utils::to_csr_f64(&element.get_data()?)?;
+        if csr.nrows() != csr.ncols() {
+            return Err(anyhow::anyhow!(
+                "Matrix {} is not square: {} x {}",
+                key,
+                csr.nrows(),
+                csr.ncols()
+            ));
+        }
+        let mut result = Vec::with_capacity(csr.nrows());
+        for row in csr.row_iter() {
+            let mut entries: Vec<(usize, f64)> = row
+                .col_indices()
+                .iter()
+                .copied()
+                .zip(row.values().iter().copied())
+                .filter(|&(_, weight)| weight != 0.0)
+                .collect();
+            if let Some(k) = k {
+                entries.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                entries.truncate(k);
+            }
+            result.push(entries.into_iter().map(|(col, _)| col).collect());
+        }
+        Ok(result)
+    }
+
+    /// Converts the square matrix at `key` into an edge list.
+    ///
+    /// Every stored nonzero entry `(i, j)` becomes one row with `source = names[i]`,
+    /// `target = names[j]` and its value as `weight`, in row-major order. Collections do not
+    /// know the names of their axis, pass `obs_names()` for obsp and `var_names()` for varp.
+    ///
+    /// # Returns
+    ///
+    /// A DataFrame with the string columns `source` and `target` and the `f64` column
+    /// `weight`, the format graph libraries such as igraph or networkx import.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key is missing, the matrix is not square or not numeric, or
+    /// `names` does not have one entry per row.
+    pub fn to_edge_list(&self, key: &str, names: &[String]) -> anyhow::Result<DataFrame> {
+        let csr = crate::utils::to_csr_f64(&self.get_array_shallow(key)?.get_data()?)?;
+        if csr.nrows() != csr.ncols() {
+            return Err(anyhow::anyhow!(
+                "Matrix {} is not square: {} x {}",
+                key,
+                csr.nrows(),
+                csr.ncols()
+            ));
+        }
+        if names.len() != csr.nrows() {
+            return Err(anyhow::anyhow!(
+                "Got {} names for matrix {} with {} rows",
+                names.len(),
+                key,
+                csr.nrows()
+            ));
+        }
+        let (mut sources, mut targets, mut weights) = (Vec::new(), Vec::new(), Vec::new());
+        for (i, row) in csr.row_iter().enumerate() {
+            for (&j, &weight) in row.col_indices().iter().zip(row.values()) {
+                if weight != 0.0 {
+                    sources.push(names[i].as_str());
+                    targets.push(names[j].as_str());
+                    weights.push(weight);
+                }
+            }
+        }
+        Ok(DataFrame::new(vec![
+            Series::new("source", sources),
+            Series::new("target", targets),
+            Series::new("weight", weights),
+        ])?)
+    }
+
+    /// Multiplies the array at `src` with `weights` and stores the product under `dst`.
+    ///
+    /// The array at `src` (`n x d`) is converted to `f64` and multiplied with the `d x k`
+    /// weight matrix, the dense `n x k` result is added as a new array.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `src` is missing or not a numeric 2-dimensional array, the number
+    /// of rows of `weights` differs from its number of columns, or `dst` already exists or
+    /// the result does not fit the dimensions of the collection.
+    pub fn project(&self, src: &str, weights: &Array2<f64>, dst: String) -> anyhow::Result<()> {
+        let source = self.get_array_shallow(src)?.to_dense_f64()?;
+        if source.ncols() != weights.nrows() {
+            return Err(anyhow::anyhow!(
+                "Cannot project array {} of shape {:?} with weights of shape {:?}",
+                src,
+                source.shape(),
+                weights.shape()
+            ));
+        }
+        let projected = source.dot(weights);
+        self.add_array(dst, IMArrayElement::new(ArrayData::from(projected.into_dyn())))
+    }
+
+    /// Divides every row of the square sparse matrix at `key` by its row sum, in place.
+    ///
+    /// Rows summing to zero are left untouched. `f32` CSR matrices keep their type, all other
+    /// sparse matrices are stored as `f64` CSR afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key is missing or the matrix is dense, not square or not numeric.
+    pub fn row_normalize(&self, key: &str) -> anyhow::Result<()> {
+        let element = self.get_array_shallow(key)?;
+        let data = element.get_data()?;
+        let shape = data.shape();
+        if shape[0] != shape[1] {
+            return Err(anyhow::anyhow!(
+                "Matrix {} is not square: {} x {}",
+                key,
+                shape[0],
+                shape[1]
+            ));
+        }
+        macro_rules! normalize_rows {
+            ($csr:expr) => {
+                for mut row in $csr.row_iter_mut() {
+                    let total = row.values().iter().copied().sum::<_>();
+                    if total != 0.0 {
+                        row.values_mut().iter_mut().for_each(|v| *v /= total);
+                    }
+                }
+            };
+        }
+        let normalized = match data {
+            ArrayData::CsrMatrix(DynCsrMatrix::F32(mut csr)) => {
+                normalize_rows!(csr);
+                ArrayData::CsrMatrix(DynCsrMatrix::F32(csr))
+            }
+            ArrayData::Array(_) => {
+                return Err(anyhow::anyhow!("Matrix {} is dense, expected a sparse matrix", key))
+            }
+            other => {
+                let mut csr = crate::utils::to_csr_f64(&other)?;
+                normalize_rows!(csr);
+                ArrayData::CsrMatrix(DynCsrMatrix::F64(csr))
+            }
+        };
+        element.set_data(normalized)
+    }
+
+    /// Replaces the contents with those of `other`, array by array.
+    ///
+    /// Arrays present in both collections swap their slot contents, so shallow handles to
+    /// individual arrays keep observing the collection. Arrays only present in `other` are moved over.
+    pub(crate) fn swap_contents(&self, other: &Self) {
+        let mut write_guard = self.0.write_inner();
+        let mut other_guard = other.0.write_inner();
+        let imarray = write_guard.deref_mut();
+        let other_imarray = other_guard.deref_mut();
+        std::mem::swap(&mut imarray.dim1, &mut other_imarray.dim1);
+        std::mem::swap(&mut imarray.dim2, &mut other_imarray.dim2);
+        for (key, element) in other_imarray.data.iter() {
+            match imarray.data.get(key) {
+                Some(existing) => existing.0.swap(&element.0),
+                None => {
+                    imarray.data.insert(key.clone(), element.clone());
+                }
+            }
+        }
+    }
+
     // Perform an operation on all arrays
     pub fn map<F>(&self, f: F) -> anyhow::Result<()>
     where
@@ -629,6 +2839,18 @@ impl IMElementCollection {
         Ok(())
     }
 
+    /// Returns the sorted keys whose element slot has been emptied, e.g. by `extract`.
+    pub fn empty_slot_keys(&self) -> Vec<String> {
+        let read_guard = self.0.read_inner();
+        let mut keys: Vec<String> = read_guard
+            .iter()
+            .filter(|(_, element)| element.0.is_none())
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
     pub fn remove_data(&self, key: &str) -> anyhow::Result<Element> {
         let mut write_guard = self.0.write_inner();
         write_guard
@@ -646,9 +2868,13 @@ impl IMElementCollection {
 
     pub fn get_data_deep(&self, key: &str) -> anyhow::Result<Element> {
         let read_guard = self.0.read_inner();
-        read_guard
+        let element = read_guard
             .get(key)
-            .map(|element| element.deep_clone())
-            .ok_or_else(|| anyhow::anyhow!("Key not found"))
+            .ok_or_else(|| anyhow::anyhow!("Key not found"))?;
+        let data = element.0.lock_read();
+        match data.as_ref() {
+            Some(data) => Ok(Element::new(data.deep_clone())),
+            None => Err(anyhow::anyhow!("Element {} has been extracted and is empty", key)),
+        }
     }
 }
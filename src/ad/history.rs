@@ -0,0 +1,53 @@
+use std::time::SystemTime;
+
+use crate::{base::DeepClone, IMAnnData};
+
+/// One operation applied to an `IMAnnData`, see [`IMAnnData::history`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpRecord {
+    /// Name of the method, e.g. `log1p_x` or `subset_inplace`.
+    pub operation: String,
+    /// Arguments of the call in a human readable form, may be empty.
+    pub details: String,
+    /// Seconds since the Unix epoch at which the operation completed.
+    pub timestamp: u64,
+}
+
+impl DeepClone for Vec<OpRecord> {
+    fn deep_clone(&self) -> Self {
+        self.clone()
+    }
+}
+
+impl IMAnnData {
+    /// Returns the operations applied to this object, oldest first.
+    ///
+    /// Records are appended by the `IMAnnData` methods that modify the object once they
+    /// succeed, and by analyses such as `highly_variable_genes_batched` that only return a
+    /// result. Objects derived through `subset`, `deep_clone` or `collapse_duplicate_obs` start
+    /// with a copy of the history of their source, objects created by `concat_obs` only
+    /// record the concatenation.
+    ///
+    /// # Notes
+    ///
+    /// Changes made directly through component handles, e.g. `x().set_data(...)`, are not
+    /// recorded.
+    pub fn history(&self) -> Vec<OpRecord> {
+        self.history.lock_read().clone().unwrap_or_default()
+    }
+
+    /// Appends an entry to the history.
+    pub(crate) fn record(&self, operation: &str, details: String) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if let Some(history) = self.history.lock_write().as_mut() {
+            history.push(OpRecord {
+                operation: operation.to_string(),
+                details,
+                timestamp,
+            });
+        }
+    }
+}
@@ -1,15 +1,47 @@
+use std::collections::{HashMap, HashSet};
+
 use anndata::{
+    backend::{DataType, ScalarType},
     container::{Axis, Dim},
-    data::{DataFrameIndex, SelectInfoElem},
-    ArrayData, HasShape,
+    data::{DataFrameIndex, DynCsrMatrix, SelectInfoElem},
+    ArrayData, ArrayOp, HasShape,
 };
-use helpers::IMAxisArrays;
+use helpers::{IMAxisArrays, LayerEntry};
+use nalgebra_sparse::CsrMatrix;
+use ndarray::{Array2, ArrayD};
+#[cfg(not(feature = "tracing"))]
 use log::{log, Level};
-use polars::{frame::DataFrame, prelude::NamedFrom, series::Series};
+use rand::Rng;
+use polars::{
+    frame::DataFrame,
+    prelude::{AnyValue, NamedFrom},
+    series::Series,
+};
 
-use crate::{base::DeepClone, IMArrayElement, IMDataFrameElement, IMElementCollection};
+use crate::{
+    base::{DeepClone, RwSlot},
+    IMArrayElement, IMDataFrameElement, IMElementCollection,
+};
 
+pub(crate) mod analysis;
+pub(crate) mod cache;
+pub(crate) mod components;
+pub(crate) mod concat;
+pub(crate) mod plan;
 pub(crate) mod helpers;
+pub(crate) mod history;
+
+/// Runs one step of a subset operation, inside a `subset_step` span when the `tracing`
+/// feature is enabled and announced through `log` otherwise.
+macro_rules! subset_step {
+    ($step:literal, $body:expr) => {{
+        #[cfg(feature = "tracing")]
+        let _step = tracing::debug_span!("subset_step", step = $step).entered();
+        #[cfg(not(feature = "tracing"))]
+        log!(Level::Debug, concat!("Subsetting step: ", $step));
+        $body
+    }};
+}
 
 pub struct IMAnnData {
     /// Number of observations (rows).
@@ -34,8 +66,25 @@ pub struct IMAnnData {
     uns: IMElementCollection,
     /// Layers of data.
     layers: IMAxisArrays,
+    /// Operations applied to the object, see [`IMAnnData::history`].
+    history: RwSlot<Vec<history::OpRecord>>,
 }
 
+/// The components of an `IMAnnData`, as returned by [`IMAnnData::into_parts`]: X, obs, var,
+/// obsm, obsp, varm, varp, uns, layers and the history.
+pub type IMAnnDataParts = (
+    IMArrayElement,
+    IMDataFrameElement,
+    IMDataFrameElement,
+    IMAxisArrays,
+    IMAxisArrays,
+    IMAxisArrays,
+    IMAxisArrays,
+    IMElementCollection,
+    IMAxisArrays,
+    Vec<history::OpRecord>,
+);
+
 impl IMAnnData {
     /// Creates a new `IMAnnData` instance.
     ///
@@ -57,12 +106,29 @@ impl IMAnnData {
         obs: IMDataFrameElement,
         var: IMDataFrameElement,
     ) -> anyhow::Result<Self> {
-        let n_obs = Dim::new(obs.get_data().height());
-        let n_vars = Dim::new(var.get_data().height());
+        let n_obs = Dim::new(obs.height());
+        let n_vars = Dim::new(var.height());
         // Validate dimensions
         let x_shape = x.get_shape()?;
-        if x_shape[0] != n_obs.get() || x_shape[1] != n_vars.get() {
-            return Err(anyhow::anyhow!("Dimensions mismatch"));
+        if x_shape.ndim() != 2 {
+            return Err(anyhow::anyhow!(
+                "X must be 2-dimensional, found shape {:?}",
+                x_shape
+            ));
+        }
+        if x_shape[0] != n_obs.get() {
+            return Err(anyhow::anyhow!(
+                "Dimensions mismatch: X has {} rows but obs has {}",
+                x_shape[0],
+                n_obs.get()
+            ));
+        }
+        if x_shape[1] != n_vars.get() {
+            return Err(anyhow::anyhow!(
+                "Dimensions mismatch: X has {} columns but var has {}",
+                x_shape[1],
+                n_vars.get()
+            ));
         }
         Ok(Self {
             n_obs: n_obs.clone(),
@@ -76,6 +142,7 @@ impl IMAnnData {
             varp: IMAxisArrays::new(Axis::Pairwise, n_vars.clone(), None),
             uns: IMElementCollection::new_empty(),
             layers: IMAxisArrays::new(Axis::RowColumn, n_obs.clone(), Some(n_vars.clone())),
+            history: RwSlot::new(Vec::new()),
         })
     }
 
@@ -99,14 +166,24 @@ impl IMAnnData {
         obs_names: Vec<String>,
         var_names: Vec<String>,
     ) -> anyhow::Result<Self> {
-        let s = matrix.shape();
+        let x = IMArrayElement::new_checked(matrix)?;
+        let s = x.get_shape()?;
         let n_obs = s[0];
         let n_vars = s[1];
 
         // Validate dimensions
-        if n_obs != obs_names.len() || n_vars != var_names.len() {
+        if n_obs != obs_names.len() {
+            return Err(anyhow::anyhow!(
+                "Dimensions mismatch: matrix has {} rows but {} obs names were given",
+                n_obs,
+                obs_names.len()
+            ));
+        }
+        if n_vars != var_names.len() {
             return Err(anyhow::anyhow!(
-                "Dimensions mismatch between matrix and index names"
+                "Dimensions mismatch: matrix has {} columns but {} var names were given",
+                n_vars,
+                var_names.len()
             ));
         }
 
@@ -121,7 +198,91 @@ impl IMAnnData {
         let var = IMDataFrameElement::new(var_df, var_index);
 
         // Create the IMAnnData object
-        IMAnnData::new(IMArrayElement::new(matrix), obs, var)
+        IMAnnData::new(x, obs, var)
+    }
+
+    /// Creates a new `IMAnnData` instance from frames that carry their names in an `index`
+    /// column.
+    ///
+    /// The observation and variable names are read from the `index` column of `obs_df` and
+    /// `var_df`, which stays part of the frames.
+    ///
+    /// # Arguments
+    ///
+    /// * `x` - Main data matrix.
+    /// * `obs_df` - Observation metadata with an `index` column.
+    /// * `var_df` - Variable metadata with an `index` column.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a frame has no `index` column or it contains nulls, or if the
+    /// frame heights do not match the shape of `x`.
+    pub fn new_from_frames(x: ArrayData, obs_df: DataFrame, var_df: DataFrame) -> anyhow::Result<Self> {
+        let names = |df: &DataFrame, axis: &str| -> anyhow::Result<Vec<String>> {
+            let index = df
+                .column("index")
+                .map_err(|_| anyhow::anyhow!("{} DataFrame has no index column", axis))?
+                .cast(&polars::datatypes::DataType::String)?;
+            index
+                .str()?
+                .into_iter()
+                .map(|name| {
+                    name.map(str::to_string)
+                        .ok_or_else(|| anyhow::anyhow!("{} index column contains nulls", axis))
+                })
+                .collect()
+        };
+        let obs_names = names(&obs_df, "obs")?;
+        let var_names = names(&var_df, "var")?;
+        IMAnnData::new_extended(x, obs_names, var_names, obs_df, var_df)
+    }
+
+    /// Creates a new basic `IMAnnData` instance from a dense `ndarray` matrix.
+    ///
+    /// # Arguments
+    ///
+    /// * `matrix` - Dense matrix with one row per observation.
+    /// * `obs_names` - Names for the observations (rows).
+    /// * `var_names` - Names for the variables (columns).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`IMAnnData::new_basic`].
+    pub fn from_dense<T>(
+        matrix: Array2<T>,
+        obs_names: Vec<String>,
+        var_names: Vec<String>,
+    ) -> anyhow::Result<Self>
+    where
+        ArrayData: From<ArrayD<T>>,
+    {
+        IMAnnData::new_basic(ArrayData::from(matrix.into_dyn()), obs_names, var_names)
+    }
+
+    /// Creates a new basic `IMAnnData` instance from a `nalgebra_sparse` CSR matrix.
+    ///
+    /// # Arguments
+    ///
+    /// * `matrix` - Sparse matrix with one row per observation.
+    /// * `obs_names` - Names for the observations (rows).
+    /// * `var_names` - Names for the variables (columns).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`IMAnnData::new_basic`].
+    pub fn from_csr<T>(
+        matrix: CsrMatrix<T>,
+        obs_names: Vec<String>,
+        var_names: Vec<String>,
+    ) -> anyhow::Result<Self>
+    where
+        DynCsrMatrix: From<CsrMatrix<T>>,
+    {
+        IMAnnData::new_basic(
+            ArrayData::CsrMatrix(DynCsrMatrix::from(matrix)),
+            obs_names,
+            var_names,
+        )
     }
 
     pub fn new_extended(
@@ -131,16 +292,36 @@ impl IMAnnData {
         obs_df: DataFrame,
         var_df: DataFrame,
     ) -> anyhow::Result<Self> {
-        let s = matrix.shape();
+        let x = IMArrayElement::new_checked(matrix)?;
+        let s = x.get_shape()?;
         let n_obs = s[0];
         let n_vars = s[1];
 
         // Validate dimensions
-        if n_obs != obs_names.len() || n_vars != var_names.len() {
+        if n_obs != obs_names.len() {
             return Err(anyhow::anyhow!(
-                "Dimensions mismatch between matrix and index names"
+                "Dimensions mismatch: matrix has {} rows but {} obs names were given",
+                n_obs,
+                obs_names.len()
             ));
         }
+        if n_vars != var_names.len() {
+            return Err(anyhow::anyhow!(
+                "Dimensions mismatch: matrix has {} columns but {} var names were given",
+                n_vars,
+                var_names.len()
+            ));
+        }
+        for (name, df, expected) in [("obs", &obs_df, n_obs), ("var", &var_df, n_vars)] {
+            if df.width() > 0 && df.height() != expected {
+                return Err(anyhow::anyhow!(
+                    "Dimensions mismatch: {} DataFrame has {} rows but the matrix has {}",
+                    name,
+                    df.height(),
+                    expected
+                ));
+            }
+        }
 
         // Create basic obs DataFrame and IMDataFrameElement
         let obs_index: DataFrameIndex = obs_names.into();
@@ -151,7 +332,7 @@ impl IMAnnData {
         let var = IMDataFrameElement::new(var_df, var_index);
 
         // Create the IMAnnData object
-        IMAnnData::new(IMArrayElement::new(matrix), obs, var)
+        IMAnnData::new(x, obs, var)
     }
 
     /// Returns the number of observations.
@@ -172,6 +353,189 @@ impl IMAnnData {
         self.var.get_index().into_vec()
     }
 
+    /// Renames observations according to `mapping`, leaving unmapped names unchanged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the renamed observation names are not unique.
+    pub fn rename_obs_names(&self, mapping: &HashMap<String, String>) -> anyhow::Result<()> {
+        self.obs.rename_index(mapping)
+    }
+
+    /// Renames variables according to `mapping`, leaving unmapped names unchanged.
+    ///
+    /// Both the var index and its `index` column are updated, e.g. to map gene IDs to symbols.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the renamed variable names are not unique.
+    pub fn rename_var_names(&self, mapping: &HashMap<String, String>) -> anyhow::Result<()> {
+        self.var.rename_index(mapping)
+    }
+
+    /// Returns the metadata of observation `i` keyed by column name.
+    ///
+    /// The observation name is included under the key `index`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `i` is out of bounds.
+    pub fn obs_row(&self, i: usize) -> anyhow::Result<HashMap<String, AnyValue<'static>>> {
+        self.obs.get_row(i)
+    }
+
+    /// Returns the observation names shared with another `IMAnnData` instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The `IMAnnData` instance to compare against.
+    ///
+    /// # Returns
+    ///
+    /// Returns the names present in both objects, in the order they appear in `self`.
+    pub fn obs_names_intersection(&self, other: &IMAnnData) -> Vec<String> {
+        let other_names: HashSet<String> = other.obs_names().into_iter().collect();
+        self.obs_names()
+            .into_iter()
+            .filter(|name| other_names.contains(name))
+            .collect()
+    }
+
+    /// Returns the observation names that are not present in another `IMAnnData` instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The `IMAnnData` instance to compare against.
+    ///
+    /// # Returns
+    ///
+    /// Returns the names only present in `self`, in the order they appear in `self`.
+    pub fn obs_names_difference(&self, other: &IMAnnData) -> Vec<String> {
+        let other_names: HashSet<String> = other.obs_names().into_iter().collect();
+        self.obs_names()
+            .into_iter()
+            .filter(|name| !other_names.contains(name))
+            .collect()
+    }
+
+    /// Reports observation names that occur more than once.
+    ///
+    /// # Returns
+    ///
+    /// Every duplicated name with the positions where it occurs, ascending. The names are
+    /// ordered by their first occurrence. The result is empty if all names are unique.
+    pub fn duplicate_obs_names(&self) -> Vec<(String, Vec<usize>)> {
+        duplicate_positions(self.obs_names())
+    }
+
+    /// Reports variable names that occur more than once.
+    ///
+    /// See [`IMAnnData::duplicate_obs_names`] for the layout of the result.
+    pub fn duplicate_var_names(&self) -> Vec<(String, Vec<usize>)> {
+        duplicate_positions(self.var_names())
+    }
+
+    /// Checks that all components agree on the number of observations and variables.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error describing the first inconsistency found.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let (n_obs, n_vars) = (self.n_obs(), self.n_vars());
+        let x_shape = self.x.get_shape()?;
+        if x_shape.ndim() != 2 || x_shape[0] != n_obs || x_shape[1] != n_vars {
+            return Err(anyhow::anyhow!(
+                "X has shape {:?}, expected ({}, {})",
+                x_shape,
+                n_obs,
+                n_vars
+            ));
+        }
+        for (name, frame, expected) in [("obs", &self.obs, n_obs), ("var", &self.var, n_vars)] {
+            let (height, index_len) = (frame.height(), frame.get_index().len());
+            if height != expected || index_len != expected {
+                return Err(anyhow::anyhow!(
+                    "{} has {} rows and {} index entries, expected {}",
+                    name,
+                    height,
+                    index_len,
+                    expected
+                ));
+            }
+        }
+        let arrays = [
+            ("layers", &self.layers, n_obs, Some(n_vars)),
+            ("obsm", &self.obsm, n_obs, None),
+            ("obsp", &self.obsp, n_obs, Some(n_obs)),
+            ("varm", &self.varm, n_vars, None),
+            ("varp", &self.varp, n_vars, Some(n_vars)),
+        ];
+        for (name, arrays, rows, cols) in arrays {
+            if arrays.dimensions().0.get() != rows {
+                return Err(anyhow::anyhow!(
+                    "{} is sized for {} rows, expected {}",
+                    name,
+                    arrays.dimensions().0.get(),
+                    rows
+                ));
+            }
+            for key in arrays.keys() {
+                let shape = arrays.get_array_shallow(&key)?.get_shape()?;
+                if shape[0] != rows || cols.is_some_and(|cols| shape[1] != cols) {
+                    return Err(anyhow::anyhow!(
+                        "{} entry {} has shape {:?}, expected {} rows{}",
+                        name,
+                        key,
+                        shape,
+                        rows,
+                        cols.map(|cols| format!(" and {} columns", cols))
+                            .unwrap_or_default()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `f` to every stored value of X in place.
+    ///
+    /// See [`IMArrayElement::apply_values_inplace`] for the handling of sparse matrices.
+    pub fn apply_x<F>(&self, f: F) -> anyhow::Result<()>
+    where
+        F: Fn(f64) -> f64,
+    {
+        self.x.apply_values_inplace(f)?;
+        self.record("apply_x", String::new());
+        Ok(())
+    }
+
+    /// Replaces every value of X by its square root in place.
+    ///
+    /// See [`IMArrayElement::sqrt_inplace`].
+    pub fn sqrt_x(&self) -> anyhow::Result<()> {
+        self.x.sqrt_inplace()?;
+        self.record("sqrt_x", String::new());
+        Ok(())
+    }
+
+    /// Replaces every value `v` of X by `ln(1 + v)` in place.
+    ///
+    /// See [`IMArrayElement::apply_values_inplace`] for the handling of sparse matrices.
+    pub fn log1p_x(&self) -> anyhow::Result<()> {
+        self.x.apply_values_inplace(f64::ln_1p)?;
+        self.record("log1p_x", String::new());
+        Ok(())
+    }
+
+    /// Multiplies every value of X by `factor` in place.
+    ///
+    /// See [`IMArrayElement::scale_inplace`].
+    pub fn scale_x(&self, factor: f64) -> anyhow::Result<()> {
+        self.x.scale_inplace(factor)?;
+        self.record("scale_x", format!("factor={}", factor));
+        Ok(())
+    }
+
     /// Returns a shallow clone of the main data matrix.
     ///
     /// # Notes
@@ -182,6 +546,11 @@ impl IMAnnData {
         self.x.clone()
     }
 
+    /// Returns `true` if the main data matrix contains no NaN or infinite values.
+    pub fn x_is_finite(&self) -> anyhow::Result<bool> {
+        Ok(!self.x.has_non_finite()?)
+    }
+
     /// Returns a shallow clone of the observations metadata.
     ///
     /// # Notes
@@ -217,7 +586,31 @@ impl IMAnnData {
     ///
     /// Returns an error if a layer with the same name already exists.
     pub fn add_layer(&mut self, name: String, data: IMArrayElement) -> anyhow::Result<()> {
-        self.layers.add_array(name, data)
+        self.layers.add_array(name.clone(), data)?;
+        self.record("add_layer", name);
+        Ok(())
+    }
+
+    /// Adds a layer of shape `(n_obs, n_vars)` filled with zeros.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the new layer.
+    /// * `dtype` - Numeric type of the layer.
+    /// * `sparse` - Whether to store the layer as a CSR matrix without entries or as a dense array.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a layer with the same name already exists, `dtype` is not numeric
+    /// or a dense layer would exceed the dense size limit.
+    pub fn add_empty_layer(
+        &mut self,
+        name: String,
+        dtype: ScalarType,
+        sparse: bool,
+    ) -> anyhow::Result<()> {
+        let data = crate::utils::zeros_of(dtype, sparse, self.n_obs(), self.n_vars())?;
+        self.add_layer(name, IMArrayElement::new(data))
     }
 
     /// Retrieves a deep clone of a layer by name.
@@ -254,6 +647,39 @@ impl IMAnnData {
         self.layers.get_array_shallow(name)
     }
 
+    /// Returns the name, shape and data type of every layer, sorted by name.
+    ///
+    /// The layers are inspected in one pass under the read lock of the layer collection.
+    /// Layers whose data was extracted are left out.
+    pub fn layer_infos(&self) -> Vec<(String, Vec<usize>, DataType)> {
+        self.layers.infos()
+    }
+
+    /// Returns a shallow handle to a layer together with its shape, dtype and nnz.
+    ///
+    /// The metadata is read under a single read lock, saving the separate lock round-trips
+    /// of `get_shape`, `get_type` and `nnz`, e.g. when rendering a list of layers.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the layer is not found or its data was extracted.
+    pub fn layer_entry(&self, name: &str) -> anyhow::Result<LayerEntry> {
+        LayerEntry::read(self.layers.get_array_shallow(name)?)
+    }
+
+    /// Retrieves a shallow clone of a layer by name, if it exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the layer to retrieve.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Some(IMArrayElement)` if the layer was found, otherwise `None`.
+    pub fn try_get_layer(&self, name: &str) -> Option<IMArrayElement> {
+        self.layers.try_get(name)
+    }
+
     /// Removes a layer by name and returns it.
     ///
     /// # Arguments
@@ -268,7 +694,9 @@ impl IMAnnData {
     ///
     /// Returns an error if the layer is not found.
     pub fn remove_layer(&mut self, name: &str) -> anyhow::Result<IMArrayElement> {
-        self.layers.remove_array(name)
+        let layer = self.layers.remove_array(name)?;
+        self.record("remove_layer", name.to_string());
+        Ok(layer)
     }
 
     /// Updates an existing layer with new data.
@@ -286,7 +714,22 @@ impl IMAnnData {
     ///
     /// Returns an error if the layer is not found.
     pub fn update_array(&mut self, name: &str, data: IMArrayElement) -> anyhow::Result<()> {
-        self.layers.update_array(name, data)
+        self.layers.update_array(name, data)?;
+        self.record("update_array", name.to_string());
+        Ok(())
+    }
+
+    /// Returns `true` if X and the layer `name` share the same underlying data.
+    ///
+    /// Aliased elements observe each other's modifications, e.g. after adding a shallow clone
+    /// of X as a layer.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the layer does not exist.
+    pub fn x_aliases_layer(&self, name: &str) -> anyhow::Result<bool> {
+        let layer = self.layers.get_array_shallow(name)?;
+        Ok(self.x.0.ptr_eq(&layer.0))
     }
 
     /// Returns a shallow clone of the observation multi-dimensional annotation.
@@ -319,6 +762,49 @@ impl IMAnnData {
         self.obsp.clone()
     }
 
+    /// Stores a dense `n_obs x n_obs` matrix, e.g. externally computed distances, in obsp.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Name of the new obsp entry.
+    /// * `matrix` - The pairwise matrix.
+    /// * `sparsify_threshold` - If set, entries below the threshold are dropped and the matrix
+    ///   is stored as CSR; otherwise it is stored as a dense array.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the matrix is not `n_obs x n_obs` or `key` already exists.
+    pub fn set_obsp_from_dense(
+        &self,
+        key: String,
+        matrix: ndarray::Array2<f64>,
+        sparsify_threshold: Option<f64>,
+    ) -> anyhow::Result<()> {
+        let n_obs = self.n_obs();
+        if matrix.nrows() != n_obs || matrix.ncols() != n_obs {
+            return Err(anyhow::anyhow!(
+                "Pairwise matrix has shape {:?}, expected ({}, {})",
+                matrix.shape(),
+                n_obs,
+                n_obs
+            ));
+        }
+        let data = match sparsify_threshold {
+            None => ArrayData::from(matrix.into_dyn()),
+            Some(threshold) => {
+                let mut coo = nalgebra_sparse::CooMatrix::new(n_obs, n_obs);
+                for ((i, j), &value) in matrix.indexed_iter() {
+                    if value >= threshold && value != 0.0 {
+                        coo.push(i, j, value);
+                    }
+                }
+                let csr = nalgebra_sparse::CsrMatrix::from(&coo);
+                ArrayData::CsrMatrix(anndata::data::DynCsrMatrix::from(csr))
+            }
+        };
+        self.obsp.add_array(key, IMArrayElement::new(data))
+    }
+
     /// Returns a shallow clone of the variable multi-dimensional annotation.
     ///
     /// # Returns
@@ -378,49 +864,240 @@ impl IMAnnData {
     pub fn layers(&self) -> IMAxisArrays {
         self.layers.clone()
     }
+
+    /// Keeps only the given obsm entries, see [`IMAxisArrays::retain`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a requested key does not exist.
+    pub fn retain_obsm(&self, keys: &[&str]) -> anyhow::Result<()> {
+        self.obsm.retain(keys)?;
+        self.record("retain_obsm", keys.join(","));
+        Ok(())
+    }
+
+    /// Keeps only the given varm entries, see [`IMAxisArrays::retain`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a requested key does not exist.
+    pub fn retain_varm(&self, keys: &[&str]) -> anyhow::Result<()> {
+        self.varm.retain(keys)?;
+        self.record("retain_varm", keys.join(","));
+        Ok(())
+    }
+
+    /// Keeps only the given layers, see [`IMAxisArrays::retain`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a requested layer does not exist.
+    pub fn retain_layers(&self, keys: &[&str]) -> anyhow::Result<()> {
+        self.layers.retain(keys)?;
+        self.record("retain_layers", keys.join(","));
+        Ok(())
+    }
+
+    /// Releases the memory of every component, even while shallow clones exist.
+    ///
+    /// All slots are emptied: X, obs, var, the entries of obsm, obsp, varm, varp, uns and
+    /// layers, and the collections themselves. Any later access through this object or any
+    /// shallow clone of it or of one of its components fails or panics, e.g.
+    /// `x().get_data()` returns an error.
+    ///
+    /// # Notes
+    ///
+    /// This invalidates all shallow clones, including components shared with other objects,
+    /// e.g. X of an object created with `ComponentSet::NONE`. Deep clones are not affected.
+    pub fn close(&self) {
+        for collection in [&self.obsm, &self.obsp, &self.varm, &self.varp, &self.layers] {
+            collection.release();
+        }
+        if let Some(uns) = self.uns.0.extract() {
+            uns.values().for_each(|element| element.0.drop());
+        }
+        self.x.0.drop();
+        self.obs.release();
+        self.var.release();
+    }
+
+    /// Consumes the object and returns its components by move.
+    ///
+    /// # Returns
+    ///
+    /// The components in the order X, obs, var, obsm, obsp, varm, varp, uns, layers, history.
+    ///
+    /// # Notes
+    ///
+    /// The components keep sharing their storage with any shallow clones taken earlier. The
+    /// returned axis collections keep the dimensions of this object, use
+    /// [`IMAnnData::from_parts`] to reassemble them.
+    pub fn into_parts(self) -> IMAnnDataParts {
+        (
+            self.x,
+            self.obs,
+            self.var,
+            self.obsm,
+            self.obsp,
+            self.varm,
+            self.varp,
+            self.uns,
+            self.layers,
+            self.history.extract().unwrap_or_default(),
+        )
+    }
+
+    /// Assembles an object from its components, the inverse of [`IMAnnData::into_parts`].
+    ///
+    /// The components are moved into the object without copying.
+    ///
+    /// # Arguments
+    ///
+    /// * `parts` - X, obs, var, obsm, obsp, varm, varp, uns, layers and the history.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if X does not match the heights of obs and var, or if an axis
+    /// collection has the wrong kind of axis, is sized for other dimensions or holds an entry
+    /// that does not fit them.
+    pub fn from_parts(parts: IMAnnDataParts) -> anyhow::Result<Self> {
+        let (x, obs, var, obsm, obsp, varm, varp, uns, layers, history) = parts;
+        let mut adata = IMAnnData::new(x, obs, var)?;
+        let (n_obs, n_vars) = (adata.n_obs(), adata.n_vars());
+        let collections = [
+            ("obsm", &obsm, Axis::Row, n_obs, None),
+            ("obsp", &obsp, Axis::Pairwise, n_obs, Some(n_obs)),
+            ("varm", &varm, Axis::Row, n_vars, None),
+            ("varp", &varp, Axis::Pairwise, n_vars, Some(n_vars)),
+            ("layers", &layers, Axis::RowColumn, n_obs, Some(n_vars)),
+        ];
+        for (name, arrays, axis, rows, cols) in collections {
+            if std::mem::discriminant(&arrays.axis()) != std::mem::discriminant(&axis) {
+                return Err(anyhow::anyhow!("{} has the wrong kind of axis", name));
+            }
+            let (dim1, dim2) = arrays.dimensions();
+            let (found_rows, found_cols) = (dim1.get(), dim2.map(|d| d.get()));
+            if found_rows != rows || found_cols != cols {
+                return Err(anyhow::anyhow!(
+                    "{} is sized for {} rows and {:?} columns, expected {} rows and {:?} columns",
+                    name,
+                    found_rows,
+                    found_cols,
+                    rows,
+                    cols
+                ));
+            }
+        }
+        adata.obsm = obsm;
+        adata.obsp = obsp;
+        adata.varm = varm;
+        adata.varp = varp;
+        adata.uns = uns;
+        adata.layers = layers;
+        adata.history = RwSlot::new(history);
+        // The entries of the collections are checked against their dimensions here
+        adata.validate()?;
+        Ok(adata)
+    }
     // !!!!! THIS IS VERY UNSAFE as it might allow for lock races, requires wrapping IMAnnData into a RwLock in order to prevent that, or transition to async data running of functions !!!!!
     pub fn subset_inplace(&mut self, selection: &[&SelectInfoElem]) -> anyhow::Result<()> {
+        #[cfg(feature = "tracing")]
+        let (span, start) = (self.subset_span(selection, true), std::time::Instant::now());
+        #[cfg(not(feature = "tracing"))]
         log!(Level::Debug, "Staring subsetting inplace");
         if selection.len() != 2 {
             return Err(anyhow::anyhow!("Invalid selection, only 2-dimensional selections are supported on the in-memory anndata object!"));
         }
 
-        let obs_sel = selection[0];
-        let var_sel = selection[1];
+        // Validate the selections against every component before any work is done, the
+        // derived selections of axis arrays are in bounds whenever their shapes are consistent
+        subset_step!("validate", {
+            selection[0].bound_check(self.n_obs())?;
+            selection[1].bound_check(self.n_vars())?;
+            self.validate()
+                .map_err(|e| anyhow::anyhow!("Cannot subset an inconsistent object: {}", e))?;
+        });
 
-        // check if these changes are valid
-        log!(Level::Debug, "Performing boundchecks");
-        obs_sel.bound_check(self.n_obs())?;
-        var_sel.bound_check(self.n_vars())?;
-
-        log!(Level::Debug, "Subsetting X");
-        self.x.subset_inplace(selection)?;
-        log!(Level::Debug, "Subsetting obs");
-        self.obs.subset_inplace(obs_sel)?;
-        log!(Level::Debug, "Subsetting var");
-        self.var.subset_inplace(var_sel)?;
-        log!(Level::Debug, "Subsetting layers");
-        self.layers.subset_inplace(selection)?;
-        log!(Level::Debug, "Subsetting obsm");
-        self.obsm
-            .subset_inplace(vec![&obs_sel.clone(), &SelectInfoElem::full()].as_slice())?;
-        log!(Level::Debug, "Subsetting obsp");
-        self.obsp
-            .subset_inplace(vec![&obs_sel.clone(), &obs_sel.clone()].as_slice())?;
-        log!(Level::Debug, "Subsetting varm");
-        self.varm
-            .subset_inplace(vec![&var_sel.clone(), &SelectInfoElem::full()].as_slice())?;
-        log!(Level::Debug, "Subsetting varp");
-        self.varp
-            .subset_inplace(vec![&var_sel.clone(), &var_sel.clone()].as_slice())?;
-
-        self.n_obs = Dim::new(self.obs.get_data().height());
-        self.n_vars = Dim::new(self.var.get_data().height());
+        // Compute every component first, so a failing subset leaves the object untouched
+        let subset = self.subset(selection)?;
+
+        subset_step!("swap", {
+            self.x.0.swap(&subset.x.0);
+            self.obs.swap_contents(&subset.obs);
+            self.var.swap_contents(&subset.var);
+            self.layers.swap_contents(&subset.layers);
+            self.obsm.swap_contents(&subset.obsm);
+            self.obsp.swap_contents(&subset.obsp);
+            self.varm.swap_contents(&subset.varm);
+            self.varp.swap_contents(&subset.varp);
+        });
 
+        self.n_obs = Dim::new(self.obs.height());
+        self.n_vars = Dim::new(self.var.height());
+        self.record(
+            "subset_inplace",
+            format!("{} obs x {} vars", self.n_obs(), self.n_vars()),
+        );
+
+        #[cfg(feature = "tracing")]
+        span.record("elapsed_ms", start.elapsed().as_secs_f64() * 1000.0);
         Ok(())
     }
 
+    /// Converts the object into an `anndata` object for use with other `anndata-rs` code.
+    ///
+    /// # Notes
+    ///
+    /// `anndata-rs` does not offer an in-memory backend, the returned object is therefore backed
+    /// by an HDF5 file in a temporary directory, which is removed when the [`crate::TempAnnData`]
+    /// is dropped. Use [`crate::convert_to_anndata`] to choose the backend and location of the
+    /// file.
+    pub fn to_anndata_mem(&self) -> anyhow::Result<crate::TempAnnData> {
+        crate::converter::convert_to_anndata_temp(self)
+    }
+
+    /// Randomly samples observations and returns them as a new `IMAnnData` instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Number of observations to sample.
+    /// * `replace` - Whether observations may be drawn more than once.
+    /// * `seed` - Seed of the random number generator, the same seed yields the same sample.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `n` exceeds the number of observations while sampling without replacement.
+    pub fn sample_obs(&self, n: usize, replace: bool, seed: u64) -> anyhow::Result<Self> {
+        self.sample_obs_with_rng(n, replace, &mut crate::utils::rng_from_seed(seed))
+    }
+
+    /// Randomly samples observations using the provided random number generator.
+    ///
+    /// See [`IMAnnData::sample_obs`] for details.
+    pub fn sample_obs_with_rng<R: Rng + ?Sized>(
+        &self,
+        n: usize,
+        replace: bool,
+        rng: &mut R,
+    ) -> anyhow::Result<Self> {
+        let indices = crate::utils::sample_indices(rng, self.n_obs(), n, replace)?;
+        let obs_sel = SelectInfoElem::Index(indices);
+        self.subset(&[&obs_sel, &SelectInfoElem::full()])
+    }
+
     pub fn subset(&self, selection: &[&SelectInfoElem]) -> anyhow::Result<Self> {
+        self.subset_skipping_layers(selection, &HashSet::new())
+    }
+
+    /// Subsets like [`IMAnnData::subset`], leaving the layers named in `skip_layers` out of
+    /// the result.
+    pub(crate) fn subset_skipping_layers(
+        &self,
+        selection: &[&SelectInfoElem],
+        skip_layers: &HashSet<String>,
+    ) -> anyhow::Result<Self> {
+        #[cfg(feature = "tracing")]
+        let (span, start) = (self.subset_span(selection, false), std::time::Instant::now());
         if selection.len() != 2 {
             return Err(anyhow::anyhow!("Invalid selection, only 2-dimensional selections are supported on the in-memory anndata object!"));
         }
@@ -429,30 +1106,42 @@ impl IMAnnData {
         let var_sel = selection[1];
 
         // check if these changes are valid
-        obs_sel.bound_check(self.n_obs())?;
-        var_sel.bound_check(self.n_vars())?;
-
-        let obs = self.obs.subset(obs_sel)?;
-        let var = self.var.subset(var_sel)?;
-        let layers = self.layers.subset(selection)?;
-        let obsm = self
-            .obsm
-            .subset(vec![&obs_sel.clone(), &SelectInfoElem::full()].as_slice())?;
-        let obsp = self
-            .obsp
-            .subset(vec![&obs_sel.clone(), &obs_sel.clone()].as_slice())?;
-        let varm = self
-            .varm
-            .subset(vec![&var_sel.clone(), &SelectInfoElem::full()].as_slice())?;
-        let varp = self
-            .varp
-            .subset(vec![&var_sel.clone(), &var_sel.clone()].as_slice())?;
-
-        let x = self.x.subset(selection)?;
+        subset_step!("bound_check", {
+            obs_sel.bound_check(self.n_obs())?;
+            var_sel.bound_check(self.n_vars())?;
+        });
 
-        Ok(IMAnnData {
-            n_obs: Dim::new(obs.get_data().height()),
-            n_vars: Dim::new(var.get_data().height()),
+        let obs = subset_step!("obs", self.obs.subset(obs_sel)?);
+        let var = subset_step!("var", self.var.subset(var_sel)?);
+        let layers = subset_step!("layers", self.layers.subset_except(selection, skip_layers)?);
+        let obsm = subset_step!(
+            "obsm",
+            self.obsm
+                .subset(vec![&obs_sel.clone(), &SelectInfoElem::full()].as_slice())?
+        );
+        let obsp = subset_step!(
+            "obsp",
+            self.obsp
+                .subset(vec![&obs_sel.clone(), &obs_sel.clone()].as_slice())?
+        );
+        let varm = subset_step!(
+            "varm",
+            self.varm
+                .subset(vec![&var_sel.clone(), &SelectInfoElem::full()].as_slice())?
+        );
+        let varp = subset_step!(
+            "varp",
+            self.varp
+                .subset(vec![&var_sel.clone(), &var_sel.clone()].as_slice())?
+        );
+
+        let x = subset_step!("X", self.x.subset(selection)?);
+
+        #[cfg(feature = "tracing")]
+        span.record("elapsed_ms", start.elapsed().as_secs_f64() * 1000.0);
+        let subset = IMAnnData {
+            n_obs: Dim::new(obs.height()),
+            n_vars: Dim::new(var.height()),
             x,
             obs,
             obsm,
@@ -462,14 +1151,312 @@ impl IMAnnData {
             varp,
             uns: self.uns.clone(),
             layers,
-        })
+            history: self.history.deep_clone(),
+        };
+        subset.record(
+            "subset",
+            format!("{} obs x {} vars", subset.n_obs(), subset.n_vars()),
+        );
+        Ok(subset)
+    }
+
+    /// Selects the observations whose entry in `mask` is `true`, keeping all variables.
+    ///
+    /// # Arguments
+    ///
+    /// * `mask` - One entry per observation.
+    /// * `remove_unused_categories` - If set, categorical obs columns of the result only keep
+    ///   the categories still present, like pandas' `remove_unused_categories`. var is not
+    ///   subset, so its columns keep all categories.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the mask length does not match the number of observations.
+    pub fn subset_obs_mask(
+        &self,
+        mask: &[bool],
+        remove_unused_categories: bool,
+    ) -> anyhow::Result<Self> {
+        if mask.len() != self.n_obs() {
+            return Err(anyhow::anyhow!(
+                "Mask has length {}, expected {}",
+                mask.len(),
+                self.n_obs()
+            ));
+        }
+        let obs_sel = crate::utils::mask_to_select_info_elem(mask);
+        let subset = self.subset(&[&obs_sel, &SelectInfoElem::full()])?;
+        if remove_unused_categories {
+            subset.obs.remove_unused_categories()?;
+        }
+        Ok(subset)
+    }
+
+    /// Drops unused categories from all categorical obs and var columns.
+    ///
+    /// Subsetting keeps every category of the original columns, call this afterwards to only
+    /// keep the categories that are still present.
+    pub fn remove_unused_categories(&self) -> anyhow::Result<()> {
+        self.obs.remove_unused_categories()?;
+        self.var.remove_unused_categories()?;
+        self.record("remove_unused_categories", String::new());
+        Ok(())
+    }
+
+    /// Applies `f` to consecutive chunks of observations and writes the results back.
+    ///
+    /// Each chunk of at most `size` observations is materialized as its own `IMAnnData`
+    /// holding the chunk's rows of X, the layers and obs, together with a copy of var. Axis
+    /// arrays and uns are not copied into the chunks. `f` transforms the chunk, and the rows
+    /// of X and the layers are copied into the new matrices right away, so besides the
+    /// original data only the new matrices and one chunk are held in memory. The new matrices
+    /// replace X and the layers once every chunk succeeded, changes to other components of a
+    /// chunk are discarded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `size` is zero, `f` fails, changes the shape of a chunk, or leaves
+    /// X or a layer of a chunk with a different storage format or dtype than in the first
+    /// chunk. X and the layers must be numeric arrays or CSR matrices. The object is left
+    /// unchanged in case of an error.
+    pub fn map_obs_chunks<F>(&mut self, size: usize, mut f: F) -> anyhow::Result<()>
+    where
+        F: FnMut(&mut IMAnnData) -> anyhow::Result<()>,
+    {
+        if size == 0 {
+            return Err(anyhow::anyhow!("Chunk size must be greater than zero"));
+        }
+        let (n_obs, n_vars) = (self.n_obs(), self.n_vars());
+        let layer_keys = self.layers.keys();
+        let mut x_stack = crate::utils::RowStack::new(n_obs, n_vars);
+        let mut layer_stacks: Vec<_> = layer_keys
+            .iter()
+            .map(|_| crate::utils::RowStack::new(n_obs, n_vars))
+            .collect();
+        // Checks the shape of a transformed chunk and copies its rows into the result
+        let push_chunk = |name: &str,
+                          stack: &mut crate::utils::RowStack,
+                          data: ArrayData,
+                          expected: [usize; 2]|
+         -> anyhow::Result<()> {
+            let shape = data.shape();
+            if shape.ndim() != 2 || shape[0] != expected[0] || shape[1] != expected[1] {
+                return Err(anyhow::anyhow!(
+                    "Chunk {} shape {:?} does not match expected shape {:?}",
+                    name,
+                    shape,
+                    expected
+                ));
+            }
+            stack
+                .push(&data)
+                .map_err(|e| anyhow::anyhow!("Cannot reassemble chunk {}: {}", name, e))
+        };
+
+        let mut start = 0;
+        while start < n_obs {
+            let end = (start + size).min(n_obs);
+            let obs_sel = SelectInfoElem::Slice(ndarray::Slice::from(start..end));
+            let mut chunk = self.obs_chunk(&obs_sel)?;
+            f(&mut chunk)?;
+
+            let expected = [end - start, n_vars];
+            push_chunk("X", &mut x_stack, chunk.x.get_data()?, expected)?;
+            for (key, stack) in layer_keys.iter().zip(layer_stacks.iter_mut()) {
+                let layer = chunk.layers.get_array_shallow(key)?.get_data()?;
+                push_chunk(&format!("layer {}", key), stack, layer, expected)?;
+            }
+            start = end;
+        }
+
+        // Assemble everything before the first write so that a failure leaves the object intact
+        let x = match x_stack.finish()? {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let layers = layer_keys
+            .iter()
+            .zip(layer_stacks)
+            .map(|(key, stack)| {
+                let data = stack
+                    .finish()?
+                    .ok_or_else(|| anyhow::anyhow!("Layer {} received no chunks", key))?;
+                Ok((self.layers.get_array_shallow(key)?, data))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.x.set_data(x)?;
+        for (layer, data) in layers {
+            layer.set_data(data)?;
+        }
+        self.record("map_obs_chunks", format!("size={}", size));
+        Ok(())
+    }
+
+    /// Copies the selected observations of X, the layers and obs into a new object sharing
+    /// no data with `self`, for [`IMAnnData::map_obs_chunks`].
+    fn obs_chunk(&self, obs_sel: &SelectInfoElem) -> anyhow::Result<IMAnnData> {
+        let selection = [obs_sel, &SelectInfoElem::full()];
+        let chunk = IMAnnData::new(
+            self.x.subset(&selection)?,
+            self.obs.subset(obs_sel)?,
+            self.var.deep_clone(),
+        )?;
+        for key in self.layers.keys() {
+            let layer = self.layers.get_array_shallow(&key)?.subset(&selection)?;
+            chunk.layers.add_array(key, layer)?;
+        }
+        Ok(chunk)
+    }
+
+    /// Enters the `subset` span describing the dimensions and the size of the selection.
+    #[cfg(feature = "tracing")]
+    fn subset_span(
+        &self,
+        selection: &[&SelectInfoElem],
+        inplace: bool,
+    ) -> tracing::span::EnteredSpan {
+        let selected = |i: usize, bound: usize| {
+            selection
+                .get(i)
+                .and_then(|s| crate::utils::select_info_elem_to_indices(s, bound).ok())
+                .map(|indices| indices.len())
+        };
+        tracing::debug_span!(
+            "subset",
+            inplace,
+            n_obs = self.n_obs(),
+            n_vars = self.n_vars(),
+            obs_selected = selected(0, self.n_obs()),
+            var_selected = selected(1, self.n_vars()),
+            elapsed_ms = tracing::field::Empty,
+        )
+        .entered()
     }
 }
 
 use std::fmt;
 
-impl fmt::Display for IMAnnData {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+/// Number of X columns shown in the preview of [`IMAnnData::display_with`].
+const DISPLAY_PREVIEW_COLS: usize = 8;
+
+/// Controls the output of [`IMAnnData::display_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayOptions {
+    /// Maximum number of keys listed per collection, further keys are replaced by an ellipsis.
+    pub max_keys: usize,
+    /// Whether the dtypes of X and the layers are shown.
+    pub show_dtypes: bool,
+    /// Number of decimal places of previewed values.
+    pub float_precision: usize,
+    /// Number of rows of X previewed, `0` disables the preview.
+    pub preview_rows: usize,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        DisplayOptions {
+            max_keys: 20,
+            show_dtypes: true,
+            float_precision: 3,
+            preview_rows: 0,
+        }
+    }
+}
+
+/// Joins `items` with commas, listing at most `max` of them followed by an ellipsis.
+fn truncated_list(items: &[String], max: usize) -> String {
+    if items.len() <= max {
+        return items.join(", ");
+    }
+    let mut listed = items[..max].to_vec();
+    listed.push(format!("... ({} more)", items.len() - max));
+    listed.join(", ")
+}
+
+impl IMAnnData {
+    /// Renders a summary of the object, as the `Display` impl does, with custom options.
+    ///
+    /// # Arguments
+    ///
+    /// * `opts` - Limits on listed keys, dtype visibility and the X preview.
+    ///
+    /// # Returns
+    ///
+    /// The rendered summary. Errors while reading components are rendered in place.
+    pub fn display_with(&self, opts: DisplayOptions) -> String {
+        let mut out = String::new();
+        if let Err(e) = self.write_summary(&mut out, &opts) {
+            out.push_str(&format!("<error: {}>", e));
+        }
+        out
+    }
+
+    /// Describes the structure of the object as a JSON document, without any data.
+    ///
+    /// The document has the keys `n_obs`, `n_vars`, `X` (shape and dtype), `layers`, `obs`
+    /// and `var` (column names and dtypes), `obsm`, `obsp`, `varm` and `varp` (keys with
+    /// shapes and dtypes), `uns` (keys only) and `history` (see [`IMAnnData::history`]).
+    /// Collections are sorted by key.
+    ///
+    /// # Notes
+    ///
+    /// Components that cannot be read, e.g. an extracted X, are reported as `null`.
+    pub fn manifest(&self) -> serde_json::Value {
+        use serde_json::{json, Map, Value};
+
+        let arrays = |infos: Vec<(String, Vec<usize>, DataType)>| -> Value {
+            infos
+                .into_iter()
+                .map(|(key, shape, dtype)| {
+                    (key, json!({ "shape": shape, "dtype": dtype.to_string() }))
+                })
+                .collect::<Map<String, Value>>()
+                .into()
+        };
+        let columns = |frame: &IMDataFrameElement| -> Value {
+            frame.with_df(|df| {
+                df.get_columns()
+                    .iter()
+                    .map(|series| json!({ "name": series.name(), "dtype": series.dtype().to_string() }))
+                    .collect::<Vec<_>>()
+                    .into()
+            })
+        };
+        let x = match (self.x.get_shape(), self.x.get_type()) {
+            (Ok(shape), Ok(dtype)) => {
+                let shape: Vec<usize> = (0..shape.ndim()).map(|i| shape[i]).collect();
+                json!({ "shape": shape, "dtype": dtype.to_string() })
+            }
+            _ => Value::Null,
+        };
+        let mut uns: Vec<String> = self.uns.0.read_inner().keys().cloned().collect();
+        uns.sort();
+
+        json!({
+            "n_obs": self.n_obs(),
+            "n_vars": self.n_vars(),
+            "X": x,
+            "layers": arrays(self.layers.infos()),
+            "obs": columns(&self.obs),
+            "var": columns(&self.var),
+            "obsm": arrays(self.obsm.infos()),
+            "obsp": arrays(self.obsp.infos()),
+            "varm": arrays(self.varm.infos()),
+            "varp": arrays(self.varp.infos()),
+            "uns": uns,
+            "history": self
+                .history()
+                .into_iter()
+                .map(|record| json!({
+                    "operation": record.operation,
+                    "details": record.details,
+                    "timestamp": record.timestamp,
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    fn write_summary(&self, f: &mut impl fmt::Write, opts: &DisplayOptions) -> anyhow::Result<()> {
         writeln!(f, "IMAnnData Object")?;
         writeln!(f, "-----------------")?;
         writeln!(
@@ -480,44 +1467,211 @@ impl fmt::Display for IMAnnData {
         )?;
 
         // X matrix info
-        let x_shape = self.x().get_shape().map_err(|_| fmt::Error)?;
-        writeln!(
-            f,
-            "X: {:?} {}",
-            x_shape,
-            self.x().get_type().map_err(|_| fmt::Error)?
-        )?;
+        let x_shape = self.x.get_shape()?;
+        if opts.show_dtypes {
+            writeln!(f, "X: {:?} {}", x_shape, self.x.get_type()?)?;
+        } else {
+            writeln!(f, "X: {:?}", x_shape)?;
+        }
+        if opts.preview_rows > 0 && x_shape.ndim() == 2 {
+            let rows = SelectInfoElem::Index((0..opts.preview_rows.min(x_shape[0])).collect());
+            let cols = SelectInfoElem::Index((0..DISPLAY_PREVIEW_COLS.min(x_shape[1])).collect());
+            if let Ok(preview) = self.x.subset(&[&rows, &cols]).and_then(|x| x.to_dense_f64()) {
+                let more = if x_shape[1] > DISPLAY_PREVIEW_COLS { ", ..." } else { "" };
+                for row in preview.rows() {
+                    let values: Vec<String> = row
+                        .iter()
+                        .map(|v| format!("{:.*}", opts.float_precision, v))
+                        .collect();
+                    writeln!(f, "  [{}{}]", values.join(", "), more)?;
+                }
+            }
+        }
 
         // Layers info
-        let layer_keys = self.layers().keys();
+        let layers: Vec<String> = self
+            .layer_infos()
+            .into_iter()
+            .map(|(name, shape, dtype)| {
+                if opts.show_dtypes {
+                    format!("{} {:?} {}", name, shape, dtype)
+                } else {
+                    format!("{} {:?}", name, shape)
+                }
+            })
+            .collect();
         writeln!(
             f,
             "Layers: {} - {}",
-            layer_keys.len(),
-            layer_keys.join(", ")
+            layers.len(),
+            truncated_list(&layers, opts.max_keys)
         )?;
 
         // Obs and Var info
         writeln!(
             f,
             "Obs DataFrame Shape: {:?}",
-            self.obs().get_data().shape()
+            (self.obs.height(), self.obs.width())
         )?;
         writeln!(
             f,
             "Var DataFrame Shape: {:?}",
-            self.var().get_data().shape()
+            (self.var.height(), self.var.width())
         )?;
 
         // Obsm, Obsp, Varm, Varp info
-        writeln!(f, "Obsm keys: {}", self.obsm().keys().join(", "))?;
-        writeln!(f, "Obsp keys: {}", self.obsp().keys().join(", "))?;
-        writeln!(f, "Varm keys: {}", self.varm().keys().join(", "))?;
-        writeln!(f, "Varp keys: {}", self.varp().keys().join(", "))?;
+        for (label, collection) in [
+            ("Obsm", &self.obsm),
+            ("Obsp", &self.obsp),
+            ("Varm", &self.varm),
+            ("Varp", &self.varp),
+        ] {
+            let mut keys = collection.keys();
+            keys.sort();
+            writeln!(f, "{} keys: {}", label, truncated_list(&keys, opts.max_keys))?;
+        }
+        Ok(())
+    }
+}
 
-        // Uns info
+impl fmt::Display for IMAnnData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_summary(f, &DisplayOptions::default())
+            .map_err(|_| fmt::Error)
+    }
+}
 
-        Ok(())
+impl IMAnnData {
+    /// Creates an independent deep copy that reflects a single point in time.
+    ///
+    /// The read locks of all components are acquired in the order X, obs, var, obsm, obsp,
+    /// varm, varp, uns, layers and held until every component is copied, so no component can
+    /// be replaced or swapped, e.g. by `subset_inplace`, while the snapshot is taken. Writers
+    /// are only blocked for the duration of the copy.
+    ///
+    /// # Notes
+    ///
+    /// Individual arrays inside obsm, layers and the other collections are locked one at a
+    /// time while they are copied.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a component slot is empty.
+    pub fn snapshot(&self) -> anyhow::Result<IMAnnData> {
+        fn present<'a, T>(slot: &'a Option<T>, name: &str) -> anyhow::Result<&'a T> {
+            slot.as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Cannot snapshot {}, the slot is empty", name))
+        }
+
+        let x_guard = self.x.0.lock_read();
+        let obs_guard = self.obs.slot().lock_read();
+        let var_guard = self.var.slot().lock_read();
+        let obsm_guard = self.obsm.0.lock_read();
+        let obsp_guard = self.obsp.0.lock_read();
+        let varm_guard = self.varm.0.lock_read();
+        let varp_guard = self.varp.0.lock_read();
+        let uns_guard = self.uns.0.lock_read();
+        let layers_guard = self.layers.0.lock_read();
+
+        let obs = present(&obs_guard, "obs")?.clone();
+        let var = present(&var_guard, "var")?.clone();
+        let axis_arrays = |guard: &Option<helpers::InnerIMAxisArray>, name: &str| {
+            present(guard, name).map(|inner| IMAxisArrays(RwSlot::new(inner.deep_clone())))
+        };
+        let uns = present(&uns_guard, "uns")?
+            .iter()
+            .map(|(key, element)| (key.clone(), element.deep_clone()))
+            .collect();
+        Ok(IMAnnData {
+            n_obs: Dim::new(obs.index.len()),
+            n_vars: Dim::new(var.index.len()),
+            x: IMArrayElement::new(present(&x_guard, "X")?.clone()),
+            obs: IMDataFrameElement::from_inner(obs),
+            obsm: axis_arrays(&obsm_guard, "obsm")?,
+            obsp: axis_arrays(&obsp_guard, "obsp")?,
+            var: IMDataFrameElement::from_inner(var),
+            varm: axis_arrays(&varm_guard, "varm")?,
+            varp: axis_arrays(&varp_guard, "varp")?,
+            uns: IMElementCollection(RwSlot::new(uns)),
+            layers: axis_arrays(&layers_guard, "layers")?,
+            history: self.history.deep_clone(),
+        })
+    }
+
+    /// Compares the contents of two objects, ignoring storage sharing.
+    ///
+    /// # Returns
+    ///
+    /// `true` if X, obs, var, all axis arrays and uns hold equal data under the same keys.
+    /// Frames are compared with missing values treated as equal, and elements that cannot be
+    /// read (for example extracted ones) make the objects unequal.
+    pub fn content_equals(&self, other: &IMAnnData) -> bool {
+        self.compare_contents(other, &|a, b| a == b)
+    }
+
+    /// Compares the contents of two objects, allowing small differences in numeric arrays.
+    ///
+    /// Like [`IMAnnData::content_equals`], but X, layers and the axis arrays match if every
+    /// value satisfies `|a - b| <= atol + rtol * |b|`, with `b` taken from `other`. Numeric
+    /// matrices are compared by value, so a sparse and a dense matrix with the same values
+    /// match. obs, var and uns are still compared exactly.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - Object to compare with.
+    /// * `rtol` - Relative tolerance.
+    /// * `atol` - Absolute tolerance.
+    pub fn content_equals_approx(&self, other: &IMAnnData, rtol: f64, atol: f64) -> bool {
+        self.compare_contents(other, &|a, b| crate::utils::arrays_close(a, b, rtol, atol))
+    }
+
+    /// Compares all components, using `arrays_match` for X, layers and the axis arrays.
+    fn compare_contents(
+        &self,
+        other: &IMAnnData,
+        arrays_match: &dyn Fn(&ArrayData, &ArrayData) -> bool,
+    ) -> bool {
+        let elements_match = |a: &IMArrayElement, b: &IMArrayElement| {
+            matches!((a.get_data(), b.get_data()), (Ok(a), Ok(b)) if arrays_match(&a, &b))
+        };
+        let frames_equal = |a: &IMDataFrameElement, b: &IMDataFrameElement| {
+            a.get_index().into_vec() == b.get_index().into_vec()
+                && a.get_data().equals_missing(&b.get_data())
+        };
+        let collections_match = |a: &IMAxisArrays, b: &IMAxisArrays| {
+            let (mut keys, mut other_keys) = (a.keys(), b.keys());
+            keys.sort();
+            other_keys.sort();
+            keys == other_keys
+                && keys.iter().all(|key| {
+                    match (a.get_array_shallow(key), b.get_array_shallow(key)) {
+                        (Ok(x), Ok(y)) => elements_match(&x, &y),
+                        _ => false,
+                    }
+                })
+        };
+
+        let uns_equal = {
+            let (uns, other_uns) = (self.uns.0.read_inner(), other.uns.0.read_inner());
+            uns.len() == other_uns.len()
+                && uns.iter().all(|(key, element)| {
+                    other_uns.get(key).is_some_and(|other_element| {
+                        matches!(
+                            (element.get_data(), other_element.get_data()),
+                            (Ok(a), Ok(b)) if a == b
+                        )
+                    })
+                })
+        };
+        elements_match(&self.x, &other.x)
+            && uns_equal
+            && frames_equal(&self.obs, &other.obs)
+            && frames_equal(&self.var, &other.var)
+            && collections_match(&self.layers, &other.layers)
+            && collections_match(&self.obsm, &other.obsm)
+            && collections_match(&self.obsp, &other.obsp)
+            && collections_match(&self.varm, &other.varm)
+            && collections_match(&self.varp, &other.varp)
     }
 }
 
@@ -535,6 +1689,21 @@ impl DeepClone for IMAnnData {
             varp: self.varp.deep_clone(),
             uns: self.uns.deep_clone(),
             layers: self.layers.deep_clone(),
+            history: self.history.deep_clone(),
         }
     }
 }
+
+/// Groups the positions of repeated entries of `names`, ordered by first occurrence.
+fn duplicate_positions(names: Vec<String>) -> Vec<(String, Vec<usize>)> {
+    let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, name) in names.iter().enumerate() {
+        positions.entry(name.clone()).or_default().push(i);
+    }
+    let mut duplicates: Vec<(String, Vec<usize>)> = positions
+        .into_iter()
+        .filter(|(_, found)| found.len() > 1)
+        .collect();
+    duplicates.sort_by_key(|(_, found)| found[0]);
+    duplicates
+}
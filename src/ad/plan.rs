@@ -0,0 +1,276 @@
+use std::collections::HashSet;
+
+use anndata::data::SelectInfoElem;
+use polars::datatypes::DataType;
+
+use crate::{IMAnnData, IMDataFrameElement};
+
+/// A single selection criterion along one axis.
+#[derive(Debug, Clone)]
+enum Criterion {
+    /// Keep positions whose mask entry is `true`.
+    Mask(Vec<bool>),
+    /// Keep the entries with one of the given names.
+    Names(HashSet<String>),
+    /// Keep entries whose numeric metadata column lies within the inclusive bounds.
+    Range {
+        column: String,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+}
+
+/// Accumulates obs and var selection criteria and applies them in a single subset.
+///
+/// All criteria are evaluated against the object passed to [`SubsetPlan::apply`] and combined
+/// with a logical AND, so no intermediate objects are created. Masks therefore refer to the
+/// positions in that object, not to the result of earlier criteria.
+///
+/// # Example
+///
+/// ```ignore
+/// let filtered = SubsetPlan::new()
+///     .filter_obs_range("n_counts", Some(500.0), None)
+///     .keep_var_names(highly_variable)
+///     .apply(&adata)?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SubsetPlan {
+    obs: Vec<Criterion>,
+    var: Vec<Criterion>,
+}
+
+impl SubsetPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps the observations whose entry in `mask` is `true`.
+    pub fn filter_obs_mask(mut self, mask: Vec<bool>) -> Self {
+        self.obs.push(Criterion::Mask(mask));
+        self
+    }
+
+    /// Keeps the observations with one of the given names.
+    pub fn keep_obs_names(mut self, names: Vec<String>) -> Self {
+        self.obs.push(Criterion::Names(names.into_iter().collect()));
+        self
+    }
+
+    /// Keeps the observations whose numeric obs column lies within `[min, max]`.
+    ///
+    /// A missing bound is unbounded, observations with a missing value are dropped.
+    pub fn filter_obs_range(mut self, column: &str, min: Option<f64>, max: Option<f64>) -> Self {
+        self.obs.push(Criterion::Range {
+            column: column.to_string(),
+            min,
+            max,
+        });
+        self
+    }
+
+    /// Keeps the variables whose entry in `mask` is `true`.
+    pub fn filter_var_mask(mut self, mask: Vec<bool>) -> Self {
+        self.var.push(Criterion::Mask(mask));
+        self
+    }
+
+    /// Keeps the variables with one of the given names.
+    pub fn keep_var_names(mut self, names: Vec<String>) -> Self {
+        self.var.push(Criterion::Names(names.into_iter().collect()));
+        self
+    }
+
+    /// Keeps the variables whose numeric var column lies within `[min, max]`.
+    ///
+    /// A missing bound is unbounded, variables with a missing value are dropped.
+    pub fn filter_var_range(mut self, column: &str, min: Option<f64>, max: Option<f64>) -> Self {
+        self.var.push(Criterion::Range {
+            column: column.to_string(),
+            min,
+            max,
+        });
+        self
+    }
+
+    /// Computes the final obs and var selections without subsetting.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a mask has the wrong length or a column is missing or not numeric.
+    pub fn selections(&self, adata: &IMAnnData) -> anyhow::Result<(SelectInfoElem, SelectInfoElem)> {
+        let obs = resolve(&self.obs, &adata.obs(), "obs")?;
+        let var = resolve(&self.var, &adata.var(), "var")?;
+        Ok((obs, var))
+    }
+
+    /// Applies all criteria to `adata` in one pass and returns the subset.
+    ///
+    /// # Errors
+    ///
+    /// See [`SubsetPlan::selections`].
+    pub fn apply(&self, adata: &IMAnnData) -> anyhow::Result<IMAnnData> {
+        let (obs, var) = self.selections(adata)?;
+        adata.subset(&[&obs, &var])
+    }
+}
+
+/// Combines the criteria of one axis into the selection of the kept positions.
+fn resolve(
+    criteria: &[Criterion],
+    frame: &IMDataFrameElement,
+    axis: &str,
+) -> anyhow::Result<SelectInfoElem> {
+    if criteria.is_empty() {
+        return Ok(SelectInfoElem::full());
+    }
+    let names = frame.get_index().into_vec();
+    let mut keep = vec![true; names.len()];
+    for criterion in criteria {
+        match criterion {
+            Criterion::Mask(mask) => {
+                if mask.len() != keep.len() {
+                    return Err(anyhow::anyhow!(
+                        "{} mask has length {}, expected {}",
+                        axis,
+                        mask.len(),
+                        keep.len()
+                    ));
+                }
+                keep.iter_mut().zip(mask).for_each(|(k, &m)| *k &= m);
+            }
+            Criterion::Names(selected) => {
+                keep.iter_mut()
+                    .zip(&names)
+                    .for_each(|(k, name)| *k &= selected.contains(name));
+            }
+            Criterion::Range { column, min, max } => {
+                let series = frame.get_column_from_df(column)?;
+                if !series.dtype().is_numeric() {
+                    return Err(anyhow::anyhow!(
+                        "{} column {} is not numeric: {}",
+                        axis,
+                        column,
+                        series.dtype()
+                    ));
+                }
+                let values = series.cast(&DataType::Float64)?;
+                for (k, value) in keep.iter_mut().zip(values.f64()?) {
+                    *k &= match value {
+                        Some(v) => min.iter().all(|&min| v >= min) && max.iter().all(|&max| v <= max),
+                        None => false,
+                    };
+                }
+            }
+        }
+    }
+    Ok(crate::utils::mask_to_select_info_elem(&keep))
+}
+
+/// Selects how each layer is subset by [`IMAnnData::subset_with_layer_policy`].
+///
+/// By default every layer follows both selections of X, like [`IMAnnData::subset`].
+#[derive(Debug, Clone, Default)]
+pub struct LayerSubsetPolicy {
+    obs_only: HashSet<String>,
+    obs_only_to_obsm: bool,
+}
+
+impl LayerSubsetPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subsets the layer `name` along the observations only, keeping all its columns.
+    ///
+    /// Layers have one column per variable, so the layer can only stay a layer if the
+    /// variable selection keeps every variable in order. Otherwise subsetting fails unless
+    /// [`LayerSubsetPolicy::move_obs_only_to_obsm`] is set.
+    pub fn obs_only(mut self, name: &str) -> Self {
+        self.obs_only.insert(name.to_string());
+        self
+    }
+
+    /// Moves obs-only layers that no longer match the selected variables to obsm, under the
+    /// same key.
+    ///
+    /// A moved layer is no longer returned by `get_layer` and is written to the `obsm` group
+    /// of h5ad files.
+    pub fn move_obs_only_to_obsm(mut self) -> Self {
+        self.obs_only_to_obsm = true;
+        self
+    }
+}
+
+impl IMAnnData {
+    /// Subsets the object like [`IMAnnData::subset`], with per-layer control over the column
+    /// selection.
+    ///
+    /// Layers marked as obs-only in `policy` are subset along the observations and keep all
+    /// their columns. If the variable selection keeps every variable in order they stay
+    /// layers. Otherwise they no longer match the variables of X, and are moved to obsm under
+    /// the same key if the policy allows it with
+    /// [`LayerSubsetPolicy::move_obs_only_to_obsm`].
+    ///
+    /// # Arguments
+    ///
+    /// * `selection` - Obs and var selections, as for `subset`.
+    /// * `policy` - Layers that are only subset along the observations.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `subset` fails or an obs-only layer does not exist. If the variable
+    /// selection changes the variables, also returns an error if the policy does not allow
+    /// moving obs-only layers to obsm, or obsm already has an entry with the name of one.
+    pub fn subset_with_layer_policy(
+        &self,
+        selection: &[&SelectInfoElem],
+        policy: &LayerSubsetPolicy,
+    ) -> anyhow::Result<IMAnnData> {
+        if policy.obs_only.is_empty() {
+            return self.subset(selection);
+        }
+        if selection.len() != 2 {
+            return Err(anyhow::anyhow!(
+                "Invalid selection, only 2-dimensional selections are supported"
+            ));
+        }
+        let mut obs_only: Vec<&String> = policy.obs_only.iter().collect();
+        obs_only.sort();
+        for name in &obs_only {
+            self.layers().get_array_shallow(name)?;
+        }
+        let n_vars = self.n_vars();
+        let var_indices = crate::utils::select_info_elem_to_indices(selection[1], n_vars)?;
+        let keeps_vars = var_indices.iter().copied().eq(0..n_vars);
+        if !keeps_vars {
+            if !policy.obs_only_to_obsm {
+                return Err(anyhow::anyhow!(
+                    "Obs-only layers {} keep {} columns but {} variables are selected, allow \
+                     moving them to obsm with LayerSubsetPolicy::move_obs_only_to_obsm",
+                    obs_only.iter().map(|name| name.as_str()).collect::<Vec<_>>().join(", "),
+                    n_vars,
+                    var_indices.len()
+                ));
+            }
+            if let Some(name) = obs_only.iter().find(|name| self.obsm().try_get(name).is_some()) {
+                return Err(anyhow::anyhow!(
+                    "Cannot move layer {} to obsm, the key is already used",
+                    name
+                ));
+            }
+        }
+        // Obs-only layers are left out here and subset along the observations below
+        let subset = self.subset_skipping_layers(selection, &policy.obs_only)?;
+        let rows = [selection[0], &SelectInfoElem::full()];
+        for name in obs_only {
+            let layer = self.layers().get_array_shallow(name)?.subset(&rows)?;
+            if keeps_vars {
+                subset.layers().add_array(name.clone(), layer)?;
+            } else {
+                subset.obsm().add_array(name.clone(), layer)?;
+            }
+        }
+        Ok(subset)
+    }
+}
@@ -1,7 +1,10 @@
 use std::{
     fmt,
     ops::{Deref, DerefMut},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 
@@ -13,7 +16,23 @@ pub trait DeepClone {
 
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-pub struct RwSlot<T>(Arc<RwLock<Option<T>>>);
+pub struct RwSlot<T>(
+    Arc<RwLock<Option<T>>>,
+    /// Write generation, shared by all shallow clones.
+    Arc<AtomicU64>,
+    #[cfg(feature = "debug-locks")] LockDebug,
+);
+
+/// Write tracking of a slot handle, only compiled with the `debug-locks` feature.
+///
+/// The label belongs to the handle, the last writer is shared by all shallow clones.
+#[cfg(feature = "debug-locks")]
+#[derive(Default)]
+struct LockDebug {
+    label: parking_lot::Mutex<Option<String>>,
+    last_writer: Arc<parking_lot::Mutex<Option<String>>>,
+    active_writers: Arc<std::sync::atomic::AtomicUsize>,
+}
 
 impl<T> Clone for RwSlot<T> {
     fn clone(&self) -> Self {
@@ -46,11 +65,20 @@ where
 
 impl<T> RwSlot<T> {
     pub fn new(x: T) -> Self {
-        RwSlot(Arc::new(RwLock::new(Some(x))))
+        Self::from_arc(Arc::new(RwLock::new(Some(x))))
     }
 
     pub fn none() -> Self {
-        RwSlot(Arc::new(RwLock::new(None)))
+        Self::from_arc(Arc::new(RwLock::new(None)))
+    }
+
+    fn from_arc(inner: Arc<RwLock<Option<T>>>) -> Self {
+        RwSlot(
+            inner,
+            Arc::new(AtomicU64::new(0)),
+            #[cfg(feature = "debug-locks")]
+            LockDebug::default(),
+        )
     }
 
     pub fn is_none(&self) -> bool {
@@ -66,6 +94,18 @@ impl<T> RwSlot<T> {
     }
 
     pub fn lock_write(&self) -> RwLockWriteGuard<'_, Option<T>> {
+        let guard = self.0.write();
+        self.1.fetch_add(1, Ordering::Release);
+        #[cfg(feature = "debug-locks")]
+        self.record_write();
+        guard
+    }
+
+    /// Acquires the write lock without advancing the generation.
+    ///
+    /// Only for callers that leave the value as it was, for example to take a matrix apart
+    /// and reassemble it, so values cached for the current generation stay valid.
+    pub(crate) fn lock_write_unchanged(&self) -> RwLockWriteGuard<'_, Option<T>> {
         self.0.write()
     }
 
@@ -74,7 +114,40 @@ impl<T> RwSlot<T> {
     }
 
     pub fn write_inner(&self) -> WriteInner<'_, T> {
-        WriteInner(self.0.write())
+        let guard = self.lock_write();
+        #[cfg(feature = "debug-locks")]
+        self.2
+            .active_writers
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        WriteInner(
+            guard,
+            #[cfg(feature = "debug-locks")]
+            Arc::clone(&self.2.active_writers),
+        )
+    }
+
+    /// Runs `f` on the value while holding the read lock.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slot is empty.
+    pub fn with_read<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        f(self.read_inner().deref())
+    }
+
+    /// Runs `f` on the value while holding the write lock, so multi-step mutations are atomic.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the slot is empty.
+    pub fn with_write<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        f(self.write_inner().deref_mut())
     }
 
     pub fn insert(&self, data: T) -> Option<T> {
@@ -86,7 +159,65 @@ impl<T> RwSlot<T> {
     }
 
     pub fn shallow_clone(&self) -> Self {
-        RwSlot(Arc::clone(&self.0))
+        RwSlot(
+            Arc::clone(&self.0),
+            Arc::clone(&self.1),
+            #[cfg(feature = "debug-locks")]
+            LockDebug {
+                label: parking_lot::Mutex::new(self.2.label.lock().clone()),
+                last_writer: Arc::clone(&self.2.last_writer),
+                active_writers: Arc::clone(&self.2.active_writers),
+            },
+        )
+    }
+
+    /// Labels this handle, write access through it is reported by [`RwSlot::last_writer`].
+    #[cfg(feature = "debug-locks")]
+    pub fn set_label(&self, label: impl Into<String>) {
+        *self.2.label.lock() = Some(label.into());
+    }
+
+    /// Returns the label of the handle that most recently acquired write access to the slot.
+    ///
+    /// `None` if the slot was never written or the last write went through an unlabeled handle.
+    #[cfg(feature = "debug-locks")]
+    pub fn last_writer(&self) -> Option<String> {
+        self.2.last_writer.lock().clone()
+    }
+
+    /// Returns the number of [`WriteInner`] guards of this slot that are currently alive.
+    ///
+    /// Raw guards from [`RwSlot::lock_write`] are not counted. A count that stays above zero
+    /// after an operation finished points to a leaked guard.
+    #[cfg(feature = "debug-locks")]
+    pub fn active_writers(&self) -> usize {
+        self.2
+            .active_writers
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    #[cfg(feature = "debug-locks")]
+    fn record_write(&self) {
+        *self.2.last_writer.lock() = self.2.label.lock().clone();
+    }
+
+    /// Returns the write generation of the slot, which grows every time write access is
+    /// acquired through any handle.
+    ///
+    /// A value computed while holding the read lock stays valid as long as the generation
+    /// read under the same lock is unchanged.
+    pub fn generation(&self) -> u64 {
+        self.1.load(Ordering::Acquire)
+    }
+
+    /// Number of handles sharing this slot, including `self`.
+    pub fn ref_count(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+
+    /// Returns `true` if both handles point to the same slot.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
     }
 
     pub fn drop(&self) {
@@ -110,7 +241,17 @@ impl<T> Deref for ReadInner<'_, T> {
     }
 }
 
-pub struct WriteInner<'a, T>(pub RwLockWriteGuard<'a, Option<T>>);
+pub struct WriteInner<'a, T>(
+    pub RwLockWriteGuard<'a, Option<T>>,
+    #[cfg(feature = "debug-locks")] Arc<std::sync::atomic::AtomicUsize>,
+);
+
+#[cfg(feature = "debug-locks")]
+impl<T> Drop for WriteInner<'_, T> {
+    fn drop(&mut self) {
+        self.1.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
 
 impl<T> Deref for WriteInner<'_, T> {
     type Target = T;
@@ -130,6 +271,12 @@ impl<T> DerefMut for WriteInner<'_, T> {
 mod tests {
     use super::*;
 
+    impl DeepClone for Vec<i32> {
+        fn deep_clone(&self) -> Self {
+            self.clone()
+        }
+    }
+
     #[test]
     fn create_slot_with_value() {
         let slot = RwSlot::new(10);
@@ -177,6 +324,18 @@ mod tests {
         assert_eq!(*slot.read_inner(), 30);
     }
 
+    #[test]
+    fn with_write_and_read() {
+        let slot = RwSlot::new(10);
+        let result = slot.with_write(|value| {
+            *value += 1;
+            *value += 1;
+            *value
+        });
+        assert_eq!(result, 12);
+        assert_eq!(slot.with_read(|value| *value * 2), 24);
+    }
+
     #[test]
     fn display_slot() {
         let slot = RwSlot::new(10);
@@ -201,6 +360,55 @@ mod tests {
         assert_eq!(*cloned.read_inner(), vec![1, 2, 3, 4]);
     }
 
+    #[cfg(feature = "debug-locks")]
+    #[test]
+    fn last_writer_reports_label() {
+        let slot = RwSlot::new(10);
+        let labeled = slot.shallow_clone();
+        labeled.set_label("normalize");
+        assert_eq!(slot.last_writer(), None);
+
+        *labeled.write_inner() = 20;
+        assert_eq!(slot.last_writer(), Some("normalize".to_string()));
+
+        slot.insert(30);
+        assert_eq!(labeled.last_writer(), None);
+    }
+
+    #[cfg(feature = "debug-locks")]
+    #[test]
+    fn active_writers_counts_live_guards() {
+        let slot = RwSlot::new(10);
+        let clone = slot.shallow_clone();
+        assert_eq!(slot.active_writers(), 0);
+        slot.with_write(|value| {
+            *value += 1;
+            assert_eq!(clone.active_writers(), 1);
+        });
+        assert_eq!(slot.active_writers(), 0);
+
+        let guard = clone.write_inner();
+        assert_eq!(slot.active_writers(), 1);
+        drop(guard);
+        assert_eq!(slot.active_writers(), 0);
+    }
+
+    #[test]
+    fn test_ref_count() {
+        let original = RwSlot::new(vec![1, 2, 3]);
+        assert_eq!(original.ref_count(), 1);
+
+        let shallow = original.shallow_clone();
+        assert_eq!(original.ref_count(), 2);
+
+        let deep = original.deep_clone();
+        assert_eq!(original.ref_count(), 2);
+        assert_eq!(deep.ref_count(), 1);
+
+        drop(shallow);
+        assert_eq!(original.ref_count(), 1);
+    }
+
     #[test]
     fn test_default_clone() {
         let original = RwSlot::new(vec![1, 2, 3]);
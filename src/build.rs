@@ -0,0 +1,121 @@
+use anndata::{data::DynCsrMatrix, ArrayData};
+use nalgebra_sparse::CsrMatrix;
+
+/// Builds a CSR matrix row by row, e.g. from a streaming source.
+///
+/// The row offsets are tracked while rows are pushed, and the index and value buffers are
+/// moved into the matrix by [`CsrBuilder::finish`] without another copy.
+///
+/// # Example
+///
+/// ```ignore
+/// let mut builder = CsrBuilder::new(n_vars);
+/// for (indices, values) in rows {
+///     builder.push_row(indices, values)?;
+/// }
+/// let x = builder.finish()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct CsrBuilder {
+    n_cols: usize,
+    indptr: Vec<usize>,
+    indices: Vec<usize>,
+    values: Vec<f64>,
+}
+
+impl CsrBuilder {
+    /// Creates an empty builder for a matrix with `n_cols` columns.
+    pub fn new(n_cols: usize) -> Self {
+        Self::with_capacity(n_cols, 0, 0)
+    }
+
+    /// Creates an empty builder with room for `n_rows` rows and `nnz` stored values.
+    ///
+    /// If both estimates are met, pushing rows does not reallocate.
+    pub fn with_capacity(n_cols: usize, n_rows: usize, nnz: usize) -> Self {
+        let mut indptr = Vec::with_capacity(n_rows + 1);
+        indptr.push(0);
+        CsrBuilder {
+            n_cols,
+            indptr,
+            indices: Vec::with_capacity(nnz),
+            values: Vec::with_capacity(nnz),
+        }
+    }
+
+    /// Returns the number of rows pushed so far.
+    pub fn n_rows(&self) -> usize {
+        self.indptr.len() - 1
+    }
+
+    /// Returns the number of values stored so far.
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Appends a row given by the column indices and values of its stored entries.
+    ///
+    /// The entries do not need to be sorted by column.
+    ///
+    /// # Arguments
+    ///
+    /// * `indices` - Column indices of the stored entries.
+    /// * `values` - Values of the stored entries, in the same order as `indices`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the lengths differ, a column index is not below `n_cols` or a
+    /// column occurs twice. The builder is unchanged in that case.
+    pub fn push_row(&mut self, indices: Vec<usize>, values: Vec<f64>) -> anyhow::Result<()> {
+        if indices.len() != values.len() {
+            return Err(anyhow::anyhow!(
+                "Row {} has {} indices but {} values",
+                self.n_rows(),
+                indices.len(),
+                values.len()
+            ));
+        }
+        if let Some(&col) = indices.iter().find(|&&col| col >= self.n_cols) {
+            return Err(anyhow::anyhow!(
+                "Column index {} in row {} is out of bounds for {} columns",
+                col,
+                self.n_rows(),
+                self.n_cols
+            ));
+        }
+        let mut entries: Vec<(usize, f64)> = indices.into_iter().zip(values).collect();
+        if !entries.windows(2).all(|w| w[0].0 < w[1].0) {
+            entries.sort_by_key(|&(col, _)| col);
+            if let Some(w) = entries.windows(2).find(|w| w[0].0 == w[1].0) {
+                return Err(anyhow::anyhow!(
+                    "Column index {} occurs twice in row {}",
+                    w[0].0,
+                    self.n_rows()
+                ));
+            }
+        }
+        for (col, value) in entries {
+            self.indices.push(col);
+            self.values.push(value);
+        }
+        self.indptr.push(self.indices.len());
+        Ok(())
+    }
+
+    /// Consumes the builder and returns the assembled `f64` CSR matrix.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the buffers do not form a valid CSR matrix.
+    pub fn finish(self) -> anyhow::Result<ArrayData> {
+        let n_rows = self.n_rows();
+        let csr = CsrMatrix::try_from_csr_data(
+            n_rows,
+            self.n_cols,
+            self.indptr,
+            self.indices,
+            self.values,
+        )?;
+        Ok(ArrayData::CsrMatrix(DynCsrMatrix::from(csr)))
+    }
+}
@@ -1,6 +1,15 @@
-use std::ops::Deref;
+use std::{ops::Deref, path::Path};
 
-use anndata::{AnnData, AnnDataOp, ArrayData, ArrayElemOp, AxisArrays, Backend, Data, ElemCollection};
+use anndata::{
+    data::SelectInfoElem, AnnData, AnnDataOp, ArrayData, ArrayElemOp, AxisArrays, Backend, Data,
+    ElemCollection,
+};
+use anndata_hdf5::H5;
+use tempfile::TempDir;
+use polars::{
+    frame::DataFrame,
+    prelude::{IdxCa, NamedFrom},
+};
 use anyhow::Ok;
 
 use crate::{ad::helpers::{Element, IMAxisArrays}, IMAnnData, IMArrayElement, IMElementCollection};
@@ -16,11 +25,131 @@ pub fn convert_to_in_memory<B: Backend>(anndata: AnnData<B>) -> anyhow::Result<I
     convert_axis_arrays_to_mem(anndata.obsp(), imad.obsp())?;
     convert_axis_arrays_to_mem(anndata.varm(), imad.varm())?;
     convert_axis_arrays_to_mem(anndata.varp(), imad.varp())?;
+    convert_axis_arrays_to_mem(anndata.layers(), imad.layers())?;
     convert_uns_to_mem(anndata.uns(), imad.uns())?;
     anndata.close()?;
     Ok(imad)
 }
 
+/// Converts the selected part of an `anndata` object into an `IMAnnData`.
+///
+/// The selections are applied while reading through the backend's slicing, so only the
+/// selected part of X, the layers and the axis arrays is ever loaded. obs and var are read
+/// in full and subset afterwards, uns is read in full.
+///
+/// # Arguments
+///
+/// * `anndata` - Object to convert, it is closed afterwards.
+/// * `obs` - Selection of observations.
+/// * `var` - Selection of variables.
+///
+/// # Errors
+///
+/// Returns an error if a selection is out of bounds for the shape reported by the backend,
+/// or if reading any element fails.
+pub fn convert_to_in_memory_subset<B: Backend>(
+    anndata: AnnData<B>,
+    obs: &SelectInfoElem,
+    var: &SelectInfoElem,
+) -> anyhow::Result<IMAnnData> {
+    obs.bound_check(anndata.n_obs())?;
+    var.bound_check(anndata.n_vars())?;
+    let obs_indices = crate::utils::select_info_elem_to_indices(obs, anndata.n_obs())?;
+    let var_indices = crate::utils::select_info_elem_to_indices(var, anndata.n_vars())?;
+    let take_rows = |df: DataFrame, indices: &[usize]| -> anyhow::Result<DataFrame> {
+        let indices_u32: Vec<u32> = indices.iter().map(|&i| i as u32).collect();
+        Ok(df.take(&IdxCa::new("idx", &indices_u32))?)
+    };
+    let select_names = |names: Vec<String>, indices: &[usize]| -> Vec<String> {
+        indices.iter().map(|&i| names[i].clone()).collect()
+    };
+
+    let obs_df = take_rows(anndata.read_obs()?, &obs_indices)?;
+    let obs_names = select_names(anndata.obs_names().into_vec(), &obs_indices);
+    let var_df = take_rows(anndata.read_var()?, &var_indices)?;
+    let var_names = select_names(anndata.var_names().into_vec(), &var_indices);
+    let x = anndata
+        .x()
+        .slice::<ArrayData, _>([obs.clone(), var.clone()])?
+        .ok_or_else(|| anyhow::anyhow!("X is empty"))?;
+    let imad = IMAnnData::new_extended(x, obs_names, var_names, obs_df, var_df)?;
+
+    let full = SelectInfoElem::full();
+    convert_axis_arrays_subset_to_mem(anndata.layers(), imad.layers(), &[obs.clone(), var.clone()])?;
+    convert_axis_arrays_subset_to_mem(anndata.obsm(), imad.obsm(), &[obs.clone(), full.clone()])?;
+    convert_axis_arrays_subset_to_mem(anndata.obsp(), imad.obsp(), &[obs.clone(), obs.clone()])?;
+    convert_axis_arrays_subset_to_mem(anndata.varm(), imad.varm(), &[var.clone(), full])?;
+    convert_axis_arrays_subset_to_mem(anndata.varp(), imad.varp(), &[var.clone(), var.clone()])?;
+    convert_uns_to_mem(anndata.uns(), imad.uns())?;
+    anndata.close()?;
+    Ok(imad)
+}
+
+/// Converts an `IMAnnData` into an `anndata` object stored by the backend `B` at `path`.
+///
+/// X, obs, var, obsm, obsp, varm, varp, layers and uns are written through `AnnDataOp`,
+/// an existing file at `path` is overwritten.
+pub fn convert_to_anndata<B: Backend, P: AsRef<Path>>(
+    imad: &IMAnnData,
+    path: P,
+) -> anyhow::Result<AnnData<B>> {
+    let anndata = AnnData::<B>::new(path)?;
+    crate::writer::write_elements(imad, &anndata, false)?;
+    Ok(anndata)
+}
+
+/// An `anndata` object backed by an HDF5 file in a temporary directory of its own.
+///
+/// `anndata-rs` does not provide an in-memory backend, so [`IMAnnData::to_anndata_mem`]
+/// writes the object to a file. The object is closed and the directory with the file is
+/// removed when this value is dropped. It dereferences to the `AnnData<H5>` for use with
+/// other `anndata-rs` code.
+pub struct TempAnnData {
+    anndata: Option<AnnData<H5>>,
+    dir: Option<TempDir>,
+}
+
+impl TempAnnData {
+    /// Splits into the `anndata` object and the directory holding its file.
+    ///
+    /// Use this to pass the object to functions that take it by value. The file is removed
+    /// when the returned `TempDir` is dropped, so keep it alive as long as the object is used.
+    pub fn into_parts(mut self) -> (AnnData<H5>, TempDir) {
+        match (self.anndata.take(), self.dir.take()) {
+            (Some(anndata), Some(dir)) => (anndata, dir),
+            _ => unreachable!("TempAnnData holds its parts until dropped"),
+        }
+    }
+}
+
+impl Deref for TempAnnData {
+    type Target = AnnData<H5>;
+
+    fn deref(&self) -> &AnnData<H5> {
+        self.anndata.as_ref().expect("TempAnnData holds an object until dropped")
+    }
+}
+
+impl Drop for TempAnnData {
+    fn drop(&mut self) {
+        // Close the file before its directory is removed
+        if let Some(anndata) = self.anndata.take() {
+            let _ = anndata.close();
+        }
+        self.dir.take();
+    }
+}
+
+/// Writes an `IMAnnData` into a new temporary directory and opens it as an `anndata` object.
+pub(crate) fn convert_to_anndata_temp(imad: &IMAnnData) -> anyhow::Result<TempAnnData> {
+    let dir = tempfile::Builder::new().prefix("anndata-memory-").tempdir()?;
+    let anndata = convert_to_anndata::<H5, _>(imad, dir.path().join("data.h5ad"))?;
+    Ok(TempAnnData {
+        anndata: Some(anndata),
+        dir: Some(dir),
+    })
+}
+
 fn convert_axis_arrays_to_mem<B: Backend>(axis_arr: &AxisArrays<B>, reference_element: IMAxisArrays) -> anyhow::Result<()> {
     if axis_arr.is_none() {
         return Ok(());
@@ -36,6 +165,33 @@ fn convert_axis_arrays_to_mem<B: Backend>(axis_arr: &AxisArrays<B>, reference_el
     Ok(())
 }
 
+/// Reads the selected part of every array of `axis_arr` into `reference_element`.
+///
+/// `selection` holds the selection of the first two axes, further axes are read in full.
+fn convert_axis_arrays_subset_to_mem<B: Backend>(
+    axis_arr: &AxisArrays<B>,
+    reference_element: IMAxisArrays,
+    selection: &[SelectInfoElem; 2],
+) -> anyhow::Result<()> {
+    if axis_arr.is_none() {
+        return Ok(());
+    }
+    let x = axis_arr.inner();
+    let iax = x.deref();
+    let data = iax.deref();
+    for (k, v) in data.iter() {
+        let ndim = v.shape().map_or(2, |shape| shape.ndim());
+        let arr = if ndim == 1 {
+            v.slice_axis::<ArrayData, _>(0, &selection[0])?
+        } else {
+            v.slice::<ArrayData, _>(selection)?
+        }
+        .ok_or_else(|| anyhow::anyhow!("Array {} is empty", k))?;
+        reference_element.add_array(k.to_string(), IMArrayElement::new(arr))?;
+    }
+    Ok(())
+}
+
 fn convert_uns_to_mem<B: Backend>(elem_col: &ElemCollection<B>, reference_element: IMElementCollection) -> anyhow::Result<()> {
     if elem_col.is_none() {
         return Ok(());
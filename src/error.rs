@@ -0,0 +1,25 @@
+use std::fmt;
+
+/// Error returned when an operation does not support the element type of an array, e.g.
+/// arithmetic on boolean or string arrays.
+///
+/// Returned inside `anyhow::Error`, use `downcast_ref::<DtypeMismatch>()` to detect it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DtypeMismatch {
+    /// Name of the operation that was attempted.
+    pub operation: &'static str,
+    /// Description of the type of the array.
+    pub found: String,
+}
+
+impl fmt::Display for DtypeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is not supported for arrays of type {}",
+            self.operation, self.found
+        )
+    }
+}
+
+impl std::error::Error for DtypeMismatch {}
@@ -1,13 +1,31 @@
 mod ad;
 mod base;
+mod build;
 mod converter;
+mod error;
+mod writer;
 pub(crate) mod utils;
 
-pub use ad::IMAnnData;
-pub use ad::helpers::IMArrayElement;
-pub use ad::helpers::IMDataFrameElement;
+pub use ad::{DisplayOptions, IMAnnData, IMAnnDataParts};
+pub use ad::analysis::{AggOp, MatrixAxis, Metric, NanPolicy};
+pub use ad::components::ComponentSet;
+pub use ad::concat::ConcatJoin;
+pub use ad::plan::{LayerSubsetPolicy, SubsetPlan};
+pub use ad::helpers::{ArrayStats, IMArrayElement, LayerEntry};
+pub use ad::helpers::{BoolOp, DeriveFn, IMDataFrameElement};
 pub use ad::helpers::IMElementCollection;
 pub use ad::helpers::Element;
 pub use ad::helpers::IMAxisArrays;
+pub use ad::history::OpRecord;
 pub use converter::convert_to_in_memory;
-pub use base::DeepClone;
\ No newline at end of file
+pub use converter::convert_to_in_memory_subset;
+pub use converter::convert_to_anndata;
+pub use converter::TempAnnData;
+pub use writer::{write_h5ad, write_h5ad_with_options, Compression, WriteOptions};
+pub use base::DeepClone;
+pub use build::CsrBuilder;
+pub use error::DtypeMismatch;
+pub use utils::{
+    indices_to_select_info_elem, mask_to_select_info_elem, range_to_select_info_elem,
+    select_info_elem_to_indices,
+};
\ No newline at end of file
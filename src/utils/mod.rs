@@ -1,7 +1,38 @@
-use anndata::data::SelectInfoElem;
-use ndarray::Slice;
+use anndata::{
+    backend::ScalarType,
+    data::{DynArray, DynCscMatrix, DynCsrMatrix, SelectInfoElem},
+    ArrayData, HasShape,
+};
+use nalgebra_sparse::{CscMatrix, CsrMatrix};
+use ndarray::{ArrayD, IxDyn, Slice};
+use rand::{rngs::StdRng, seq::index, Rng, SeedableRng};
 
-pub(crate) fn select_info_elem_to_indices(elem: &SelectInfoElem, bound: usize) -> anyhow::Result<Vec<usize>> {
+mod stack;
+
+pub(crate) use stack::RowStack;
+
+/// Largest number of elements a dense conversion may allocate.
+pub(crate) const MAX_DENSE_ELEMENTS: usize = 1 << 30;
+
+/// Errors if a dense `nrows x ncols` allocation would exceed [`MAX_DENSE_ELEMENTS`].
+pub(crate) fn check_dense_size(nrows: usize, ncols: usize) -> anyhow::Result<()> {
+    match nrows.checked_mul(ncols) {
+        Some(n) if n <= MAX_DENSE_ELEMENTS => Ok(()),
+        _ => Err(anyhow::anyhow!(
+            "Dense matrix of shape ({}, {}) exceeds the limit of {} elements",
+            nrows,
+            ncols,
+            MAX_DENSE_ELEMENTS
+        )),
+    }
+}
+
+/// Resolves a selection into the explicit, bounds-checked indices it selects.
+///
+/// # Errors
+///
+/// Returns an error if the selection exceeds `bound`.
+pub fn select_info_elem_to_indices(elem: &SelectInfoElem, bound: usize) -> anyhow::Result<Vec<usize>> {
     match elem {
         SelectInfoElem::Index(indices) => {
             // For Index, we just need to verify that all indices are within bounds
@@ -16,8 +47,8 @@ pub(crate) fn select_info_elem_to_indices(elem: &SelectInfoElem, bound: usize) -
             let Slice { start, end, step } = *slice;
             let end = end.unwrap_or(bound as isize);
             
-            // Ensure the slice is within bounds
-            if start as usize >= bound || end as usize > bound {
+            // Ensure the slice is within bounds, an empty slice selects nothing
+            if end as usize > bound || (start < end && start as usize >= bound) {
                 anyhow::bail!("Slice out of bounds: start={}, end={}, bound={}", start, end, bound);
             }
 
@@ -30,4 +61,274 @@ pub(crate) fn select_info_elem_to_indices(elem: &SelectInfoElem, bound: usize) -
             Ok(indices)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Creates a selection of the given positions, the inverse of [`select_info_elem_to_indices`].
+pub fn indices_to_select_info_elem(indices: Vec<usize>) -> SelectInfoElem {
+    SelectInfoElem::Index(indices)
+}
+
+/// Creates a selection of the positions whose entry in `mask` is `true`.
+pub fn mask_to_select_info_elem(mask: &[bool]) -> SelectInfoElem {
+    SelectInfoElem::Index(
+        mask.iter()
+            .enumerate()
+            .filter(|(_, &keep)| keep)
+            .map(|(i, _)| i)
+            .collect(),
+    )
+}
+
+/// Creates a selection of every `step`-th position in `start..end`.
+///
+/// # Errors
+///
+/// Returns an error if `step` is zero or `start` is greater than `end`.
+pub fn range_to_select_info_elem(
+    start: usize,
+    end: usize,
+    step: usize,
+) -> anyhow::Result<SelectInfoElem> {
+    if step == 0 {
+        return Err(anyhow::anyhow!("Step must be greater than zero"));
+    }
+    if start > end {
+        return Err(anyhow::anyhow!("Range start {} is greater than its end {}", start, end));
+    }
+    Ok(SelectInfoElem::Slice(Slice::new(start as isize, Some(end as isize), step as isize)))
+}
+
+/// Default random number generator for stochastic methods, seeded for reproducibility.
+pub(crate) fn rng_from_seed(seed: u64) -> StdRng {
+    StdRng::seed_from_u64(seed)
+}
+
+pub(crate) fn sample_indices<R: Rng + ?Sized>(
+    rng: &mut R,
+    bound: usize,
+    n: usize,
+    replace: bool,
+) -> anyhow::Result<Vec<usize>> {
+    if replace {
+        if bound == 0 && n > 0 {
+            anyhow::bail!("Cannot sample {} indices from an empty range", n);
+        }
+        Ok((0..n).map(|_| rng.gen_range(0..bound)).collect())
+    } else {
+        if n > bound {
+            anyhow::bail!(
+                "Cannot sample {} indices without replacement from {} elements",
+                n,
+                bound
+            );
+        }
+        Ok(index::sample(rng, bound, n).into_vec())
+    }
+}
+
+/// Matches the numeric variants of `DynArray`, `DynCsrMatrix` or `DynCscMatrix`, binding the
+/// inner value to `$inner` and evaluating `$body` for each; other variants evaluate `$fallback`.
+macro_rules! dispatch_numeric {
+    ($value:expr, $enum:ident, $inner:ident => $body:expr, $fallback:expr) => {
+        match $value {
+            $enum::I8($inner) => $body,
+            $enum::I16($inner) => $body,
+            $enum::I32($inner) => $body,
+            $enum::I64($inner) => $body,
+            $enum::U8($inner) => $body,
+            $enum::U16($inner) => $body,
+            $enum::U32($inner) => $body,
+            $enum::U64($inner) => $body,
+            $enum::F32($inner) => $body,
+            $enum::F64($inner) => $body,
+            #[allow(unreachable_patterns)]
+            _ => $fallback,
+        }
+    };
+}
+
+pub(crate) use dispatch_numeric;
+
+/// Converts a numeric 2-dimensional array into a `CsrMatrix<f64>`.
+pub(crate) fn to_csr_f64(data: &ArrayData) -> anyhow::Result<CsrMatrix<f64>> {
+    match data {
+        ArrayData::CsrMatrix(csr) => dispatch_numeric!(
+            csr,
+            DynCsrMatrix,
+            m => Ok(CsrMatrix::try_from_csr_data(
+                m.nrows(),
+                m.ncols(),
+                m.row_offsets().to_vec(),
+                m.col_indices().to_vec(),
+                m.values().iter().map(|&v| v as f64).collect(),
+            )?),
+            Err(anyhow::anyhow!("Unsupported CSR matrix type"))
+        ),
+        ArrayData::CscMatrix(csc) => dispatch_numeric!(
+            csc,
+            DynCscMatrix,
+            m => {
+                let csc_f64 = CscMatrix::try_from_csc_data(
+                    m.nrows(),
+                    m.ncols(),
+                    m.col_offsets().to_vec(),
+                    m.row_indices().to_vec(),
+                    m.values().iter().map(|&v| v as f64).collect(),
+                )?;
+                Ok(CsrMatrix::from(&csc_f64))
+            },
+            Err(anyhow::anyhow!("Unsupported CSC matrix type"))
+        ),
+        ArrayData::Array(arr) => dispatch_numeric!(
+            arr,
+            DynArray,
+            a => {
+                if a.ndim() != 2 {
+                    anyhow::bail!("Expected a 2-dimensional array, found {} dimensions", a.ndim());
+                }
+                let (nrows, ncols) = (a.shape()[0], a.shape()[1]);
+                let mut offsets = Vec::with_capacity(nrows + 1);
+                let mut indices = Vec::new();
+                let mut values = Vec::new();
+                offsets.push(0);
+                for row in a.outer_iter() {
+                    for (j, &v) in row.iter().enumerate() {
+                        let v = v as f64;
+                        if v != 0.0 {
+                            indices.push(j);
+                            values.push(v);
+                        }
+                    }
+                    offsets.push(indices.len());
+                }
+                Ok(CsrMatrix::try_from_csr_data(nrows, ncols, offsets, indices, values)?)
+            },
+            Err(anyhow::anyhow!("Unsupported dense array type"))
+        ),
+        _ => Err(anyhow::anyhow!("Unsupported array type for numeric access")),
+    }
+}
+
+/// Creates an all-zero `nrows x ncols` array with the storage format and type of `template`.
+pub(crate) fn zeros_like(
+    template: &ArrayData,
+    nrows: usize,
+    ncols: usize,
+) -> anyhow::Result<ArrayData> {
+    fn csr_zeros<T>(_: &CsrMatrix<T>, nrows: usize, ncols: usize) -> CsrMatrix<T> {
+        CsrMatrix::zeros(nrows, ncols)
+    }
+    fn csc_zeros<T>(_: &CscMatrix<T>, nrows: usize, ncols: usize) -> CscMatrix<T> {
+        CscMatrix::zeros(nrows, ncols)
+    }
+    fn dense_zeros<T: Clone + num::Zero>(_: &ArrayD<T>, nrows: usize, ncols: usize) -> ArrayD<T> {
+        ArrayD::zeros(IxDyn(&[nrows, ncols]))
+    }
+
+    match template {
+        ArrayData::CsrMatrix(csr) => dispatch_numeric!(
+            csr,
+            DynCsrMatrix,
+            m => Ok(ArrayData::CsrMatrix(DynCsrMatrix::from(csr_zeros(m, nrows, ncols)))),
+            Err(anyhow::anyhow!("Unsupported CSR matrix type"))
+        ),
+        ArrayData::CscMatrix(csc) => dispatch_numeric!(
+            csc,
+            DynCscMatrix,
+            m => Ok(ArrayData::CscMatrix(DynCscMatrix::from(csc_zeros(m, nrows, ncols)))),
+            Err(anyhow::anyhow!("Unsupported CSC matrix type"))
+        ),
+        ArrayData::Array(arr) => dispatch_numeric!(
+            arr,
+            DynArray,
+            a => Ok(ArrayData::Array(DynArray::from(dense_zeros(a, nrows, ncols)))),
+            Err(anyhow::anyhow!("Unsupported dense array type"))
+        ),
+        _ => Err(anyhow::anyhow!("Unsupported array type")),
+    }
+}
+
+/// Creates an all-zero `nrows x ncols` matrix of the given numeric type, as CSR or dense array.
+pub(crate) fn zeros_of(
+    dtype: ScalarType,
+    sparse: bool,
+    nrows: usize,
+    ncols: usize,
+) -> anyhow::Result<ArrayData> {
+    macro_rules! zeros {
+        ($t:ty) => {
+            if sparse {
+                ArrayData::CsrMatrix(DynCsrMatrix::from(CsrMatrix::<$t>::zeros(nrows, ncols)))
+            } else {
+                check_dense_size(nrows, ncols)?;
+                ArrayData::Array(DynArray::from(ArrayD::<$t>::zeros(IxDyn(&[nrows, ncols]))))
+            }
+        };
+    }
+
+    Ok(match dtype {
+        ScalarType::I8 => zeros!(i8),
+        ScalarType::I16 => zeros!(i16),
+        ScalarType::I32 => zeros!(i32),
+        ScalarType::I64 => zeros!(i64),
+        ScalarType::U8 => zeros!(u8),
+        ScalarType::U16 => zeros!(u16),
+        ScalarType::U32 => zeros!(u32),
+        ScalarType::U64 => zeros!(u64),
+        ScalarType::F32 => zeros!(f32),
+        ScalarType::F64 => zeros!(f64),
+        other => anyhow::bail!("Cannot create a zero matrix of type {:?}", other),
+    })
+}
+
+/// Compares two arrays with the tolerance `|a - b| <= atol + rtol * |b|` per value.
+///
+/// Numeric 2-dimensional arrays are compared by value regardless of their storage format,
+/// implicit zeros of sparse matrices compare as zero. NaNs at the same position are equal.
+/// Other arrays (non-numeric or with more dimensions) fall back to exact equality.
+pub(crate) fn arrays_close(a: &ArrayData, b: &ArrayData, rtol: f64, atol: f64) -> bool {
+    if a.shape() != b.shape() {
+        return false;
+    }
+    let close = |x: f64, y: f64| {
+        x == y || (x.is_nan() && y.is_nan()) || (x - y).abs() <= atol + rtol * y.abs()
+    };
+    if let (ArrayData::Array(x), ArrayData::Array(y)) = (a, b) {
+        let values = |arr: &DynArray| -> Option<Vec<f64>> {
+            dispatch_numeric!(arr, DynArray, v => Some(v.iter().map(|&e| e as f64).collect()), None)
+        };
+        return match (values(x), values(y)) {
+            (Some(x), Some(y)) => x.into_iter().zip(y).all(|(x, y)| close(x, y)),
+            _ => a == b,
+        };
+    }
+    let (x, y) = match (to_csr_f64(a), to_csr_f64(b)) {
+        (Ok(x), Ok(y)) => (x, y),
+        _ => return a == b,
+    };
+    x.row_iter().zip(y.row_iter()).all(|(row_x, row_y)| {
+        // Column indices are sorted within a row, walk both rows like a merge
+        let (cols_x, vals_x) = (row_x.col_indices(), row_x.values());
+        let (cols_y, vals_y) = (row_y.col_indices(), row_y.values());
+        let (mut i, mut j) = (0, 0);
+        while i < cols_x.len() || j < cols_y.len() {
+            let col_x = cols_x.get(i).copied().unwrap_or(usize::MAX);
+            let col_y = cols_y.get(j).copied().unwrap_or(usize::MAX);
+            let (vx, vy) = match col_x.cmp(&col_y) {
+                std::cmp::Ordering::Less => (vals_x[i], 0.0),
+                std::cmp::Ordering::Greater => (0.0, vals_y[j]),
+                std::cmp::Ordering::Equal => (vals_x[i], vals_y[j]),
+            };
+            if !close(vx, vy) {
+                return false;
+            }
+            if col_x <= col_y {
+                i += 1;
+            }
+            if col_y <= col_x {
+                j += 1;
+            }
+        }
+        true
+    })
+}
@@ -0,0 +1,173 @@
+use anndata::{
+    data::{DynArray, DynCsrMatrix},
+    ArrayData, HasShape, WriteData,
+};
+use nalgebra_sparse::CsrMatrix;
+use ndarray::{ArrayD, IxDyn};
+
+use super::dispatch_numeric;
+
+macro_rules! stack_values {
+    ($($variant:ident: $t:ty),*) => {
+        /// Stored values of one numeric scalar type.
+        enum Values {
+            $($variant(Vec<$t>),)*
+        }
+
+        impl Values {
+            /// Creates an empty buffer of the scalar type of `data` with room for `capacity` values.
+            fn empty_like(data: &ArrayData, capacity: usize) -> Option<Self> {
+                match data {
+                    $(ArrayData::CsrMatrix(DynCsrMatrix::$variant(_))
+                    | ArrayData::Array(DynArray::$variant(_)) => {
+                        Some(Values::$variant(Vec::with_capacity(capacity)))
+                    })*
+                    _ => None,
+                }
+            }
+
+            /// Appends the stored values of `data`, returns `false` if its type differs.
+            fn extend(&mut self, data: &ArrayData) -> bool {
+                match (self, data) {
+                    $((Values::$variant(values), ArrayData::CsrMatrix(DynCsrMatrix::$variant(m))) => {
+                        values.extend_from_slice(m.values());
+                        true
+                    })*
+                    // `iter` walks the array in logical, i.e. row-major, order
+                    $((Values::$variant(values), ArrayData::Array(DynArray::$variant(a))) => {
+                        values.extend(a.iter().cloned());
+                        true
+                    })*
+                    _ => false,
+                }
+            }
+
+            fn into_csr(
+                self,
+                shape: (usize, usize),
+                offsets: Vec<usize>,
+                indices: Vec<usize>,
+            ) -> anyhow::Result<ArrayData> {
+                Ok(match self {
+                    $(Values::$variant(values) => ArrayData::CsrMatrix(DynCsrMatrix::from(
+                        CsrMatrix::try_from_csr_data(shape.0, shape.1, offsets, indices, values)?,
+                    )),)*
+                })
+            }
+
+            fn into_dense(self, shape: (usize, usize)) -> anyhow::Result<ArrayData> {
+                Ok(match self {
+                    $(Values::$variant(values) => ArrayData::from(
+                        ArrayD::from_shape_vec(IxDyn(&[shape.0, shape.1]), values)?,
+                    ),)*
+                })
+            }
+        }
+    };
+}
+
+stack_values!(
+    I8: i8, I16: i16, I32: i32, I64: i64, U8: u8, U16: u16, U32: u32, U64: u64, F32: f32,
+    F64: f64
+);
+
+/// Stacks blocks of rows into one matrix, copying every block into the result right away.
+///
+/// All blocks must be numeric 2-dimensional arrays or CSR matrices of the same scalar type
+/// and storage format as the first one. Unlike collecting the blocks and stacking them at the
+/// end, only the result and the current block are held at any time.
+pub(crate) struct RowStack {
+    n_rows: usize,
+    n_cols: usize,
+    expected_rows: usize,
+    sparse: bool,
+    offsets: Vec<usize>,
+    indices: Vec<usize>,
+    values: Option<Values>,
+}
+
+impl RowStack {
+    /// Creates an empty stack for `expected_rows` rows of `n_cols` columns.
+    ///
+    /// `expected_rows` is used to preallocate the result, dense results are allocated in full
+    /// when the first block arrives.
+    pub(crate) fn new(expected_rows: usize, n_cols: usize) -> Self {
+        RowStack {
+            n_rows: 0,
+            n_cols,
+            expected_rows,
+            sparse: false,
+            offsets: Vec::new(),
+            indices: Vec::new(),
+            values: None,
+        }
+    }
+
+    /// Appends the rows of `block`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `block` is not a numeric 2-dimensional array or CSR matrix, has a
+    /// different number of columns, or differs in scalar type or storage format from the
+    /// first block.
+    pub(crate) fn push(&mut self, block: &ArrayData) -> anyhow::Result<()> {
+        let shape = block.shape();
+        if shape.ndim() != 2 || shape[1] != self.n_cols {
+            return Err(anyhow::anyhow!(
+                "Block of shape {:?} does not have {} columns",
+                shape,
+                self.n_cols
+            ));
+        }
+        let sparse = matches!(block, ArrayData::CsrMatrix(_));
+        if self.values.is_none() {
+            let capacity = if sparse {
+                0
+            } else {
+                super::check_dense_size(self.expected_rows, self.n_cols)?;
+                self.expected_rows * self.n_cols
+            };
+            let values = Values::empty_like(block, capacity).ok_or_else(|| {
+                anyhow::anyhow!("Cannot stack blocks of type {:?}", block.data_type())
+            })?;
+            if sparse {
+                self.offsets.reserve(self.expected_rows + 1);
+                self.offsets.push(0);
+            }
+            self.sparse = sparse;
+            self.values = Some(values);
+        } else if sparse != self.sparse {
+            return Err(anyhow::anyhow!("Block storage format differs from the first block"));
+        }
+        let values = self.values.as_mut().expect("initialized above");
+        if !values.extend(block) {
+            return Err(anyhow::anyhow!(
+                "Block of type {:?} differs from the first block",
+                block.data_type()
+            ));
+        }
+        if let ArrayData::CsrMatrix(csr) = block {
+            let (offsets, indices) = dispatch_numeric!(
+                csr,
+                DynCsrMatrix,
+                m => (m.row_offsets(), m.col_indices()),
+                unreachable!("non-numeric blocks are rejected above")
+            );
+            let base = self.indices.len();
+            self.offsets.extend(offsets[1..].iter().map(|&o| base + o));
+            self.indices.extend_from_slice(indices);
+        }
+        self.n_rows += shape[0];
+        Ok(())
+    }
+
+    /// Returns the stacked matrix, `None` if no block was pushed.
+    pub(crate) fn finish(self) -> anyhow::Result<Option<ArrayData>> {
+        let shape = (self.n_rows, self.n_cols);
+        match self.values {
+            None => Ok(None),
+            Some(values) if self.sparse => values.into_csr(shape, self.offsets, self.indices).map(Some),
+            Some(values) => values.into_dense(shape).map(Some),
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use std::{ops::Deref, path::Path, str::FromStr};
+
+use anndata::{
+    data::{DynArray, DynCscMatrix, DynCsrMatrix},
+    AnnData, AnnDataOp, ArrayData, AxisArraysOp, Backend, ElemCollectionOp,
+};
+use anndata_hdf5::H5;
+use hdf5::{types::VarLenUnicode, Group, H5Type};
+use nalgebra_sparse::{CscMatrix, CsrMatrix};
+use ndarray::ArrayD;
+
+use crate::{utils::dispatch_numeric, IMAnnData, IMAxisArrays};
+
+/// Number of rows stored per chunk when compression is requested without an explicit chunk size.
+const DEFAULT_CHUNK_ROWS: usize = 1000;
+
+/// Compression filter applied to the datasets of `X` and the layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Gzip (deflate) compression with a level between 0 and 9.
+    Gzip(u8),
+    /// LZF compression, only available with the `lzf` feature.
+    #[cfg(feature = "lzf")]
+    Lzf,
+}
+
+/// Options controlling how the matrices of an `IMAnnData` are stored in an h5ad file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Compression filter, `None` stores the matrices uncompressed.
+    pub compression: Option<Compression>,
+    /// Number of matrix rows per chunk, `None` picks a default when compressing.
+    pub chunk_rows: Option<usize>,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            compression: Some(Compression::Gzip(4)),
+            chunk_rows: None,
+        }
+    }
+}
+
+/// Writes an `IMAnnData` instance to an h5ad file using the default `WriteOptions`.
+///
+/// # Arguments
+///
+/// * `adata` - The object to write.
+/// * `path` - Location of the h5ad file, an existing file is overwritten.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be created or any element fails to be written.
+pub fn write_h5ad<P: AsRef<Path>>(adata: &IMAnnData, path: P) -> anyhow::Result<()> {
+    write_h5ad_with_options(adata, path, WriteOptions::default())
+}
+
+/// Writes an `IMAnnData` instance to an h5ad file.
+///
+/// # Arguments
+///
+/// * `adata` - The object to write.
+/// * `path` - Location of the h5ad file, an existing file is overwritten.
+/// * `options` - Compression and chunking applied to `X` and the layers.
+///
+/// # Notes
+///
+/// Metadata, axis arrays and `uns` are written through `anndata`. Numeric
+/// matrices in `X` and the layers are written directly with the HDF5 dataset-creation
+/// parameters derived from `options`; other matrices fall back to the `anndata` defaults.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be created or any element fails to be written.
+pub fn write_h5ad_with_options<P: AsRef<Path>>(
+    adata: &IMAnnData,
+    path: P,
+    options: WriteOptions,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let out = AnnData::<H5>::new(path)?;
+    let deferred = write_elements(adata, &out, true)?;
+    out.close()?;
+
+    if deferred.is_empty() {
+        return Ok(());
+    }
+    let file = hdf5::File::open_rw(path)?;
+    for (key, data) in deferred {
+        match key {
+            None => write_array(&file, "X", &data, &options)?,
+            Some(key) => {
+                let layers = if file.link_exists("layers") {
+                    file.group("layers")?
+                } else {
+                    // Every h5ad group carries its encoding, as written by `anndata`
+                    let group = file.create_group("layers")?;
+                    write_str_attr(&group, "encoding-type", "dict")?;
+                    write_str_attr(&group, "encoding-version", "0.1.0")?;
+                    group
+                };
+                write_array(&layers, &key, &data, &options)?
+            }
+        }
+    }
+    file.close()?;
+    Ok(())
+}
+
+/// Writes all elements of `adata` into `out` through the `anndata` API.
+///
+/// With `defer_chunkable` set, numeric matrices of `X` and the layers are not written but
+/// returned together with their layer name (`None` for `X`), so the caller can store them
+/// with custom dataset-creation parameters.
+pub(crate) fn write_elements<B: Backend>(
+    adata: &IMAnnData,
+    out: &AnnData<B>,
+    defer_chunkable: bool,
+) -> anyhow::Result<Vec<(Option<String>, ArrayData)>> {
+    let mut deferred: Vec<(Option<String>, ArrayData)> = Vec::new();
+
+    out.set_obs_names(adata.obs().get_index())?;
+    out.set_var_names(adata.var().get_index())?;
+    let mut obs_df = adata.obs().get_data();
+    let _ = obs_df.drop_in_place("index");
+    out.set_obs(obs_df)?;
+    let mut var_df = adata.var().get_data();
+    let _ = var_df.drop_in_place("index");
+    out.set_var(var_df)?;
+
+    let x = adata.x().get_data()?;
+    if defer_chunkable && is_chunkable(&x) {
+        deferred.push((None, x));
+    } else {
+        out.set_x(x)?;
+    }
+    let layers = adata.layers();
+    for key in layers.keys() {
+        let data = layers.get_array_shallow(&key)?.get_data()?;
+        if defer_chunkable && is_chunkable(&data) {
+            deferred.push((Some(key), data));
+        } else {
+            out.layers().add(&key, data)?;
+        }
+    }
+
+    write_axis_arrays(&adata.obsm(), out.obsm())?;
+    write_axis_arrays(&adata.obsp(), out.obsp())?;
+    write_axis_arrays(&adata.varm(), out.varm())?;
+    write_axis_arrays(&adata.varp(), out.varp())?;
+    let uns = adata.uns();
+    for (key, element) in uns.0.read_inner().deref().iter() {
+        out.uns().add(key, element.get_data()?)?;
+    }
+    Ok(deferred)
+}
+
+fn write_axis_arrays<A: AxisArraysOp>(source: &IMAxisArrays, target: A) -> anyhow::Result<()> {
+    for key in source.keys() {
+        target.add(&key, source.get_array_shallow(&key)?.get_data()?)?;
+    }
+    Ok(())
+}
+
+fn is_chunkable(data: &ArrayData) -> bool {
+    match data {
+        ArrayData::Array(arr) => dispatch_numeric!(arr, DynArray, _a => true, false),
+        ArrayData::CsrMatrix(csr) => dispatch_numeric!(csr, DynCsrMatrix, _m => true, false),
+        ArrayData::CscMatrix(csc) => dispatch_numeric!(csc, DynCscMatrix, _m => true, false),
+        _ => false,
+    }
+}
+
+fn write_array(
+    group: &Group,
+    name: &str,
+    data: &ArrayData,
+    options: &WriteOptions,
+) -> anyhow::Result<()> {
+    match data {
+        ArrayData::Array(arr) => dispatch_numeric!(
+            arr,
+            DynArray,
+            a => write_dense(group, name, a, options),
+            Err(anyhow::anyhow!("Unsupported dense array type for {}", name))
+        ),
+        ArrayData::CsrMatrix(csr) => dispatch_numeric!(
+            csr,
+            DynCsrMatrix,
+            m => write_csr(group, name, m, options),
+            Err(anyhow::anyhow!("Unsupported CSR matrix type for {}", name))
+        ),
+        ArrayData::CscMatrix(csc) => dispatch_numeric!(
+            csc,
+            DynCscMatrix,
+            m => write_csc(group, name, m, options),
+            Err(anyhow::anyhow!("Unsupported CSC matrix type for {}", name))
+        ),
+        _ => Err(anyhow::anyhow!("Unsupported array type for {}", name)),
+    }
+}
+
+fn write_dense<T: H5Type + Clone>(
+    group: &Group,
+    name: &str,
+    arr: &ArrayD<T>,
+    options: &WriteOptions,
+) -> anyhow::Result<()> {
+    let arr = arr.as_standard_layout();
+    let shape = arr.shape().to_vec();
+    let mut builder = group.new_dataset_builder().with_data(arr.view());
+    if let Some(chunk_rows) = effective_chunk_rows(options) {
+        if shape.iter().all(|&d| d > 0) {
+            let mut chunk = shape.clone();
+            chunk[0] = chunk_rows.min(shape[0]);
+            builder = apply_compression(builder.chunk(chunk), options);
+        }
+    }
+    let dataset = builder.create(name)?;
+    write_str_attr(&dataset, "encoding-type", "array")?;
+    write_str_attr(&dataset, "encoding-version", "0.2.0")?;
+    Ok(())
+}
+
+fn write_csr<T: H5Type>(
+    group: &Group,
+    name: &str,
+    m: &CsrMatrix<T>,
+    options: &WriteOptions,
+) -> anyhow::Result<()> {
+    write_compressed_sparse(
+        group,
+        name,
+        "csr_matrix",
+        [m.nrows(), m.ncols()],
+        m.row_offsets(),
+        m.col_indices(),
+        m.values(),
+        options,
+    )
+}
+
+fn write_csc<T: H5Type>(
+    group: &Group,
+    name: &str,
+    m: &CscMatrix<T>,
+    options: &WriteOptions,
+) -> anyhow::Result<()> {
+    write_compressed_sparse(
+        group,
+        name,
+        "csc_matrix",
+        [m.nrows(), m.ncols()],
+        m.col_offsets(),
+        m.row_indices(),
+        m.values(),
+        options,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_compressed_sparse<T: H5Type>(
+    group: &Group,
+    name: &str,
+    encoding: &str,
+    shape: [usize; 2],
+    offsets: &[usize],
+    indices: &[usize],
+    values: &[T],
+    options: &WriteOptions,
+) -> anyhow::Result<()> {
+    let sparse_group = group.create_group(name)?;
+    write_str_attr(&sparse_group, "encoding-type", encoding)?;
+    write_str_attr(&sparse_group, "encoding-version", "0.1.0")?;
+    let shape_i64 = [shape[0] as i64, shape[1] as i64];
+    sparse_group
+        .new_attr_builder()
+        .with_data(&shape_i64[..])
+        .create("shape")?;
+
+    // Chunks of the flat arrays hold roughly `chunk_rows` rows worth of entries
+    let major = offsets.len().saturating_sub(1).max(1);
+    let chunk_len = effective_chunk_rows(options)
+        .map(|rows| ((values.len() / major).max(1) * rows).max(1));
+
+    let indptr: Vec<i64> = offsets.iter().map(|&v| v as i64).collect();
+    let indices: Vec<i64> = indices.iter().map(|&v| v as i64).collect();
+    write_flat(&sparse_group, "data", values, chunk_len, options)?;
+    write_flat(&sparse_group, "indices", &indices, chunk_len, options)?;
+    write_flat(&sparse_group, "indptr", &indptr, chunk_len, options)?;
+    Ok(())
+}
+
+fn write_flat<T: H5Type>(
+    group: &Group,
+    name: &str,
+    values: &[T],
+    chunk_len: Option<usize>,
+    options: &WriteOptions,
+) -> anyhow::Result<()> {
+    let mut builder = group.new_dataset_builder().with_data(values);
+    if let Some(chunk_len) = chunk_len {
+        if !values.is_empty() {
+            builder = apply_compression(builder.chunk(chunk_len.min(values.len())), options);
+        }
+    }
+    builder.create(name)?;
+    Ok(())
+}
+
+fn effective_chunk_rows(options: &WriteOptions) -> Option<usize> {
+    match (options.chunk_rows, options.compression) {
+        (Some(rows), _) => Some(rows.max(1)),
+        (None, Some(_)) => Some(DEFAULT_CHUNK_ROWS),
+        (None, None) => None,
+    }
+}
+
+fn apply_compression<'d, T: H5Type, D: ndarray::Dimension>(
+    builder: hdf5::DatasetBuilderData<'d, T, D>,
+    options: &WriteOptions,
+) -> hdf5::DatasetBuilderData<'d, T, D> {
+    match options.compression {
+        Some(Compression::Gzip(level)) => builder.shuffle().deflate(level.min(9)),
+        #[cfg(feature = "lzf")]
+        Some(Compression::Lzf) => builder.shuffle().lzf(),
+        None => builder,
+    }
+}
+
+fn write_str_attr(location: &hdf5::Location, name: &str, value: &str) -> anyhow::Result<()> {
+    let value = VarLenUnicode::from_str(value)?;
+    location
+        .new_attr::<VarLenUnicode>()
+        .create(name)?
+        .write_scalar(&value)?;
+    Ok(())
+}
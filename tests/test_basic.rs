@@ -1,6 +1,24 @@
-use anndata::{container::Axis, data::DynCsrMatrix, ArrayData};
+use std::collections::HashMap;
+
+use anndata::{
+    backend::ScalarType,
+    container::{Axis, Dim},
+    data::{DynCsrMatrix, SelectInfoElem},
+    ArrayData,
+};
 use nalgebra_sparse::{CooMatrix, CsrMatrix};
-use anndata_memory::{IMAnnData, IMArrayElement};
+use polars::{
+    prelude::{AnyValue, CategoricalOrdering, DataType, NamedFrom},
+    series::Series,
+};
+use anndata_memory::{
+    AggOp, BoolOp, ComponentSet, ConcatJoin, CsrBuilder, DeepClone, DisplayOptions, DtypeMismatch, Element, IMAnnData, IMArrayElement, IMAxisArrays, IMDataFrameElement,
+    IMElementCollection, LayerSubsetPolicy, MatrixAxis, Metric, NanPolicy, SubsetPlan,
+};
+use anndata_memory::{
+    indices_to_select_info_elem, mask_to_select_info_elem, range_to_select_info_elem,
+    select_info_elem_to_indices,
+};
 
 fn create_test_data() -> (ArrayData, Vec<String>, Vec<String>) {
     let nrows = 3;
@@ -119,4 +137,2481 @@ fn test_uns() {
 
     let uns = adata.uns();
     assert!(uns.get_data("test_key").is_err());
-}
\ No newline at end of file
+}
+#[test]
+fn test_obs_names_intersection_and_difference() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names.clone()).unwrap();
+    let other_obs = vec!["obs3".to_string(), "obs4".to_string(), "obs2".to_string()];
+    let other = IMAnnData::new_basic(matrix, other_obs, var_names).unwrap();
+
+    assert_eq!(
+        adata.obs_names_intersection(&other),
+        vec!["obs2".to_string(), "obs3".to_string()]
+    );
+    assert_eq!(adata.obs_names_difference(&other), vec!["obs1".to_string()]);
+    assert_eq!(other.obs_names_difference(&adata), vec!["obs4".to_string()]);
+}
+
+#[test]
+fn test_non_finite_detection() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names.clone(), var_names.clone()).unwrap();
+    assert!(adata.x_is_finite().unwrap());
+    assert_eq!(adata.x().count_non_finite().unwrap(), 0);
+
+    let mut coo_matrix = CooMatrix::new(3, 3);
+    coo_matrix.push(0, 0, 1.0);
+    coo_matrix.push(1, 2, f64::NAN);
+    coo_matrix.push(2, 1, f64::INFINITY);
+    let csr_matrix: CsrMatrix<f64> = CsrMatrix::from(&coo_matrix);
+    let matrix = ArrayData::CsrMatrix(DynCsrMatrix::from(csr_matrix));
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    assert!(!adata.x_is_finite().unwrap());
+    assert!(adata.x().has_non_finite().unwrap());
+    assert_eq!(adata.x().count_non_finite().unwrap(), 2);
+}
+
+#[test]
+fn test_sample_obs_reproducible() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    let first = adata.sample_obs(2, false, 42).unwrap();
+    let second = adata.sample_obs(2, false, 42).unwrap();
+    assert_eq!(first.n_obs(), 2);
+    assert_eq!(first.n_vars(), 3);
+    assert_eq!(first.obs_names(), second.obs_names());
+
+    let with_replacement = adata.sample_obs(5, true, 7).unwrap();
+    assert_eq!(with_replacement.n_obs(), 5);
+    assert!(adata.sample_obs(4, false, 42).is_err());
+}
+
+#[test]
+fn test_subset_array_cols() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    let embedding = ndarray::Array2::from_shape_fn((3, 5), |(i, j)| (i * 5 + j) as f64);
+    let obsm = adata.obsm();
+    obsm.add_array(
+        "X_pca".to_string(),
+        IMArrayElement::new(ArrayData::from(embedding.into_dyn())),
+    )
+    .unwrap();
+
+    assert!(obsm.subset_array_cols("X_pca", 6).is_err());
+    obsm.subset_array_cols("X_pca", 2).unwrap();
+
+    let shape = obsm.get_array("X_pca").unwrap().get_shape().unwrap();
+    assert_eq!(vec![shape[0], shape[1]], vec![3, 2]);
+    assert_eq!(obsm.dimensions().0.get(), 3);
+    assert!(obsm.subset_array_cols("missing", 1).is_err());
+}
+
+#[test]
+fn test_subset_inplace_failure_leaves_object_unchanged() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix, obs_names.clone(), var_names).unwrap();
+
+    // Corrupt obsm so that its subset fails after the X selection would have been computed
+    let broken = IMAxisArrays::new_from(Axis::Row, Dim::new(2), None, HashMap::new());
+    adata.obsm().0.swap(&broken.0);
+
+    let obs_sel = SelectInfoElem::Index(vec![2]);
+    let var_sel = SelectInfoElem::full();
+    assert!(adata.subset_inplace(&[&obs_sel, &var_sel]).is_err());
+
+    let shape = adata.x().get_shape().unwrap();
+    assert_eq!(vec![shape[0], shape[1]], vec![3, 3]);
+    assert_eq!(adata.n_obs(), 3);
+    assert_eq!(adata.obs_names(), obs_names);
+    assert_eq!(adata.obs().get_data().height(), 3);
+}
+
+#[test]
+fn test_subset_inplace_updates_shallow_handles() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let x = adata.x();
+
+    let obs_sel = SelectInfoElem::Index(vec![0, 2]);
+    let var_sel = SelectInfoElem::full();
+    adata.subset_inplace(&[&obs_sel, &var_sel]).unwrap();
+
+    let shape = x.get_shape().unwrap();
+    assert_eq!(vec![shape[0], shape[1]], vec![2, 3]);
+    assert_eq!(adata.obs_names(), vec!["obs1".to_string(), "obs3".to_string()]);
+}
+
+#[test]
+fn test_score_genes() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names.clone()).unwrap();
+
+    // With every gene in the set no controls remain, so the score is the plain mean
+    let scores = adata.score_genes(&var_names, 5, 0, "score").unwrap();
+    let expected = [1.0 / 3.0, 2.0 / 3.0, 7.0 / 3.0];
+    for (score, expected) in scores.iter().zip(expected) {
+        assert!((score - expected).abs() < 1e-12);
+    }
+    assert_eq!(adata.obs().get_column_from_df("score").unwrap().len(), 3);
+
+    assert!(adata
+        .score_genes(&["missing".to_string()], 5, 0, "score")
+        .is_err());
+}
+
+#[test]
+fn test_score_genes_excludes_set_from_controls() {
+    // 50 genes with increasing means fall into 25 bins of two: {0, 1}, {2, 3}, ...
+    let x = ndarray::Array2::from_shape_fn((3, 50), |(i, j)| ((i + 1) * j) as f64);
+    let obs_names = (0..3).map(|i| format!("cell{}", i)).collect();
+    let var_names: Vec<String> = (0..50).map(|j| format!("gene{}", j)).collect();
+    let adata = IMAnnData::new_basic(ArrayData::from(x.into_dyn()), obs_names, var_names).unwrap();
+
+    // gene3 can only be controlled by gene2, whatever the seed draws
+    for seed in [0, 1, 42] {
+        let scores = adata
+            .score_genes(&["gene3".to_string()], 5, seed, "module")
+            .unwrap();
+        assert_eq!(scores, vec![1.0, 2.0, 3.0]);
+    }
+    let stored = adata.obs().get_column_from_df("module").unwrap();
+    assert_eq!(stored.f64().unwrap().get(2), Some(3.0));
+}
+
+#[test]
+fn test_remove_columns() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let obs = adata.obs();
+    for name in ["qc1", "qc2", "qc3"] {
+        obs.attach_column_to_df(Series::new(name, &[1i32, 2, 3])).unwrap();
+    }
+
+    let err = obs.remove_columns(&["qc1", "qc2", "missing"]).unwrap_err();
+    assert!(err.to_string().contains("missing"));
+    let columns: Vec<String> = obs
+        .get_data()
+        .get_column_names()
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+    assert_eq!(columns, vec!["index".to_string(), "qc3".to_string()]);
+
+    assert!(obs.remove_columns(&["index"]).is_err());
+    assert!(obs.get_column_from_df("index").is_ok());
+}
+
+#[test]
+fn test_value_counts() {
+    let (_, _, var_names) = create_test_data();
+    let obs_names: Vec<String> = (0..6).map(|i| format!("obs{}", i)).collect();
+    let mut coo_matrix = CooMatrix::<f64>::new(6, 3);
+    coo_matrix.push(0, 0, 1.0);
+    let csr_matrix: CsrMatrix<f64> = CsrMatrix::from(&coo_matrix);
+    let adata = IMAnnData::new_basic(
+        ArrayData::CsrMatrix(DynCsrMatrix::from(csr_matrix)),
+        obs_names,
+        var_names,
+    )
+    .unwrap();
+    let obs = adata.obs();
+    obs.attach_column_to_df(Series::new(
+        "cluster",
+        &["b", "a", "b", "c", "b", "a"],
+    ))
+    .unwrap();
+    obs.attach_column_to_df(Series::new("n_genes", &[1i32, 2, 3, 4, 5, 6]))
+        .unwrap();
+
+    let counts = obs.value_counts("cluster").unwrap();
+    assert_eq!(
+        counts,
+        vec![
+            ("b".to_string(), 3),
+            ("a".to_string(), 2),
+            ("c".to_string(), 1)
+        ]
+    );
+    assert!(obs.value_counts("n_genes").is_err());
+    assert!(obs.value_counts("missing").is_err());
+}
+
+#[test]
+fn test_share_count() {
+    let (matrix, _, _) = create_test_data();
+    let element = IMArrayElement::new(matrix);
+    assert_eq!(element.share_count(), 1);
+
+    let shallow = element.clone();
+    assert_eq!(element.share_count(), 2);
+    assert_eq!(shallow.share_count(), 2);
+
+    let deep = element.deep_clone();
+    assert_eq!(element.share_count(), 2);
+    assert_eq!(deep.share_count(), 1);
+}
+
+#[test]
+fn test_to_dense_f32() {
+    let (matrix, _, _) = create_test_data();
+    let element = IMArrayElement::new(matrix);
+
+    let dense = element.to_dense_f32().unwrap();
+    let expected: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 0.0, 2.0], [0.0, 3.0, 4.0]];
+    for i in 0..3 {
+        for j in 0..3 {
+            assert!((dense[[i, j]] - expected[i][j]).abs() < 1e-6);
+        }
+    }
+
+    let dense_i64 = element.to_dense::<i64>().unwrap();
+    assert_eq!(dense_i64[[2, 2]], 4);
+    assert_eq!(element.to_dense_f64().unwrap()[[1, 2]], 2.0);
+}
+
+#[test]
+fn test_attach_columns() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let obs = adata.obs();
+
+    let result = obs.attach_columns(vec![
+        Series::new("good", &[1i32, 2, 3]),
+        Series::new("bad", &[1i32, 2]),
+    ]);
+    assert!(result.is_err());
+    assert_eq!(obs.get_data().width(), 1);
+
+    obs.attach_columns(vec![
+        Series::new("n_counts", &[1.0f64, 2.0, 7.0]),
+        Series::new("n_genes", &[1i32, 1, 2]),
+    ])
+    .unwrap();
+    assert_eq!(obs.get_data().width(), 3);
+}
+
+#[test]
+fn test_map_obs_chunks_matches_one_shot() {
+    fn scale(adata: &mut IMAnnData) -> anyhow::Result<()> {
+        let dense = adata.x().to_dense_f64()? * 2.0;
+        adata.x().set_data(ArrayData::from(dense.into_dyn()))?;
+        let layer = adata.layers().get_array_shallow("counts")?;
+        let dense = layer.to_dense_f64()? + 1.0;
+        layer.set_data(ArrayData::from(dense.into_dyn()))
+    }
+
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut chunked = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    chunked
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+    let mut one_shot = chunked.deep_clone();
+
+    chunked.map_obs_chunks(2, scale).unwrap();
+    scale(&mut one_shot).unwrap();
+
+    assert_eq!(
+        chunked.x().to_dense_f64().unwrap(),
+        one_shot.x().to_dense_f64().unwrap()
+    );
+    assert_eq!(
+        chunked.get_layer("counts").unwrap().to_dense_f64().unwrap(),
+        one_shot.get_layer("counts").unwrap().to_dense_f64().unwrap()
+    );
+    assert!(chunked.map_obs_chunks(0, scale).is_err());
+}
+
+#[test]
+fn test_map_obs_chunks_rejects_mixed_formats() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+    let original = adata.deep_clone();
+
+    // Only the second chunk is densified, so the chunks of the layer no longer stack
+    let mut n_chunks = 0;
+    let result = adata.map_obs_chunks(2, |chunk| {
+        n_chunks += 1;
+        chunk.x().set_data(ArrayData::from(
+            (chunk.x().to_dense_f64()? * 2.0).into_dyn(),
+        ))?;
+        if n_chunks == 2 {
+            let layer = chunk.layers().get_array_shallow("counts")?;
+            layer.set_data(ArrayData::from(layer.to_dense_f64()?.into_dyn()))?;
+        }
+        Ok(())
+    });
+    assert!(result.is_err());
+    assert_eq!(adata.x().to_dense_f64().unwrap(), original.x().to_dense_f64().unwrap());
+    let counts = |a: &IMAnnData| a.layers().get_array_shallow("counts").unwrap().to_dense_f64().unwrap();
+    assert_eq!(counts(&adata), counts(&original));
+}
+
+#[test]
+fn test_obs_row() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("n_genes", &[1i32, 1, 2]))
+        .unwrap();
+
+    let row = adata.obs_row(1).unwrap();
+    assert_eq!(row["n_genes"], AnyValue::Int32(1));
+    assert_eq!(row["index"], AnyValue::StringOwned("obs2".into()));
+    assert!(adata.obs_row(3).is_err());
+}
+
+#[test]
+fn test_reject_non_2d_x() {
+    let vector = ArrayData::from(ndarray::Array1::from(vec![1.0f64, 2.0, 3.0]).into_dyn());
+    assert!(IMArrayElement::new_checked(vector.clone()).is_err());
+    assert!(IMAnnData::new_basic(vector, vec!["obs1".to_string()], vec![]).is_err());
+
+    let (matrix, _, _) = create_test_data();
+    assert!(IMArrayElement::new_checked(matrix).is_ok());
+}
+
+#[test]
+fn test_balanced_subsample() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("group", &["a", "b", "a"]))
+        .unwrap();
+
+    let subset = adata.balanced_subsample("group", 1, 3).unwrap();
+    assert_eq!(subset.n_obs(), 2);
+    let groups = subset.obs().get_column_from_df("group").unwrap();
+    let mut labels: Vec<&str> = groups.str().unwrap().into_no_null_iter().collect();
+    labels.sort();
+    assert_eq!(labels, vec!["a", "b"]);
+    assert_eq!(
+        subset.obs_names(),
+        adata.balanced_subsample("group", 1, 3).unwrap().obs_names()
+    );
+
+    let all = adata.balanced_subsample("group", 5, 3).unwrap();
+    assert_eq!(all.n_obs(), 3);
+    assert!(adata.balanced_subsample("missing", 1, 3).is_err());
+
+    // Missing values do not merge with a category that is literally named "null"
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("label", &[Some("null"), None, Some("null")]))
+        .unwrap();
+    assert_eq!(adata.balanced_subsample("label", 1, 3).unwrap().n_obs(), 2);
+}
+
+#[test]
+fn test_concat_obs_outer_join_fills_missing_layers() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut first = IMAnnData::new_basic(matrix, obs_names, var_names.clone()).unwrap();
+    let (spliced, _, _) = create_test_data();
+    first
+        .add_layer("spliced".to_string(), IMArrayElement::new(spliced))
+        .unwrap();
+    let (matrix, _, _) = create_test_data();
+    let second_names = vec!["obs4".to_string(), "obs5".to_string(), "obs6".to_string()];
+    let second = IMAnnData::new_basic(matrix, second_names, var_names).unwrap();
+
+    assert!(IMAnnData::concat_obs(&[&first, &second]).is_err());
+
+    let combined = IMAnnData::concat_obs_with_join(&[&first, &second], ConcatJoin::Outer).unwrap();
+    assert_eq!(combined.n_obs(), 6);
+    assert_eq!(combined.obs_names()[3], "obs4");
+    let layer = combined.get_layer("spliced").unwrap().to_dense_f64().unwrap();
+    assert_eq!(layer.shape(), &[6, 3]);
+    assert_eq!(layer[[2, 2]], 4.0);
+    assert!(layer.slice(ndarray::s![3.., ..]).iter().all(|&v| v == 0.0));
+}
+
+#[test]
+fn test_reorder_categories() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let cell_type = Series::new("cell_type", &["b", "a", "c"])
+        .cast(&DataType::Categorical(None, CategoricalOrdering::Physical))
+        .unwrap();
+    adata.obs().attach_column_to_df(cell_type).unwrap();
+
+    let order: Vec<String> = ["c", "a", "b"].iter().map(|s| s.to_string()).collect();
+    adata.obs().reorder_categories("cell_type", &order).unwrap();
+
+    let column = adata.obs().get_column_from_df("cell_type").unwrap();
+    let categories: Vec<&str> = column
+        .categorical()
+        .unwrap()
+        .get_rev_map()
+        .get_categories()
+        .values_iter()
+        .collect();
+    assert_eq!(categories, vec!["c", "a", "b"]);
+    let values = column.cast(&DataType::String).unwrap();
+    let values: Vec<&str> = values.str().unwrap().into_no_null_iter().collect();
+    assert_eq!(values, vec!["b", "a", "c"]);
+
+    let incomplete: Vec<String> = vec!["a".to_string(), "b".to_string()];
+    assert!(adata.obs().reorder_categories("cell_type", &incomplete).is_err());
+}
+
+#[test]
+fn test_empty_slot_keys() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix.clone()))
+        .unwrap();
+    adata
+        .add_layer("spliced".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+    assert!(adata.layers().empty_slot_keys().is_empty());
+
+    adata.get_layer_shallow("spliced").unwrap().0.extract();
+    assert_eq!(adata.layers().empty_slot_keys(), vec!["spliced".to_string()]);
+
+    let uns = IMElementCollection::new_empty();
+    uns.add_data("kept".to_string(), Element::new(anndata::Data::from(1i64)))
+        .unwrap();
+    uns.add_data("gone".to_string(), Element::new(anndata::Data::from(2i64)))
+        .unwrap();
+    uns.get_data("gone").unwrap().0.extract();
+    assert_eq!(uns.empty_slot_keys(), vec!["gone".to_string()]);
+}
+
+#[test]
+fn test_to_fractions() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    adata.to_fractions(Some("fractions".to_string())).unwrap();
+    let fractions = adata.get_layer("fractions").unwrap().to_dense_f64().unwrap();
+    for row in fractions.rows() {
+        assert!((row.sum() - 1.0).abs() < 1e-12);
+    }
+    assert!((fractions[[2, 2]] - 4.0 / 7.0).abs() < 1e-12);
+    assert_eq!(adata.x().to_dense_f64().unwrap()[[2, 2]], 4.0);
+
+    adata.to_fractions(None).unwrap();
+    assert_eq!(adata.x().to_dense_f64().unwrap(), fractions);
+}
+
+#[test]
+fn test_obsp_neighbors() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let mut coo = CooMatrix::new(3, 3);
+    coo.push(0, 1, 0.5);
+    coo.push(0, 2, 0.9);
+    coo.push(1, 0, 0.5);
+    coo.push(2, 0, 0.9);
+    coo.push(2, 1, 0.2);
+    let connectivities = ArrayData::CsrMatrix(DynCsrMatrix::from(CsrMatrix::from(&coo)));
+    adata
+        .obsp()
+        .add_array("connectivities".to_string(), IMArrayElement::new(connectivities))
+        .unwrap();
+
+    let all = adata.obsp().neighbors("connectivities", None).unwrap();
+    assert_eq!(all, vec![vec![1, 2], vec![0], vec![0, 1]]);
+    let top = adata.obsp().neighbors("connectivities", Some(1)).unwrap();
+    assert_eq!(top, vec![vec![2], vec![0], vec![0]]);
+
+    assert!(adata.obsp().neighbors("missing", None).is_err());
+    assert!(adata.layers().neighbors("connectivities", None).is_err());
+}
+
+#[test]
+fn test_rename_var_names() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    let mapping: HashMap<String, String> = [("var1", "CD3E"), ("var3", "MS4A1")]
+        .iter()
+        .map(|(from, to)| (from.to_string(), to.to_string()))
+        .collect();
+    adata.rename_var_names(&mapping).unwrap();
+    assert_eq!(adata.var_names(), vec!["CD3E", "var2", "MS4A1"]);
+    let index_column = adata.var().get_column_from_df("index").unwrap();
+    let index_values: Vec<&str> = index_column.str().unwrap().into_no_null_iter().collect();
+    assert_eq!(index_values, vec!["CD3E", "var2", "MS4A1"]);
+
+    let collision: HashMap<String, String> =
+        [("var2".to_string(), "CD3E".to_string())].into_iter().collect();
+    assert!(adata.rename_var_names(&collision).is_err());
+    assert_eq!(adata.var_names(), vec!["CD3E", "var2", "MS4A1"]);
+
+    let obs_mapping: HashMap<String, String> =
+        [("obs2".to_string(), "cell_b".to_string())].into_iter().collect();
+    adata.rename_obs_names(&obs_mapping).unwrap();
+    assert_eq!(adata.obs_names(), vec!["obs1", "cell_b", "obs3"]);
+}
+
+#[test]
+fn test_x_aliases_layer() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    adata.add_layer("shallow".to_string(), adata.x()).unwrap();
+    adata
+        .add_layer("deep".to_string(), adata.x().deep_clone())
+        .unwrap();
+
+    assert!(adata.x_aliases_layer("shallow").unwrap());
+    assert!(!adata.x_aliases_layer("deep").unwrap());
+    assert!(adata.x_aliases_layer("missing").is_err());
+}
+
+#[test]
+fn test_subset_plan_matches_sequential_filters() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("n_counts", &[1.0, 2.0, 7.0]))
+        .unwrap();
+
+    let planned = SubsetPlan::new()
+        .keep_obs_names(vec!["obs1".to_string(), "obs3".to_string()])
+        .filter_obs_range("n_counts", Some(2.0), None)
+        .apply(&adata)
+        .unwrap();
+
+    let by_name = adata
+        .subset(&[&SelectInfoElem::Index(vec![0, 2]), &SelectInfoElem::full()])
+        .unwrap();
+    let sequential = SubsetPlan::new()
+        .filter_obs_range("n_counts", Some(2.0), None)
+        .apply(&by_name)
+        .unwrap();
+
+    assert_eq!(planned.obs_names(), vec!["obs3"]);
+    assert_eq!(planned.obs_names(), sequential.obs_names());
+    assert_eq!(planned.var_names(), sequential.var_names());
+    assert_eq!(
+        planned.x().to_dense_f64().unwrap(),
+        sequential.x().to_dense_f64().unwrap()
+    );
+    assert!(SubsetPlan::new()
+        .filter_obs_mask(vec![true])
+        .apply(&adata)
+        .is_err());
+}
+
+#[test]
+fn test_to_long_df() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    let long = adata.to_long_df(&["var3".to_string()]).unwrap();
+    assert_eq!(long.height(), adata.n_obs());
+    let obs: Vec<&str> = long.column("obs_name").unwrap().str().unwrap().into_no_null_iter().collect();
+    assert_eq!(obs, vec!["obs1", "obs2", "obs3"]);
+    let values: Vec<f64> = long.column("value").unwrap().f64().unwrap().into_no_null_iter().collect();
+    assert_eq!(values, vec![0.0, 2.0, 4.0]);
+    assert!(adata.to_long_df(&["missing".to_string()]).is_err());
+}
+
+#[test]
+fn test_add_empty_layer() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    adata
+        .add_empty_layer("velocity".to_string(), ScalarType::F32, true)
+        .unwrap();
+    let layer = adata.get_layer("velocity").unwrap();
+    let shape = layer.get_shape().unwrap();
+    assert_eq!(vec![shape[0], shape[1]], vec![3, 3]);
+    match layer.get_data().unwrap() {
+        ArrayData::CsrMatrix(DynCsrMatrix::F32(m)) => {
+            assert_eq!(m.nnz(), 0);
+            assert_eq!(m.row_offsets(), &[0, 0, 0, 0]);
+        }
+        _ => panic!("Expected an f32 CSR matrix"),
+    }
+
+    adata
+        .add_empty_layer("dense".to_string(), ScalarType::I32, false)
+        .unwrap();
+    assert_eq!(adata.get_layer("dense").unwrap().to_dense_f64().unwrap().sum(), 0.0);
+    assert!(adata
+        .add_empty_layer("velocity".to_string(), ScalarType::F32, true)
+        .is_err());
+}
+
+#[test]
+fn test_select_info_elem_constructors() {
+    let indices = vec![4, 0, 2];
+    let selection = indices_to_select_info_elem(indices.clone());
+    assert_eq!(select_info_elem_to_indices(&selection, 5).unwrap(), indices);
+
+    let range = range_to_select_info_elem(1, 6, 2).unwrap();
+    assert_eq!(select_info_elem_to_indices(&range, 6).unwrap(), vec![1, 3, 5]);
+    assert!(range_to_select_info_elem(0, 6, 0).is_err());
+    assert!(range_to_select_info_elem(4, 2, 1).is_err());
+}
+
+#[test]
+fn test_empty_subset_is_valid() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("n_counts", &[1.0, 2.0, 7.0]))
+        .unwrap();
+    let embedding = ndarray::Array2::<f64>::zeros((3, 2));
+    adata
+        .obsm()
+        .add_array(
+            "X_pca".to_string(),
+            IMArrayElement::new(ArrayData::from(embedding.into_dyn())),
+        )
+        .unwrap();
+
+    let empty = SubsetPlan::new()
+        .filter_obs_mask(vec![false; 3])
+        .apply(&adata)
+        .unwrap();
+    assert_eq!(empty.n_obs(), 0);
+    assert_eq!(empty.n_vars(), 3);
+    empty.validate().unwrap();
+    assert!(matches!(empty.x().get_data().unwrap(), ArrayData::CsrMatrix(_)));
+    assert_eq!(empty.x().get_shape().unwrap()[0], 0);
+    assert!(empty.obs().get_column_from_df("n_counts").is_ok());
+    assert_eq!(empty.obsm().get_array("X_pca").unwrap().get_shape().unwrap()[0], 0);
+
+    let empty_slice = adata
+        .subset(&[&range_to_select_info_elem(0, 0, 1).unwrap(), &SelectInfoElem::full()])
+        .unwrap();
+    assert_eq!(empty_slice.n_obs(), 0);
+    empty_slice.validate().unwrap();
+}
+
+#[test]
+fn test_apply_x() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    adata.apply_x(|x| x * x).unwrap();
+    let x = adata.x().to_dense_f64().unwrap();
+    assert_eq!(x[[0, 0]], 1.0);
+    assert_eq!(x[[1, 2]], 4.0);
+    assert_eq!(x[[2, 1]], 9.0);
+    assert_eq!(x[[2, 2]], 16.0);
+    assert_eq!(x[[0, 1]], 0.0);
+
+    assert!(adata.apply_x(|x| x + 1.0).is_err());
+    assert_eq!(adata.x().to_dense_f64().unwrap(), x);
+}
+
+#[test]
+fn test_dimension_mismatch_messages() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut too_many = obs_names.clone();
+    too_many.push("obs4".to_string());
+    let err = IMAnnData::new_basic(matrix.clone(), too_many, var_names.clone()).unwrap_err();
+    assert!(err.to_string().contains("3 rows but 4 obs names"));
+
+    let x = IMArrayElement::new(matrix);
+    let obs = IMDataFrameElement::new(
+        polars::frame::DataFrame::new(vec![Series::new("index", &["a", "b", "c", "d"])]).unwrap(),
+        vec!["a", "b", "c", "d"].into_iter().map(String::from).collect::<Vec<_>>().into(),
+    );
+    let var = IMDataFrameElement::new(
+        polars::frame::DataFrame::new(vec![Series::new("index", &var_names)]).unwrap(),
+        var_names.into(),
+    );
+    let err = IMAnnData::new(x, obs, var).unwrap_err();
+    assert!(err.to_string().contains("X has 3 rows but obs has 4"));
+}
+
+#[test]
+fn test_top_genes_per_cell() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    let top = adata.top_genes_per_cell(1).unwrap();
+    assert_eq!(
+        top,
+        vec![
+            vec![("var1".to_string(), 1.0)],
+            vec![("var3".to_string(), 2.0)],
+            vec![("var3".to_string(), 4.0)],
+        ]
+    );
+    let all = adata.top_genes_per_cell(5).unwrap();
+    assert_eq!(
+        all[2],
+        vec![("var3".to_string(), 4.0), ("var2".to_string(), 3.0)]
+    );
+}
+
+#[test]
+fn test_cast_column() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("n_genes", &["12", "7", "30"]))
+        .unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("batch", &["1", "two", "3"]))
+        .unwrap();
+
+    adata.obs().cast_column("n_genes", DataType::Int64).unwrap();
+    let column = adata.obs().get_column_from_df("n_genes").unwrap();
+    assert_eq!(column.dtype(), &DataType::Int64);
+    let values: Vec<i64> = column.i64().unwrap().into_no_null_iter().collect();
+    assert_eq!(values, vec![12, 7, 30]);
+
+    assert!(adata.obs().cast_column("batch", DataType::Int64).is_err());
+    assert_eq!(
+        adata.obs().get_column_from_df("batch").unwrap().dtype(),
+        &DataType::String
+    );
+}
+
+#[test]
+fn test_zscore_panel_to_obsm() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    adata.apply_x(|x| if x == 1.0 { 0.0 } else { x }).unwrap();
+
+    let genes = vec!["var3".to_string(), "var2".to_string(), "var1".to_string()];
+    adata
+        .zscore_panel_to_obsm(&genes, "panel".to_string())
+        .unwrap();
+    let panel = adata.obsm().get_array("panel").unwrap().to_dense_f64().unwrap();
+    assert_eq!(panel.shape(), &[3, 3]);
+    for column in panel.columns() {
+        assert!(column.sum().abs() < 1e-12);
+    }
+    assert!(panel.column(0).iter().any(|&v| v != 0.0));
+    assert!(panel.column(2).iter().all(|&v| v == 0.0));
+
+    assert!(adata
+        .zscore_panel_to_obsm(&["missing".to_string()], "other".to_string())
+        .is_err());
+}
+
+#[test]
+fn test_integer_sparse_ops() {
+    let mut coo = CooMatrix::new(2, 3);
+    coo.push(0, 1, 2i64);
+    coo.push(1, 0, -3i64);
+    coo.push(1, 2, 5i64);
+    let element = IMArrayElement::new(ArrayData::CsrMatrix(DynCsrMatrix::from(
+        CsrMatrix::from(&coo),
+    )));
+
+    assert_eq!(element.nnz().unwrap(), 3);
+    element.scale_inplace(2.0).unwrap();
+    match element.get_data().unwrap() {
+        ArrayData::CsrMatrix(DynCsrMatrix::I64(m)) => assert_eq!(m.values(), &[4, -6, 10]),
+        _ => panic!("Expected an i64 CSR matrix"),
+    }
+    let stats = element.stats().unwrap();
+    assert_eq!(stats.sum, 8.0);
+    assert_eq!(stats.min, -6.0);
+    assert_eq!(stats.max, 10.0);
+
+    let strings = IMArrayElement::new(ArrayData::from(
+        ndarray::Array2::from_elem((2, 2), "a".to_string()).into_dyn(),
+    ));
+    let err = strings.nnz().unwrap_err();
+    assert!(err.downcast_ref::<DtypeMismatch>().is_some());
+}
+
+#[test]
+fn test_set_obsp_from_dense() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let distances = ndarray::arr2(&[[0.0, 0.5, 2.0], [0.5, 0.0, 0.1], [2.0, 0.1, 0.0]]);
+
+    adata
+        .set_obsp_from_dense("distances".to_string(), distances.clone(), None)
+        .unwrap();
+    let stored = adata.obsp().get_array("distances").unwrap().to_dense_f64().unwrap();
+    assert_eq!(stored, distances);
+    assert_eq!(stored[[0, 2]], stored[[2, 0]]);
+
+    adata
+        .set_obsp_from_dense("sparse".to_string(), distances, Some(0.2))
+        .unwrap();
+    let sparse = adata.obsp().get_array("sparse").unwrap();
+    assert!(matches!(sparse.get_data().unwrap(), ArrayData::CsrMatrix(_)));
+    assert_eq!(sparse.nnz().unwrap(), 4);
+    let sparse = sparse.to_dense_f64().unwrap();
+    assert_eq!(sparse[[0, 1]], sparse[[1, 0]]);
+    assert_eq!(sparse[[1, 2]], 0.0);
+
+    assert!(adata
+        .set_obsp_from_dense("bad".to_string(), ndarray::Array2::zeros((2, 2)), None)
+        .is_err());
+}
+
+#[test]
+fn test_register_derived_column() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let obs = adata.obs();
+    obs.attach_column_to_df(Series::new("n_counts", &[1.0, 9.0, 99.0]))
+        .unwrap();
+
+    obs.register_derived(
+        "log_counts".to_string(),
+        std::sync::Arc::new(|df: &polars::frame::DataFrame| {
+            let counts = df.column("n_counts").unwrap().f64().unwrap();
+            let values: Vec<f64> = counts.into_no_null_iter().map(|v| (v + 1.0).log10()).collect();
+            Series::new("", values)
+        }),
+    )
+    .unwrap();
+
+    let log_counts = obs.get_column_from_df("log_counts").unwrap();
+    assert_eq!(log_counts.name(), "log_counts");
+    let values: Vec<f64> = log_counts.f64().unwrap().into_no_null_iter().collect();
+    assert_eq!(values.len(), 3);
+    for (value, expected) in values.iter().zip([0.30103, 1.0, 2.0]) {
+        assert!((value - expected).abs() < 1e-5);
+    }
+    assert!(obs.get_data().column("log_counts").is_err());
+
+    obs.set_column_in_df("n_counts", Series::new("n_counts", &[0.0, 0.0, 9.0]))
+        .unwrap();
+    let updated = obs.get_column_from_df("log_counts").unwrap();
+    assert_eq!(updated.f64().unwrap().get(2), Some(1.0));
+
+    assert!(obs
+        .register_derived("n_counts".to_string(), std::sync::Arc::new(|df: &polars::frame::DataFrame| {
+            df.column("n_counts").unwrap().clone()
+        }))
+        .is_err());
+}
+
+#[test]
+fn test_subset_obs_mask_removes_unused_categories() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let cell_type = Series::new("cell_type", &["T", "B", "NK"])
+        .cast(&DataType::Categorical(None, CategoricalOrdering::Physical))
+        .unwrap();
+    adata.obs().attach_column_to_df(cell_type).unwrap();
+    let module = Series::new("module", &["m1", "m2", "m3"])
+        .cast(&DataType::Categorical(None, CategoricalOrdering::Physical))
+        .unwrap();
+    adata.var().attach_column_to_df(module).unwrap();
+    let category_names = |column: Series| -> Vec<String> {
+        column
+            .categorical()
+            .unwrap()
+            .get_rev_map()
+            .get_categories()
+            .values_iter()
+            .map(String::from)
+            .collect()
+    };
+    let categories = |adata: &IMAnnData| -> Vec<String> {
+        category_names(adata.obs().get_column_from_df("cell_type").unwrap())
+    };
+
+    let kept = adata.subset_obs_mask(&[true, false, true], false).unwrap();
+    assert_eq!(categories(&kept), vec!["T", "B", "NK"]);
+
+    let pruned = adata.subset_obs_mask(&[true, false, true], true).unwrap();
+    assert_eq!(categories(&pruned), vec!["T", "NK"]);
+    assert_eq!(pruned.obs_names(), vec!["obs1", "obs3"]);
+    let values = pruned
+        .obs()
+        .get_column_from_df("cell_type")
+        .unwrap()
+        .cast(&DataType::String)
+        .unwrap();
+    let values: Vec<&str> = values.str().unwrap().into_no_null_iter().collect();
+    assert_eq!(values, vec!["T", "NK"]);
+    assert!(adata.subset_obs_mask(&[true], false).is_err());
+
+    // The mask does not subset var, so unused var categories are left alone
+    let narrowed = adata
+        .subset(&[&SelectInfoElem::full(), &SelectInfoElem::Index(vec![0, 1])])
+        .unwrap();
+    let pruned = narrowed.subset_obs_mask(&[true, false, true], true).unwrap();
+    let modules = category_names(pruned.var().get_column_from_df("module").unwrap());
+    assert_eq!(modules, vec!["m1", "m2", "m3"]);
+}
+
+#[test]
+fn test_snapshot_during_concurrent_writes() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("n_counts", &[1.0, 2.0, 7.0]))
+        .unwrap();
+
+    std::thread::scope(|scope| {
+        let writer = scope.spawn(|| {
+            for i in 0..200 {
+                let layers = adata.layers();
+                if i % 2 == 0 {
+                    layers
+                        .add_array("tmp".to_string(), IMArrayElement::new(matrix.clone()))
+                        .unwrap();
+                } else {
+                    layers.remove_array("tmp").unwrap();
+                }
+                adata.x().set_data(matrix.clone()).unwrap();
+            }
+        });
+        for _ in 0..50 {
+            let snapshot = adata.snapshot().unwrap();
+            snapshot.validate().unwrap();
+            assert_eq!(snapshot.n_obs(), 3);
+            assert!(snapshot.obs().get_column_from_df("n_counts").is_ok());
+        }
+        writer.join().unwrap();
+    });
+
+    let snapshot = adata.snapshot().unwrap();
+    snapshot.x().set_data(ArrayData::from(ndarray::Array2::<f64>::zeros((3, 3)).into_dyn())).unwrap();
+    assert!(matches!(adata.x().get_data().unwrap(), ArrayData::CsrMatrix(_)));
+}
+
+#[test]
+fn test_detection_rate() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    let per_var = adata
+        .detection_rate(MatrixAxis::Var, Some("pct_cells".to_string()))
+        .unwrap();
+    assert!((per_var[2] - 2.0 / 3.0).abs() < 1e-12);
+    assert!((per_var[0] - 1.0 / 3.0).abs() < 1e-12);
+    let column = adata.var().get_column_from_df("pct_cells").unwrap();
+    assert_eq!(column.f64().unwrap().get(2), Some(per_var[2]));
+
+    let per_obs = adata.detection_rate(MatrixAxis::Obs, None).unwrap();
+    assert_eq!(per_obs.len(), 3);
+    assert!((per_obs[2] - 2.0 / 3.0).abs() < 1e-12);
+}
+
+#[test]
+fn test_deep_get_of_extracted_element_errors() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+    assert!(adata.layers().get_array("counts").is_ok());
+    adata.get_layer_shallow("counts").unwrap().0.extract();
+    assert!(adata.layers().get_array("counts").is_err());
+
+    let uns = IMElementCollection::new_empty();
+    uns.add_data("value".to_string(), Element::new(anndata::Data::from(1i64)))
+        .unwrap();
+    assert!(uns.get_data_deep("value").is_ok());
+    uns.get_data("value").unwrap().0.extract();
+    assert!(uns.get_data_deep("value").is_err());
+}
+
+#[test]
+fn test_smooth_x_with_graph() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let mut graph = CooMatrix::new(3, 3);
+    graph.push(0, 1, 0.5);
+    graph.push(0, 2, 0.5);
+    graph.push(1, 0, 1.0);
+    let graph = ArrayData::CsrMatrix(DynCsrMatrix::from(CsrMatrix::from(&graph)));
+    adata
+        .obsp()
+        .add_array("connectivities".to_string(), IMArrayElement::new(graph))
+        .unwrap();
+
+    adata
+        .smooth_x_with_graph("connectivities", Some("smoothed".to_string()))
+        .unwrap();
+    let smoothed = adata.layers().get_array("smoothed").unwrap().to_dense_f64().unwrap();
+    assert_eq!(smoothed.row(0).to_vec(), vec![0.0, 1.5, 3.0]);
+    assert_eq!(smoothed.row(1).to_vec(), vec![1.0, 0.0, 0.0]);
+    assert_eq!(smoothed.row(2).to_vec(), vec![0.0, 3.0, 4.0]);
+    assert_eq!(adata.x().to_dense_f64().unwrap()[[0, 0]], 1.0);
+    assert!(adata.smooth_x_with_graph("missing", None).is_err());
+}
+
+#[test]
+fn test_deserialize_rows() {
+    #[derive(Debug, serde::Deserialize)]
+    struct ObsRecord {
+        index: String,
+        n_genes: i64,
+        cell_type: Option<String>,
+    }
+
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("n_genes", &[1i64, 1, 2]))
+        .unwrap();
+    let cell_type = Series::new("cell_type", &[Some("T"), None, Some("B")])
+        .cast(&DataType::Categorical(None, CategoricalOrdering::Physical))
+        .unwrap();
+    adata.obs().attach_column_to_df(cell_type).unwrap();
+
+    let records: Vec<ObsRecord> = adata.obs().deserialize_rows().unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].index, "obs1");
+    assert_eq!(records[2].n_genes, 2);
+    assert_eq!(records[0].cell_type.as_deref(), Some("T"));
+    assert_eq!(records[1].cell_type, None);
+
+    #[derive(Debug, serde::Deserialize)]
+    #[allow(dead_code)]
+    struct MissingColumn {
+        index: String,
+        total_counts: f64,
+    }
+    let err = adata.obs().deserialize_rows::<MissingColumn>().unwrap_err();
+    assert!(err.to_string().contains("total_counts"));
+}
+
+#[test]
+fn test_highly_variable_genes_batched() {
+    // gene0 varies in both batches, gene1 only in batch a, gene2 is constant
+    let x = ndarray::array![
+        [1.0, 0.0, 1.0],
+        [3.0, 10.0, 1.0],
+        [1.0, 0.0, 1.0],
+        [3.0, 10.0, 1.0],
+        [1.0, 5.0, 1.0],
+        [3.0, 5.0, 1.0],
+        [1.0, 5.0, 1.0],
+        [3.0, 5.0, 1.0],
+    ];
+    let obs_names = (0..8).map(|i| format!("cell{}", i)).collect();
+    let var_names = (0..3).map(|i| format!("gene{}", i)).collect();
+    let adata = IMAnnData::new_basic(ArrayData::from(x.into_dyn()), obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("batch", &["a", "a", "a", "a", "b", "b", "b", "b"]))
+        .unwrap();
+
+    let selected = adata.highly_variable_genes_batched(2, "batch").unwrap();
+    assert_eq!(selected, vec![true, false, false]);
+
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("batch_id", &[0i32, 0, 0, 0, 1, 1, 1, 1]))
+        .unwrap();
+    assert!(adata.highly_variable_genes_batched(2, "batch_id").is_err());
+}
+
+#[test]
+fn test_try_get_layer_and_axis_array() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+
+    assert!(adata.try_get_layer("missing").is_none());
+    let layer = adata.try_get_layer("counts").unwrap();
+    assert!(layer.0.ptr_eq(&adata.get_layer_shallow("counts").unwrap().0));
+
+    let embedding = ndarray::Array2::<f64>::zeros((3, 2));
+    adata
+        .obsm()
+        .add_array("X_pca".to_string(), IMArrayElement::new(ArrayData::from(embedding.into_dyn())))
+        .unwrap();
+    assert!(adata.obsm().try_get("X_pca").is_some());
+    assert!(adata.varm().try_get("X_pca").is_none());
+}
+
+#[test]
+fn test_row_normalize_graph() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let mut graph = CooMatrix::new(3, 3);
+    graph.push(0, 1, 2.0);
+    graph.push(0, 2, 6.0);
+    graph.push(2, 0, 0.5);
+    let graph = ArrayData::CsrMatrix(DynCsrMatrix::from(CsrMatrix::from(&graph)));
+    adata
+        .obsp()
+        .add_array("connectivities".to_string(), IMArrayElement::new(graph))
+        .unwrap();
+
+    adata.obsp().row_normalize("connectivities").unwrap();
+    let normalized = adata.obsp().get_array("connectivities").unwrap().to_dense_f64().unwrap();
+    assert_eq!(normalized.row(0).sum(), 1.0);
+    assert_eq!(normalized[[0, 2]], 0.75);
+    assert_eq!(normalized.row(1).sum(), 0.0);
+    assert_eq!(normalized.row(2).sum(), 1.0);
+
+    assert!(adata.obsp().row_normalize("missing").is_err());
+    adata
+        .obsm()
+        .add_array(
+            "X_pca".to_string(),
+            IMArrayElement::new(ArrayData::from(ndarray::Array2::<f64>::ones((3, 2)).into_dyn())),
+        )
+        .unwrap();
+    assert!(adata.obsm().row_normalize("X_pca").is_err());
+}
+
+#[test]
+fn test_subset_inplace_low_mem_matches_subset() {
+    let names: Vec<String> = (0..6).map(|i| format!("cell{}", i)).collect();
+    let df = polars::frame::DataFrame::new(vec![
+        Series::new("index", names.clone()),
+        Series::new("n_counts", &[10.0, 20.0, 30.0, 40.0, 50.0, 60.0]),
+        Series::new("cell_type", &["T", "B", "T", "NK", "B", "T"])
+            .cast(&DataType::Categorical(None, CategoricalOrdering::Physical))
+            .unwrap(),
+    ])
+    .unwrap();
+    let selection = SelectInfoElem::Index(vec![5, 1, 3]);
+
+    let regular = IMDataFrameElement::new(df.clone(), names.clone().into());
+    regular.subset_inplace(&selection).unwrap();
+    let low_mem = IMDataFrameElement::new(df, names.into());
+    low_mem.subset_inplace_low_mem(&selection).unwrap();
+
+    assert!(low_mem.get_data().equals_missing(&regular.get_data()));
+    assert_eq!(low_mem.get_index().into_vec(), regular.get_index().into_vec());
+    assert_eq!(low_mem.get_index().into_vec(), vec!["cell5", "cell1", "cell3"]);
+
+    assert!(low_mem
+        .subset_inplace_low_mem(&SelectInfoElem::Index(vec![7]))
+        .is_err());
+    assert!(low_mem.get_data().equals_missing(&regular.get_data()));
+    assert_eq!(low_mem.get_index().into_vec(), vec!["cell5", "cell1", "cell3"]);
+}
+
+#[test]
+fn test_sqrt_and_anscombe_inplace() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    adata.sqrt_x().unwrap();
+    assert!(matches!(adata.x().get_data().unwrap(), ArrayData::CsrMatrix(_)));
+    assert_eq!(adata.x().nnz().unwrap(), 4);
+    let x = adata.x().to_dense_f64().unwrap();
+    assert_eq!(x[[0, 0]], 1.0);
+    assert_eq!(x[[1, 2]], 2.0f64.sqrt());
+    assert_eq!(x[[2, 1]], 3.0f64.sqrt());
+    assert_eq!(x[[2, 2]], 2.0);
+    assert_eq!(x[[0, 1]], 0.0);
+
+    assert!(adata.x().anscombe_inplace().is_err());
+    let dense = IMArrayElement::new(ArrayData::from(ndarray::array![[0.0, 1.0]].into_dyn()));
+    dense.anscombe_inplace().unwrap();
+    let dense = dense.to_dense_f64().unwrap();
+    assert_eq!(dense[[0, 0]], 2.0 * 0.375f64.sqrt());
+    assert_eq!(dense[[0, 1]], 2.0 * 1.375f64.sqrt());
+}
+
+#[test]
+fn test_reorder_columns() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let obs = adata.obs();
+    obs.attach_columns(vec![
+        Series::new("a", &[1i32, 2, 3]),
+        Series::new("b", &[4i32, 5, 6]),
+        Series::new("c", &[7i32, 8, 9]),
+    ])
+    .unwrap();
+
+    obs.reorder_columns(&["c", "a", "b"]).unwrap();
+    assert_eq!(obs.get_data().get_column_names(), vec!["index", "c", "a", "b"]);
+
+    obs.reorder_columns(&["b", "c", "a", "index"]).unwrap();
+    assert_eq!(obs.get_data().get_column_names(), vec!["b", "c", "a", "index"]);
+    assert_eq!(
+        obs.get_column_from_df("c").unwrap().i32().unwrap().get(0),
+        Some(7)
+    );
+
+    assert!(obs.reorder_columns(&["a", "b"]).is_err());
+    assert!(obs.reorder_columns(&["a", "b", "c", "d"]).is_err());
+    assert!(obs.reorder_columns(&["a", "a", "b", "c"]).is_err());
+    assert_eq!(obs.get_data().get_column_names(), vec!["b", "c", "a", "index"]);
+}
+
+#[test]
+fn test_mask_to_select_info_elem() {
+    let selection = mask_to_select_info_elem(&[true, false, true]);
+    assert!(matches!(&selection, SelectInfoElem::Index(indices) if indices == &vec![0, 2]));
+    assert_eq!(select_info_elem_to_indices(&selection, 3).unwrap(), vec![0, 2]);
+    assert!(matches!(mask_to_select_info_elem(&[]), SelectInfoElem::Index(indices) if indices.is_empty()));
+}
+
+#[test]
+fn test_project_axis_array() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let embedding = ndarray::array![[1.0, 0.0], [0.0, 2.0], [1.0, 1.0]];
+    adata
+        .obsm()
+        .add_array("X_pca".to_string(), IMArrayElement::new(ArrayData::from(embedding.into_dyn())))
+        .unwrap();
+
+    // Swap the two components and flip the sign of the second
+    let rotation = ndarray::array![[0.0, -1.0], [1.0, 0.0]];
+    adata
+        .obsm()
+        .project("X_pca", &rotation, "X_rotated".to_string())
+        .unwrap();
+    let rotated = adata.obsm().get_array("X_rotated").unwrap().to_dense_f64().unwrap();
+    assert_eq!(rotated, ndarray::array![[0.0, -1.0], [2.0, 0.0], [1.0, -1.0]]);
+
+    let wrong = ndarray::Array2::<f64>::zeros((3, 2));
+    assert!(adata
+        .obsm()
+        .project("X_pca", &wrong, "X_wrong".to_string())
+        .is_err());
+    assert!(adata.obsm().try_get("X_wrong").is_none());
+}
+
+#[test]
+fn test_frame_height_and_width() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let obs = adata.obs();
+    assert_eq!(obs.height(), obs.get_index().len());
+    assert_eq!(obs.height(), 3);
+    assert_eq!(obs.width(), obs.get_data().width());
+    obs.attach_column_to_df(Series::new("n_genes", &[1i64, 1, 2])).unwrap();
+    assert_eq!(obs.width(), 2);
+}
+
+#[test]
+fn test_deep_clone_components() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+
+    let partial = adata.deep_clone_components(ComponentSet::X | ComponentSet::OBS);
+    partial.x().scale_inplace(2.0).unwrap();
+    assert_eq!(adata.x().to_dense_f64().unwrap()[[0, 0]], 1.0);
+    assert_eq!(partial.x().to_dense_f64().unwrap()[[0, 0]], 2.0);
+
+    partial.get_layer_shallow("counts").unwrap().scale_inplace(10.0).unwrap();
+    assert_eq!(adata.get_layer("counts").unwrap().to_dense_f64().unwrap()[[0, 0]], 10.0);
+
+    assert!(ComponentSet::ALL.contains(ComponentSet::LAYERS | ComponentSet::UNS));
+    assert!(!(ComponentSet::X | ComponentSet::OBS).contains(ComponentSet::VAR));
+}
+
+#[test]
+fn test_pct_counts_in_top() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let result = adata.pct_counts_in_top(&[1, 3]).unwrap();
+
+    let top1 = &result[&1];
+    assert_eq!(top1[0], 100.0);
+    assert_eq!(top1[1], 100.0);
+    assert!((top1[2] - 100.0 * 4.0 / 7.0).abs() < 1e-12);
+    assert!(result[&3].iter().all(|&pct| (pct - 100.0).abs() < 1e-12));
+
+    let column = adata
+        .obs()
+        .get_column_from_df("pct_counts_in_top_1_genes")
+        .unwrap();
+    assert_eq!(column.f64().unwrap().get(2), Some(top1[2]));
+
+    assert!(adata.pct_counts_in_top(&[4]).is_err());
+    assert!(adata.pct_counts_in_top(&[0]).is_err());
+}
+
+#[test]
+fn test_take_and_put_x() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    let x = adata.x();
+
+    let data = x.take().unwrap();
+    assert_eq!(data, matrix);
+    assert!(adata.x().get_data().is_err());
+    assert!(x.take().is_err());
+
+    let handle = std::thread::spawn(move || data);
+    x.put(handle.join().unwrap()).unwrap();
+    assert_eq!(adata.x().get_data().unwrap(), matrix);
+    assert!(x.put(matrix).is_err());
+}
+
+#[test]
+fn test_correlate_genes() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    // The reference equals the column of var3
+    let correlations = adata.correlate_genes(&[0.0, 2.0, 4.0]).unwrap();
+    assert_eq!(correlations.len(), 3);
+    assert!((correlations[2] - 1.0).abs() < 1e-12);
+    assert!((correlations[0] + 1.0 / 3.0f64.sqrt()).abs() < 1e-12);
+
+    let constant = adata.correlate_genes(&[1.0, 1.0, 1.0]).unwrap();
+    assert!(constant.iter().all(|c| c.is_nan()));
+    assert!(adata.correlate_genes(&[1.0, 2.0]).is_err());
+}
+
+#[test]
+fn test_correlate_genes_constant_fractional_gene() {
+    // Ten times 0.1 does not sum to exactly 1.0, raw moments would leave a spurious variance
+    let n = 10;
+    let x = ndarray::Array2::from_shape_fn((n, 2), |(i, j)| if j == 0 { 0.1 } else { i as f64 });
+    let obs_names = (0..n).map(|i| format!("cell{}", i)).collect();
+    let var_names = vec!["constant".to_string(), "ramp".to_string()];
+    let adata = IMAnnData::new_basic(ArrayData::from(x.into_dyn()), obs_names, var_names).unwrap();
+
+    let reference: Vec<f64> = (0..n).map(|i| i as f64).collect();
+    let correlations = adata.correlate_genes(&reference).unwrap();
+    assert!(correlations[0].is_nan());
+    assert!((correlations[1] - 1.0).abs() < 1e-12);
+}
+
+#[test]
+fn test_to_edge_list() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let mut graph = CooMatrix::new(3, 3);
+    graph.push(0, 1, 0.5);
+    graph.push(1, 0, 0.5);
+    graph.push(2, 1, 0.25);
+    let graph = CsrMatrix::from(&graph);
+    let nnz = graph.nnz();
+    adata
+        .obsp()
+        .add_array(
+            "connectivities".to_string(),
+            IMArrayElement::new(ArrayData::CsrMatrix(DynCsrMatrix::from(graph))),
+        )
+        .unwrap();
+
+    let edges = adata
+        .obsp()
+        .to_edge_list("connectivities", &adata.obs_names())
+        .unwrap();
+    assert_eq!(edges.height(), nnz);
+    assert_eq!(edges.get_column_names(), vec!["source", "target", "weight"]);
+    let sources = edges.column("source").unwrap();
+    let targets = edges.column("target").unwrap();
+    assert_eq!(sources.str().unwrap().get(2), Some("obs3"));
+    assert_eq!(targets.str().unwrap().get(2), Some("obs2"));
+    assert_eq!(edges.column("weight").unwrap().f64().unwrap().get(2), Some(0.25));
+
+    assert!(adata
+        .obsp()
+        .to_edge_list("connectivities", &adata.obs_names()[..2])
+        .is_err());
+}
+
+#[test]
+fn test_content_equals_approx() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let other = adata.deep_clone();
+    assert!(adata.content_equals(&other));
+
+    other.x().apply_values_inplace(|v| v + 1e-12).unwrap();
+    assert!(!adata.content_equals(&other));
+    assert!(adata.content_equals_approx(&other, 1e-9, 1e-9));
+    assert!(!adata.content_equals_approx(&other, 0.0, 0.0));
+
+    // The same values stored densely still match
+    let dense = adata.deep_clone();
+    let values = dense.x().to_dense_f64().unwrap();
+    dense.x().set_data(ArrayData::from(values.into_dyn())).unwrap();
+    assert!(adata.content_equals_approx(&dense, 0.0, 0.0));
+
+    other
+        .obs()
+        .attach_column_to_df(Series::new("n_genes", &[1i64, 1, 2]))
+        .unwrap();
+    assert!(!adata.content_equals_approx(&other, 1e-9, 1e-9));
+}
+
+#[test]
+fn test_content_equals_approx_relative_to_other_sparse() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    // `other` stores a small value where X of `adata` has an implicit zero
+    let mut coo = CooMatrix::new(3, 3);
+    for (row, col, value) in [(0, 0, 1.0), (0, 1, 1e-10), (1, 2, 2.0), (2, 1, 3.0), (2, 2, 4.0)] {
+        coo.push(row, col, value);
+    }
+    let other = adata.deep_clone();
+    other
+        .x()
+        .set_data(ArrayData::CsrMatrix(DynCsrMatrix::from(CsrMatrix::from(&coo))))
+        .unwrap();
+
+    // |0 - 1e-10| <= rtol * |1e-10| holds for rtol = 1 without any absolute tolerance
+    assert!(adata.content_equals_approx(&other, 1.0, 0.0));
+    assert!(!adata.content_equals_approx(&other, 0.5, 0.0));
+    // The tolerance is relative to `other`, whose value is zero in the reverse direction
+    assert!(!other.content_equals_approx(&adata, 1.0, 0.0));
+}
+
+#[test]
+fn test_layer_infos() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    let dense = ndarray::Array2::<f32>::zeros((3, 3));
+    adata
+        .add_layer("raw".to_string(), IMArrayElement::new(ArrayData::from(dense.into_dyn())))
+        .unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+
+    let infos = adata.layer_infos();
+    assert_eq!(infos.len(), 2);
+    assert_eq!(infos[0].0, "counts");
+    assert_eq!(infos[0].1, vec![3, 3]);
+    assert_eq!(infos[0].2, anndata::backend::DataType::CsrMatrix(ScalarType::F64));
+    assert_eq!(infos[1].0, "raw");
+    assert_eq!(infos[1].1, vec![3, 3]);
+    assert_eq!(infos[1].2, anndata::backend::DataType::Array(ScalarType::F32));
+}
+
+#[test]
+fn test_subset_inplace_validates_all_components_first() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix, obs_names.clone(), var_names).unwrap();
+
+    // A pairwise collection sized for the wrong number of observations
+    let broken = IMAxisArrays::new_from(Axis::Pairwise, Dim::new(2), None, HashMap::new());
+    adata.obsp().0.swap(&broken.0);
+
+    let obs_sel = SelectInfoElem::Index(vec![0, 2]);
+    let err = adata
+        .subset_inplace(&[&obs_sel, &SelectInfoElem::full()])
+        .unwrap_err();
+    assert!(err.to_string().contains("obsp"));
+    assert_eq!(adata.n_obs(), 3);
+    assert_eq!(adata.obs_names(), obs_names);
+    assert_eq!(adata.x().get_shape().unwrap()[0], 3);
+}
+
+#[test]
+fn test_null_counts() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let obs = adata.obs();
+    obs.attach_columns(vec![
+        Series::new("cell_type", &[Some("T"), None, None]),
+        Series::new("n_genes", &[1i64, 1, 2]),
+    ])
+    .unwrap();
+
+    let counts = obs.null_counts();
+    assert_eq!(
+        counts,
+        vec![
+            ("index".to_string(), 0),
+            ("cell_type".to_string(), 2),
+            ("n_genes".to_string(), 0)
+        ]
+    );
+}
+
+#[test]
+fn test_columns_with_prefix_and_select_columns() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let obs = adata.obs();
+    obs.attach_columns(vec![
+        Series::new("pct_mito", &[1.0, 2.0, 3.0]),
+        Series::new("n_genes", &[1i64, 1, 2]),
+        Series::new("pct_ribo", &[4.0, 5.0, 6.0]),
+    ])
+    .unwrap();
+
+    let prefixed = obs.columns_with_prefix("pct_");
+    assert_eq!(prefixed, vec!["pct_mito", "pct_ribo"]);
+    let names: Vec<&str> = prefixed.iter().map(|s| s.as_str()).collect();
+    let selected = obs.select_columns(&names).unwrap();
+    assert_eq!(selected.get_column_names(), vec!["index", "pct_mito", "pct_ribo"]);
+    assert_eq!(selected.height(), 3);
+
+    assert!(obs.columns_with_prefix("qc_").is_empty());
+    assert!(obs.select_columns(&["pct_mito", "qc_total"]).is_err());
+}
+
+#[test]
+fn test_extract_genes_dense() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let genes = vec!["var3".to_string(), "var1".to_string()];
+    let dense = adata.extract_genes_dense(&genes).unwrap();
+    assert_eq!(dense, ndarray::array![[0.0, 1.0], [2.0, 0.0], [4.0, 0.0]]);
+
+    let full = adata.x().to_dense_f64().unwrap();
+    let x_dense = IMAnnData::new_basic(
+        ArrayData::from(full.into_dyn()),
+        adata.obs_names(),
+        adata.var_names(),
+    )
+    .unwrap();
+    assert_eq!(x_dense.extract_genes_dense(&genes).unwrap(), dense);
+
+    assert!(adata.extract_genes_dense(&["var9".to_string()]).is_err());
+}
+
+#[test]
+fn test_eliminate_zeros_and_shrink_to_fit() {
+    let mut coo = CooMatrix::new(2, 4);
+    for j in 0..4 {
+        coo.push(0, j, (j + 1) as f64);
+        coo.push(1, j, (j + 1) as f64);
+    }
+    let element = IMArrayElement::new(ArrayData::CsrMatrix(DynCsrMatrix::from(
+        CsrMatrix::from(&coo),
+    )));
+    // Zero out every other stored value, they stay stored until eliminated
+    element.apply_values_inplace(|v| if v % 2.0 == 0.0 { 0.0 } else { v }).unwrap();
+    assert_eq!(element.nnz().unwrap(), 8);
+
+    assert_eq!(element.eliminate_zeros(false).unwrap(), 4);
+    assert_eq!(element.nnz().unwrap(), 4);
+    assert_eq!(element.sparse_capacity().unwrap(), Some(8));
+    element.shrink_to_fit().unwrap();
+    assert_eq!(element.sparse_capacity().unwrap(), Some(4));
+    assert_eq!(
+        element.to_dense_f64().unwrap(),
+        ndarray::array![[1.0, 0.0, 3.0, 0.0], [1.0, 0.0, 3.0, 0.0]]
+    );
+
+    let dense = IMArrayElement::new(ArrayData::from(ndarray::Array2::<f64>::zeros((2, 2)).into_dyn()));
+    assert_eq!(dense.eliminate_zeros(true).unwrap(), 0);
+    assert_eq!(dense.sparse_capacity().unwrap(), None);
+}
+
+#[test]
+fn test_mean_expression_by_group() {
+    let x = ndarray::array![[1.0, 0.0], [3.0, 4.0], [0.0, 2.0], [5.0, 0.0], [0.0, 6.0]];
+    let obs_names = (0..5).map(|i| format!("cell{}", i)).collect();
+    let var_names = vec!["gene0".to_string(), "gene1".to_string()];
+    let adata = IMAnnData::new_basic(ArrayData::from(x.into_dyn()), obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("cluster", &["b", "a", "b", "a", "b"]))
+        .unwrap();
+
+    let (labels, means) = adata.mean_expression_by_group("cluster").unwrap();
+    assert_eq!(labels, vec![Some("b".to_string()), Some("a".to_string())]);
+    assert_eq!(means.dim(), (2, 2));
+    assert!((means[[0, 0]] - 1.0 / 3.0).abs() < 1e-12);
+    assert!((means[[0, 1]] - 8.0 / 3.0).abs() < 1e-12);
+    assert_eq!(means.row(1).to_vec(), vec![4.0, 2.0]);
+
+    assert!(adata.mean_expression_by_group("missing").is_err());
+
+    // A missing label forms its own group, distinct from a category named "null"
+    adata
+        .obs()
+        .attach_column_to_df(Series::new(
+            "label",
+            &[Some("null"), None, Some("null"), None, Some("null")],
+        ))
+        .unwrap();
+    let (labels, means) = adata.mean_expression_by_group("label").unwrap();
+    assert_eq!(labels, vec![Some("null".to_string()), None]);
+    assert_eq!(means.row(1).to_vec(), vec![4.0, 2.0]);
+}
+
+#[test]
+fn test_with_df_reads_without_clone() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let n_obs = adata.n_obs();
+    let counts: Vec<f64> = (0..n_obs).map(|i| i as f64).collect();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("n_counts", counts.clone()))
+        .unwrap();
+
+    let total = adata
+        .obs()
+        .with_df(|df| df.column("n_counts").unwrap().f64().unwrap().sum())
+        .unwrap();
+    assert_eq!(total, counts.iter().sum::<f64>());
+    assert_eq!(adata.obs().with_df(|df| df.height()), n_obs);
+}
+
+#[test]
+fn test_add_pairwise_checked() {
+    let x = ndarray::Array2::<f64>::zeros((3, 4));
+    let obs_names = (0..3).map(|i| format!("cell{}", i)).collect();
+    let var_names = (0..4).map(|i| format!("gene{}", i)).collect();
+    let adata = IMAnnData::new_basic(ArrayData::from(x.into_dyn()), obs_names, var_names).unwrap();
+
+    let non_square = IMArrayElement::new(ArrayData::from(ndarray::Array2::<f64>::ones((3, 4)).into_dyn()));
+    let err = adata
+        .obsp()
+        .add_pairwise_checked("bad".to_string(), non_square, None)
+        .unwrap_err();
+    assert!(err.to_string().contains("square"));
+
+    let asymmetric = ndarray::array![[0.0, 1.0, 0.0], [0.5, 0.0, 0.0], [0.0, 0.0, 0.0]];
+    let element = IMArrayElement::new(ArrayData::from(asymmetric.into_dyn()));
+    assert!(adata
+        .obsp()
+        .add_pairwise_checked("asym".to_string(), element.clone(), Some(1e-9))
+        .is_err());
+    adata
+        .obsp()
+        .add_pairwise_checked("asym".to_string(), element, None)
+        .unwrap();
+
+    let symmetric = ndarray::array![[0.0, 1.0, 0.0], [1.0, 0.0, 2.0], [0.0, 2.0, 0.0]];
+    adata
+        .obsp()
+        .add_pairwise_checked(
+            "connectivities".to_string(),
+            IMArrayElement::new(ArrayData::from(symmetric.into_dyn())),
+            Some(1e-9),
+        )
+        .unwrap();
+
+    let not_pairwise = IMArrayElement::new(ArrayData::from(ndarray::Array2::<f64>::zeros((3, 3)).into_dyn()));
+    assert!(adata
+        .obsm()
+        .add_pairwise_checked("pca".to_string(), not_pairwise, None)
+        .is_err());
+}
+
+#[test]
+fn test_permute_column() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let n_obs = adata.n_obs();
+    let labels: Vec<i32> = (0..n_obs as i32).collect();
+    let other: Vec<f64> = (0..n_obs).map(|i| i as f64 * 0.5).collect();
+    adata.obs().attach_column_to_df(Series::new("label", labels.clone())).unwrap();
+    adata.obs().attach_column_to_df(Series::new("other", other.clone())).unwrap();
+    let index_before = adata.obs_names();
+
+    adata.obs().permute_column("label", 7).unwrap();
+    let permuted: Vec<i32> = adata
+        .obs()
+        .get_column_from_df("label")
+        .unwrap()
+        .i32()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    let mut sorted = permuted.clone();
+    sorted.sort();
+    assert_eq!(sorted, labels);
+    assert_eq!(adata.obs_names(), index_before);
+    let other_after: Vec<f64> = adata
+        .obs()
+        .get_column_from_df("other")
+        .unwrap()
+        .f64()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(other_after, other);
+
+    // Same seed on the same input gives the same permutation
+    adata.obs().set_column_in_df("label", Series::new("label", labels)).unwrap();
+    adata.obs().permute_column("label", 7).unwrap();
+    let again: Vec<i32> = adata
+        .obs()
+        .get_column_from_df("label")
+        .unwrap()
+        .i32()
+        .unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(again, permuted);
+
+    assert!(adata.obs().permute_column("missing", 7).is_err());
+}
+
+#[test]
+fn test_into_parts_round_trip() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+    let embedding = ndarray::Array2::<f64>::ones((adata.n_obs(), 2));
+    adata
+        .obsm()
+        .add_array("X_pca".to_string(), IMArrayElement::new(ArrayData::from(embedding.into_dyn())))
+        .unwrap();
+    let expected = adata.deep_clone();
+
+    let rebuilt = IMAnnData::from_parts(adata.into_parts()).unwrap();
+    assert!(rebuilt.content_equals(&expected));
+    assert_eq!(rebuilt.history(), expected.history());
+    assert_eq!(rebuilt.history().last().unwrap().operation, "add_layer");
+
+    // Collections sized for an object with other dimensions are rejected
+    let small = rebuilt
+        .subset(&[&SelectInfoElem::Index(vec![0, 1]), &SelectInfoElem::full()])
+        .unwrap();
+    let (x, obs, var, _, obsp, varm, varp, uns, layers, history) = rebuilt.into_parts();
+    let (_, _, _, small_obsm, ..) = small.into_parts();
+    let parts = (x, obs, var, small_obsm, obsp, varm, varp, uns, layers, history);
+    assert!(IMAnnData::from_parts(parts).is_err());
+}
+
+#[test]
+fn test_collapse_duplicate_obs() {
+    let x = ndarray::array![[1.0, 0.0], [2.0, 4.0], [3.0, 2.0]];
+    let obs_names = vec!["AAAC".to_string(), "TTTG".to_string(), "AAAC".to_string()];
+    let var_names = vec!["gene0".to_string(), "gene1".to_string()];
+    let adata = IMAnnData::new_basic(ArrayData::from(x.into_dyn()), obs_names, var_names).unwrap();
+
+    let summed = adata.collapse_duplicate_obs(AggOp::Sum).unwrap();
+    assert_eq!(summed.obs_names(), vec!["AAAC".to_string(), "TTTG".to_string()]);
+    assert_eq!(summed.var_names(), adata.var_names());
+    assert_eq!(
+        summed.x().to_dense_f64().unwrap(),
+        ndarray::array![[4.0, 2.0], [2.0, 4.0]]
+    );
+
+    let averaged = adata.collapse_duplicate_obs(AggOp::Mean).unwrap();
+    assert_eq!(
+        averaged.x().to_dense_f64().unwrap(),
+        ndarray::array![[2.0, 1.0], [2.0, 4.0]]
+    );
+    averaged.validate().unwrap();
+    assert_eq!(adata.n_obs(), 3);
+}
+
+#[test]
+fn test_display_with_options() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    for i in 0..5 {
+        let embedding = ndarray::Array2::<f64>::zeros((adata.n_obs(), 2));
+        adata
+            .obsm()
+            .add_array(format!("X_emb{}", i), IMArrayElement::new(ArrayData::from(embedding.into_dyn())))
+            .unwrap();
+    }
+
+    let opts = DisplayOptions {
+        max_keys: 2,
+        show_dtypes: false,
+        float_precision: 1,
+        preview_rows: 1,
+    };
+    let rendered = adata.display_with(opts);
+    assert!(rendered.contains("Obsm keys: X_emb0, X_emb1, ... (3 more)"));
+    assert!(!rendered.contains("X_emb2"));
+    assert!(rendered.contains("  [1.0, 0.0, 0.0]"));
+
+    let full = adata.display_with(DisplayOptions::default());
+    assert!(full.contains("X_emb4"));
+    assert_eq!(full, adata.to_string());
+}
+
+#[test]
+fn test_values_equal_ignores_explicit_zeros() {
+    let mut with_zero = CooMatrix::new(2, 3);
+    with_zero.push(0, 0, 1.0);
+    with_zero.push(0, 1, 0.0);
+    with_zero.push(1, 2, 2.0);
+    let mut without_zero = CooMatrix::new(2, 3);
+    without_zero.push(0, 0, 1.0);
+    without_zero.push(1, 2, 2.0);
+    let a = IMArrayElement::new(ArrayData::CsrMatrix(DynCsrMatrix::from(CsrMatrix::from(&with_zero))));
+    let b = IMArrayElement::new(ArrayData::CsrMatrix(DynCsrMatrix::from(CsrMatrix::from(&without_zero))));
+
+    assert_eq!(a.nnz().unwrap(), 3);
+    assert_ne!(a.get_data().unwrap(), b.get_data().unwrap());
+    assert!(a.values_equal(&b).unwrap());
+    assert!(b.values_equal(&a).unwrap());
+
+    let dense = IMArrayElement::new(ArrayData::from(
+        ndarray::array![[1.0, 0.0, 0.0], [0.0, 0.0, 2.0]].into_dyn(),
+    ));
+    assert!(a.values_equal(&dense).unwrap());
+    let different = IMArrayElement::new(ArrayData::from(
+        ndarray::array![[1.0, 0.5, 0.0], [0.0, 0.0, 2.0]].into_dyn(),
+    ));
+    assert!(!a.values_equal(&different).unwrap());
+}
+
+#[test]
+fn test_csr_builder_matches_direct_construction() {
+    let (matrix, _, _) = create_test_data();
+    let mut builder = CsrBuilder::with_capacity(3, 3, 4);
+    builder.push_row(vec![0], vec![1.0]).unwrap();
+    builder.push_row(vec![2], vec![2.0]).unwrap();
+    // Unsorted entries are put in column order
+    builder.push_row(vec![2, 1], vec![4.0, 3.0]).unwrap();
+    assert_eq!((builder.n_rows(), builder.nnz()), (3, 4));
+    assert_eq!(builder.finish().unwrap(), matrix);
+
+    let mut builder = CsrBuilder::new(3);
+    assert!(builder.push_row(vec![3], vec![1.0]).is_err());
+    assert!(builder.push_row(vec![0, 1], vec![1.0]).is_err());
+    assert!(builder.push_row(vec![1, 1], vec![1.0, 2.0]).is_err());
+    assert_eq!(builder.n_rows(), 0);
+    builder.push_row(Vec::new(), Vec::new()).unwrap();
+    let shape = IMArrayElement::new(builder.finish().unwrap()).get_shape().unwrap();
+    assert_eq!((shape[0], shape[1]), (1, 3));
+}
+
+#[test]
+fn test_join_guards_row_order() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let metadata = polars::frame::DataFrame::new(vec![
+        Series::new("barcode", &["obs3", "obs1", "obs2"]),
+        Series::new("batch", &["b", "a", "a"]),
+    ])
+    .unwrap();
+    adata.obs().join_on_index(&metadata, "barcode", true).unwrap();
+    let batch = adata.obs().get_column_from_df("batch").unwrap();
+    let batch: Vec<&str> = batch.str().unwrap().into_no_null_iter().collect();
+    assert_eq!(batch, vec!["a", "a", "b"]);
+    assert!(adata.obs().get_column_from_df("__index_key").is_err());
+
+    // A join-like operation that reverses the rows
+    let reverse = |df: polars::frame::DataFrame| Ok(df.reverse());
+    let err = adata.obs().apply_join(true, reverse).unwrap_err();
+    assert!(err.to_string().contains("reordered"));
+    let batch = adata.obs().get_column_from_df("batch").unwrap();
+    assert_eq!(batch.str().unwrap().get(2), Some("b"));
+
+    // Outside strict mode the rows are put back in index order
+    adata.obs().apply_join(false, reverse).unwrap();
+    let batch = adata.obs().get_column_from_df("batch").unwrap();
+    let batch: Vec<&str> = batch.str().unwrap().into_no_null_iter().collect();
+    assert_eq!(batch, vec!["a", "a", "b"]);
+    assert_eq!(adata.obs_names(), vec!["obs1", "obs2", "obs3"]);
+}
+
+#[test]
+fn test_manifest() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+    let embedding = ndarray::Array2::<f64>::zeros((adata.n_obs(), 2));
+    adata
+        .obsm()
+        .add_array("X_pca".to_string(), IMArrayElement::new(ArrayData::from(embedding.into_dyn())))
+        .unwrap();
+    adata.obs().attach_column_to_df(Series::new("n_counts", &[1.0, 2.0, 7.0])).unwrap();
+
+    let manifest = adata.manifest();
+    for key in ["n_obs", "n_vars", "X", "layers", "obs", "var", "obsm", "obsp", "varm", "varp", "uns"] {
+        assert!(manifest.get(key).is_some(), "missing key {}", key);
+    }
+    assert_eq!(manifest["n_obs"], 3);
+    assert_eq!(manifest["X"]["shape"], serde_json::json!([3, 3]));
+    assert_eq!(manifest["layers"]["counts"]["shape"], serde_json::json!([3, 3]));
+    assert_eq!(manifest["obsm"]["X_pca"]["shape"], serde_json::json!([3, 2]));
+    let obs_columns: Vec<&str> = manifest["obs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|column| column["name"].as_str().unwrap())
+        .collect();
+    assert!(obs_columns.contains(&"n_counts"));
+    assert!(manifest.to_string().len() < 1000);
+}
+
+#[test]
+fn test_from_dense_and_from_csr() {
+    let obs_names: Vec<String> = (0..3).map(|i| format!("cell{}", i)).collect();
+    let var_names: Vec<String> = (0..2).map(|i| format!("gene{}", i)).collect();
+    let dense = ndarray::array![[1.0, 0.0], [0.0, 2.0], [3.0, 0.0]];
+    let adata = IMAnnData::from_dense(dense.clone(), obs_names.clone(), var_names.clone()).unwrap();
+    assert_eq!((adata.n_obs(), adata.n_vars()), (3, 2));
+    assert_eq!(adata.x().to_dense_f64().unwrap(), dense);
+
+    let mut coo = CooMatrix::new(3, 2);
+    coo.push(0, 0, 1.0f32);
+    coo.push(1, 1, 2.0f32);
+    coo.push(2, 0, 3.0f32);
+    let sparse = IMAnnData::from_csr(CsrMatrix::from(&coo), obs_names.clone(), var_names.clone()).unwrap();
+    assert_eq!((sparse.n_obs(), sparse.n_vars()), (3, 2));
+    assert!(sparse.x().values_equal(&adata.x()).unwrap());
+
+    assert!(IMAnnData::from_dense(dense, var_names, obs_names).is_err());
+}
+
+#[test]
+fn test_retain_obsm_keys() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    for key in ["X_pca", "X_umap"] {
+        let embedding = ndarray::Array2::<f64>::zeros((adata.n_obs(), 2));
+        adata
+            .obsm()
+            .add_array(key.to_string(), IMArrayElement::new(ArrayData::from(embedding.into_dyn())))
+            .unwrap();
+    }
+
+    let err = adata.retain_obsm(&["X_umap", "X_tsne"]).unwrap_err();
+    assert!(err.to_string().contains("X_tsne"));
+    assert_eq!(adata.obsm().keys().len(), 2);
+
+    adata.retain_obsm(&["X_umap"]).unwrap();
+    assert_eq!(adata.obsm().keys(), vec!["X_umap".to_string()]);
+
+    adata.retain_layers(&[]).unwrap();
+    assert!(adata.layers().keys().is_empty());
+}
+
+#[test]
+fn test_cached_sums_follow_mutations() {
+    let (matrix, _, _) = create_test_data();
+    let element = IMArrayElement::new(matrix);
+    assert_eq!(element.row_sums().unwrap(), vec![1.0, 2.0, 7.0]);
+    assert_eq!(element.col_sums().unwrap(), vec![1.0, 3.0, 6.0]);
+    let generation = element.0.generation();
+    assert_eq!(element.row_sums().unwrap(), vec![1.0, 2.0, 7.0]);
+    assert_eq!(element.0.generation(), generation);
+    // Reading the capacity takes the matrix apart but leaves it unchanged
+    assert!(element.sparse_capacity().unwrap().is_some());
+    assert_eq!(element.0.generation(), generation);
+
+    // Wrapping the slot again shares the data, not the cache
+    let wrapped = IMArrayElement::from_slot(element.0.clone());
+    assert_eq!(wrapped.col_sums().unwrap(), vec![1.0, 3.0, 6.0]);
+
+    element.scale_inplace(2.0).unwrap();
+    assert_eq!(element.row_sums().unwrap(), vec![2.0, 4.0, 14.0]);
+    assert_eq!(element.col_sums().unwrap(), vec![2.0, 6.0, 12.0]);
+
+    // Writes through a shallow clone invalidate the cache of the original handle
+    let shared = element.clone();
+    shared
+        .set_data(ArrayData::from(ndarray::Array2::<f64>::ones((2, 3)).into_dyn()))
+        .unwrap();
+    assert_eq!(element.row_sums().unwrap(), vec![3.0, 3.0]);
+    assert_eq!(shared.col_sums().unwrap(), vec![2.0, 2.0, 2.0]);
+}
+
+#[test]
+fn test_select_layer_per_obs() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let corrected = ndarray::array![[9.0, 0.0, 0.0], [0.0, 8.0, 0.0], [0.0, 0.0, 7.0]];
+    adata
+        .add_layer(
+            "corrected".to_string(),
+            IMArrayElement::new(ArrayData::from(corrected.clone().into_dyn())),
+        )
+        .unwrap();
+
+    adata
+        .select_layer_per_obs("corrected", &[true, true, true], Some("all_layer".to_string()))
+        .unwrap();
+    assert_eq!(adata.get_layer("all_layer").unwrap().to_dense_f64().unwrap(), corrected);
+    adata
+        .select_layer_per_obs("corrected", &[false, false, false], Some("all_x".to_string()))
+        .unwrap();
+    assert!(adata.get_layer("all_x").unwrap().values_equal(&adata.x()).unwrap());
+
+    adata.select_layer_per_obs("corrected", &[false, true, false], None).unwrap();
+    assert_eq!(
+        adata.x().to_dense_f64().unwrap(),
+        ndarray::array![[1.0, 0.0, 0.0], [0.0, 8.0, 0.0], [0.0, 3.0, 4.0]]
+    );
+
+    assert!(adata.select_layer_per_obs("corrected", &[true], None).is_err());
+    assert!(adata.select_layer_per_obs("missing", &[true, true, true], None).is_err());
+}
+
+#[test]
+fn test_combine_bool_columns() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let obs = adata.obs();
+    obs.attach_column_to_df(Series::new("low_mito", &[true, true, false])).unwrap();
+    obs.attach_column_to_df(Series::new("high_genes", &[true, false, true])).unwrap();
+    obs.attach_column_to_df(Series::new("doublet", &[false, false, true])).unwrap();
+    obs.attach_column_to_df(Series::new("n_genes", &[10i32, 20, 30])).unwrap();
+
+    obs.combine_bool_columns(&["low_mito", "high_genes"], BoolOp::And, "pass_qc").unwrap();
+    let bools = |name: &str| -> Vec<bool> {
+        obs.get_column_from_df(name).unwrap().bool().unwrap().into_no_null_iter().collect()
+    };
+    assert_eq!(bools("pass_qc"), vec![true, false, false]);
+
+    obs.combine_bool_columns(&["low_mito", "!doublet"], BoolOp::Or, "keep").unwrap();
+    assert_eq!(bools("keep"), vec![true, true, false]);
+
+    assert!(obs.combine_bool_columns(&["low_mito", "n_genes"], BoolOp::And, "bad").is_err());
+    assert!(obs.combine_bool_columns(&[], BoolOp::And, "bad").is_err());
+    assert!(obs.get_column_from_df("bad").is_err());
+}
+
+#[test]
+fn test_close_releases_components() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+    let x = adata.x();
+    let layer = adata.get_layer_shallow("counts").unwrap();
+    let copy = adata.deep_clone();
+
+    adata.close();
+    assert!(adata.x().get_data().is_err());
+    assert!(x.get_data().is_err());
+    assert!(layer.get_data().is_err());
+    assert!(adata.obs().get_column_from_df("index").is_err());
+
+    // Deep clones keep their own data
+    assert!(copy.x().get_data().is_ok());
+    assert_eq!(copy.layer_infos().len(), 1);
+}
+
+#[test]
+fn test_axis_quantiles_count_implicit_zeros() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+
+    assert_eq!(adata.var_quantile(0.5).unwrap(), vec![0.0, 0.0, 2.0]);
+    assert_eq!(adata.var_quantile(1.0).unwrap(), vec![1.0, 3.0, 4.0]);
+    assert_eq!(adata.var_quantile(0.75).unwrap()[2], 3.0);
+    assert_eq!(adata.obs_quantile(0.5).unwrap(), vec![0.0, 0.0, 3.0]);
+    assert_eq!(adata.obs_quantile(0.0).unwrap(), vec![0.0, 0.0, 0.0]);
+
+    let x = ndarray::array![[-2.0, 1.0], [0.0, 0.0], [4.0, 0.0]];
+    let obs_names = (0..3).map(|i| format!("cell{}", i)).collect();
+    let var_names = vec!["gene0".to_string(), "gene1".to_string()];
+    let dense = IMAnnData::new_basic(ArrayData::from(x.into_dyn()), obs_names, var_names).unwrap();
+    assert_eq!(dense.var_quantile(0.5).unwrap(), vec![0.0, 0.0]);
+    assert_eq!(dense.var_quantile(0.25).unwrap(), vec![-1.0, 0.0]);
+
+    assert!(adata.var_quantile(1.5).is_err());
+    assert!(adata.obs_quantile(-0.1).is_err());
+}
+
+#[test]
+fn test_subset_with_layer_policy() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix.clone()))
+        .unwrap();
+    adata
+        .add_layer("velocity".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+    let selection = [
+        &SelectInfoElem::Index(vec![0, 2]),
+        &SelectInfoElem::Index(vec![1]),
+    ];
+
+    let default = adata
+        .subset_with_layer_policy(&selection, &LayerSubsetPolicy::new())
+        .unwrap();
+    assert!(default.content_equals(&adata.subset(&selection).unwrap()));
+
+    // A layer keeping all columns cannot stay a layer once variables are dropped
+    let policy = LayerSubsetPolicy::new().obs_only("velocity");
+    assert!(adata.subset_with_layer_policy(&selection, &policy).is_err());
+
+    let moving = policy.clone().move_obs_only_to_obsm();
+    let subset = adata.subset_with_layer_policy(&selection, &moving).unwrap();
+    let counts = subset.get_layer("counts").unwrap().get_shape().unwrap();
+    assert_eq!((counts[0], counts[1]), (2, 1));
+    assert!(subset.get_layer("velocity").is_err());
+    let velocity = subset.obsm().get_array_shallow("velocity").unwrap().get_shape().unwrap();
+    assert_eq!((velocity[0], velocity[1]), (2, 3));
+    subset.validate().unwrap();
+
+    // With all variables selected the layer stays a layer
+    let rows_only = [&SelectInfoElem::Index(vec![0, 2]), &SelectInfoElem::full()];
+    let subset = adata.subset_with_layer_policy(&rows_only, &policy).unwrap();
+    let velocity = subset.get_layer("velocity").unwrap().get_shape().unwrap();
+    assert_eq!((velocity[0], velocity[1]), (2, 3));
+    assert!(subset.obsm().try_get("velocity").is_none());
+
+    let missing = LayerSubsetPolicy::new().obs_only("spliced");
+    assert!(adata.subset_with_layer_policy(&selection, &missing).is_err());
+}
+
+#[test]
+fn test_new_from_frames() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let obs_df = polars::frame::DataFrame::new(vec![
+        Series::new("index", &obs_names),
+        Series::new("n_counts", &[1.0, 2.0, 7.0]),
+    ])
+    .unwrap();
+    let var_df = polars::frame::DataFrame::new(vec![Series::new("index", &var_names)]).unwrap();
+
+    let adata = IMAnnData::new_from_frames(matrix.clone(), obs_df.clone(), var_df.clone()).unwrap();
+    assert_eq!((adata.n_obs(), adata.n_vars()), (3, 3));
+    assert_eq!(adata.obs_names(), obs_names);
+    assert_eq!(adata.var_names(), var_names);
+    assert!(adata.obs().get_column_from_df("n_counts").is_ok());
+
+    let no_index = obs_df.drop("index").unwrap();
+    assert!(IMAnnData::new_from_frames(matrix.clone(), no_index, var_df.clone()).is_err());
+    let short = obs_df.head(Some(2));
+    assert!(IMAnnData::new_from_frames(matrix, short, var_df).is_err());
+}
+
+#[test]
+fn test_compute_distances() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let embedding = ndarray::array![[0.0, 0.0], [3.0, 4.0], [0.0, 1.0]];
+    adata
+        .obsm()
+        .add_array("X_emb".to_string(), IMArrayElement::new(ArrayData::from(embedding.into_dyn())))
+        .unwrap();
+
+    adata
+        .compute_distances("X_emb", Metric::Euclidean, "distances".to_string(), None)
+        .unwrap();
+    let distances = adata.obsp().get_array_shallow("distances").unwrap().to_dense_f64().unwrap();
+    let expected_12 = 18.0f64.sqrt();
+    let expected = ndarray::array![
+        [0.0, 5.0, 1.0],
+        [5.0, 0.0, expected_12],
+        [1.0, expected_12, 0.0]
+    ];
+    assert!(distances.iter().zip(expected.iter()).all(|(a, b)| (a - b).abs() < 1e-12));
+
+    adata
+        .compute_distances("X_emb", Metric::Euclidean, "close".to_string(), Some(4.5))
+        .unwrap();
+    let close = adata.obsp().get_array_shallow("close").unwrap();
+    assert_eq!(close.nnz().unwrap(), 4);
+
+    adata
+        .compute_distances("X_emb", Metric::Cosine, "cosine".to_string(), None)
+        .unwrap();
+    let cosine = adata.obsp().get_array_shallow("cosine").unwrap().to_dense_f64().unwrap();
+    assert!((cosine[[1, 2]] - 0.2).abs() < 1e-12);
+    assert_eq!(cosine[[0, 1]], 1.0);
+
+    assert!(adata
+        .compute_distances("missing", Metric::Euclidean, "other".to_string(), None)
+        .is_err());
+    assert!(adata
+        .compute_distances("X_emb", Metric::Euclidean, "distances".to_string(), None)
+        .is_err());
+}
+
+#[test]
+fn test_center_by_group() {
+    let x = ndarray::array![[1.0, 0.0], [3.0, 4.0], [0.0, 2.0], [5.0, 0.0], [0.0, 6.0]];
+    let obs_names = (0..5).map(|i| format!("cell{}", i)).collect();
+    let var_names = vec!["gene0".to_string(), "gene1".to_string()];
+    let adata = IMAnnData::new_basic(ArrayData::from(x.into_dyn()), obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("batch", &["b", "a", "b", "a", "b"]))
+        .unwrap();
+
+    adata.center_by_group("batch").unwrap();
+    let (_, means) = adata.mean_expression_by_group("batch").unwrap();
+    assert!(means.iter().all(|m| m.abs() < 1e-12));
+    let centered = adata.x().to_dense_f64().unwrap();
+    assert_eq!(centered.row(1).to_vec(), vec![-1.0, 2.0]);
+
+    assert!(adata.center_by_group("missing").is_err());
+}
+
+#[test]
+fn test_layer_entry() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let mut adata = IMAnnData::new_basic(matrix.clone(), obs_names, var_names).unwrap();
+    adata
+        .add_layer("counts".to_string(), IMArrayElement::new(matrix))
+        .unwrap();
+
+    let entry = adata.layer_entry("counts").unwrap();
+    let layer = adata.get_layer_shallow("counts").unwrap();
+    let shape = layer.get_shape().unwrap();
+    assert_eq!(entry.shape, vec![shape[0], shape[1]]);
+    assert_eq!(entry.dtype, layer.get_type().unwrap());
+    assert_eq!(entry.nnz, Some(4));
+    assert!(entry.element.0.ptr_eq(&layer.0));
+
+    assert!(adata.layer_entry("missing").is_err());
+}
+
+#[test]
+fn test_validate_csr_detects_broken_indptr() {
+    let (matrix, _, _) = create_test_data();
+    IMArrayElement::new(matrix).validate_csr().unwrap();
+
+    // Row offsets decrease between rows 1 and 2
+    let pattern = unsafe {
+        nalgebra_sparse::pattern::SparsityPattern::from_offset_and_indices_unchecked(
+            3,
+            3,
+            vec![0, 2, 1, 3],
+            vec![0, 2, 1],
+        )
+    };
+    let broken = CsrMatrix::try_from_pattern_and_values(pattern, vec![1.0, 2.0, 3.0]).unwrap();
+    let data = ArrayData::CsrMatrix(DynCsrMatrix::from(broken));
+    let err = IMArrayElement::new(data.clone()).validate_csr().unwrap_err();
+    assert!(err.to_string().contains("decrease"));
+    assert!(IMArrayElement::new_validated(data.clone()).is_err());
+    // Only the dimensionality is checked without validation
+    assert!(IMArrayElement::new_checked(data).is_ok());
+
+    let pattern = unsafe {
+        nalgebra_sparse::pattern::SparsityPattern::from_offset_and_indices_unchecked(
+            2,
+            2,
+            vec![0, 1, 2],
+            vec![0, 5],
+        )
+    };
+    let out_of_bounds = CsrMatrix::try_from_pattern_and_values(pattern, vec![1.0, 2.0]).unwrap();
+    let err = IMArrayElement::new(ArrayData::CsrMatrix(DynCsrMatrix::from(out_of_bounds)))
+        .validate_csr()
+        .unwrap_err();
+    assert!(err.to_string().contains("out of bounds"));
+
+    let dense = IMArrayElement::new(ArrayData::from(ndarray::Array2::<f64>::zeros((2, 2)).into_dyn()));
+    assert!(dense.validate_csr().is_err());
+}
+
+#[test]
+fn test_history_records_operations() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    assert!(adata.history().is_empty());
+
+    adata.log1p_x().unwrap();
+    adata.scale_x(2.0).unwrap();
+    let history = adata.history();
+    let operations: Vec<&str> = history.iter().map(|r| r.operation.as_str()).collect();
+    assert_eq!(operations, vec!["log1p_x", "scale_x"]);
+    assert_eq!(history[1].details, "factor=2");
+
+    let copy = adata.deep_clone();
+    assert_eq!(copy.history(), history);
+    copy.sqrt_x().unwrap();
+    assert_eq!(copy.history().len(), 3);
+    assert_eq!(adata.history().len(), 2);
+
+    let subset = adata
+        .subset(&[&SelectInfoElem::Index(vec![0]), &SelectInfoElem::full()])
+        .unwrap();
+    assert_eq!(subset.history().last().unwrap().operation, "subset");
+    assert_eq!(adata.manifest()["history"].as_array().unwrap().len(), 2);
+
+    let mut adata = adata;
+    let counts = adata.x().deep_clone();
+    adata.add_layer("counts".to_string(), counts).unwrap();
+    adata.retain_layers(&["counts"]).unwrap();
+    adata.remove_unused_categories().unwrap();
+    adata.map_obs_chunks(2, |_| Ok(())).unwrap();
+    let operations: Vec<String> = adata.history().into_iter().map(|r| r.operation).collect();
+    assert_eq!(
+        &operations[2..],
+        ["add_layer", "retain_layers", "remove_unused_categories", "map_obs_chunks"]
+    );
+    assert_eq!(adata.history()[3].details, "counts");
+
+    // Failed operations are not recorded
+    assert!(adata.retain_layers(&["missing"]).is_err());
+    assert_eq!(adata.history().len(), 6);
+
+    let collapsed = adata.collapse_duplicate_obs(AggOp::Sum).unwrap();
+    assert_eq!(collapsed.history().len(), 8);
+    assert_eq!(collapsed.history()[7].operation, "collapse_duplicate_obs");
+    let joined = IMAnnData::concat_obs(&[&adata, &collapsed]).unwrap();
+    let operations: Vec<String> = joined.history().into_iter().map(|r| r.operation).collect();
+    assert_eq!(operations, vec!["concat_obs"]);
+}
+
+#[test]
+fn test_var_weighted_statistics() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let n_obs = adata.n_obs() as f64;
+    let unweighted: Vec<f64> = adata
+        .x()
+        .col_sums()
+        .unwrap()
+        .iter()
+        .map(|s| s / n_obs)
+        .collect();
+
+    let means = adata.var_means_weighted(&[2.0, 2.0, 2.0], NanPolicy::Propagate).unwrap();
+    for (m, u) in means.iter().zip(&unweighted) {
+        assert!((m - u).abs() < 1e-12);
+    }
+
+    // var3 holds 0, 2 and 4 with a population variance of 8 / 3
+    let variances = adata.var_variances_weighted(&[1.0, 1.0, 1.0], NanPolicy::Propagate).unwrap();
+    assert!((variances[2] - 8.0 / 3.0).abs() < 1e-12);
+
+    // Zero weight drops obs1 entirely
+    let means = adata.var_means_weighted(&[0.0, 1.0, 3.0], NanPolicy::Propagate).unwrap();
+    assert_eq!(means, vec![0.0, 2.25, 3.5]);
+    let variances = adata.var_variances_weighted(&[0.0, 1.0, 3.0], NanPolicy::Propagate).unwrap();
+    assert!(variances[0].abs() < 1e-12);
+    assert!((variances[2] - 0.75).abs() < 1e-12);
+
+    assert!(adata.var_means_weighted(&[1.0, 1.0], NanPolicy::Propagate).is_err());
+    assert!(adata.var_means_weighted(&[1.0, -1.0, 1.0], NanPolicy::Propagate).is_err());
+    assert!(adata.var_variances_weighted(&[0.0, 0.0, 0.0], NanPolicy::Propagate).is_err());
+}
+
+#[test]
+fn test_add_array_aligned_reorders_rows() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    let self_names = adata.obs_names();
+
+    // Rows listed as obs3, obs1, obs2; row values encode the obs number
+    let shuffled = ndarray::arr2(&[[3.0, 30.0], [1.0, 10.0], [2.0, 20.0]]);
+    let shuffled_names = vec!["obs3".to_string(), "obs1".to_string(), "obs2".to_string()];
+    let obsm = adata.obsm();
+    obsm.add_array_aligned(
+        "X_other".to_string(),
+        IMArrayElement::new(ArrayData::from(shuffled.into_dyn())),
+        &shuffled_names,
+        &self_names,
+    )
+    .unwrap();
+    let expected = ndarray::arr2(&[[1.0, 10.0], [2.0, 20.0], [3.0, 30.0]]);
+    let expected = IMArrayElement::new(ArrayData::from(expected.into_dyn()));
+    assert!(obsm.get_array("X_other").unwrap().values_equal(&expected).unwrap());
+
+    let embedding = || {
+        IMArrayElement::new(ArrayData::from(
+            ndarray::Array2::<f64>::zeros((3, 2)).into_dyn(),
+        ))
+    };
+    let unknown = vec!["obs1".to_string(), "obs2".to_string(), "obs4".to_string()];
+    assert!(obsm
+        .add_array_aligned("X_bad".to_string(), embedding(), &unknown, &self_names)
+        .is_err());
+    let duplicated = vec!["obs1".to_string(), "obs1".to_string(), "obs2".to_string()];
+    assert!(obsm
+        .add_array_aligned("X_bad".to_string(), embedding(), &duplicated, &self_names)
+        .is_err());
+    assert!(obsm
+        .add_array_aligned("X_bad".to_string(), embedding(), &self_names[..2], &self_names)
+        .is_err());
+    // Duplicated names on the object's side would repeat obs1 and drop obs3
+    let self_duplicated = vec!["obs1".to_string(), "obs2".to_string(), "obs1".to_string()];
+    assert!(obsm
+        .add_array_aligned("X_bad".to_string(), embedding(), &self_names, &self_duplicated)
+        .is_err());
+    assert!(obsm.get_array("X_bad").is_err());
+}
+
+#[test]
+fn test_split_by_group() {
+    let (matrix, obs_names, var_names) = create_test_data();
+    let adata = IMAnnData::new_basic(matrix, obs_names, var_names).unwrap();
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("batch", &["a", "b", "a"]))
+        .unwrap();
+    adata
+        .var()
+        .attach_column_to_df(Series::new("gene_type", &["x", "y", "z"]))
+        .unwrap();
+
+    let parts = adata.split_by("batch").unwrap();
+    assert_eq!(parts.len(), 2);
+    let a = &parts[&Some("a".to_string())];
+    let b = &parts[&Some("b".to_string())];
+    assert_eq!(a.n_obs(), 2);
+    assert_eq!(b.n_obs(), 1);
+    assert_eq!(a.obs_names(), vec!["obs1".to_string(), "obs3".to_string()]);
+    assert_eq!(b.obs_names(), vec!["obs2".to_string()]);
+    for part in parts.values() {
+        assert_eq!(part.n_vars(), 3);
+        assert!(part.var().get_column_from_df("gene_type").is_ok());
+    }
+    assert_eq!(a.x().col_sums().unwrap(), vec![1.0, 3.0, 4.0]);
+
+    assert!(adata.split_by("missing").is_err());
+
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("label", &[Some("null"), None, None]))
+        .unwrap();
+    let parts = adata.split_by("label").unwrap();
+    assert_eq!(parts[&Some("null".to_string())].n_obs(), 1);
+    assert_eq!(parts[&None].n_obs(), 2);
+}
+
+#[test]
+fn test_var_means_nan_policy() {
+    let dense = ndarray::arr2(&[[1.0, f64::NAN], [2.0, 4.0], [3.0, 8.0]]);
+    let adata = IMAnnData::new_basic(
+        ArrayData::from(dense.into_dyn()),
+        vec!["obs1".to_string(), "obs2".to_string(), "obs3".to_string()],
+        vec!["var1".to_string(), "var2".to_string()],
+    )
+    .unwrap();
+
+    let propagated = adata.var_means(NanPolicy::default()).unwrap();
+    assert_eq!(propagated[0], 2.0);
+    assert!(propagated[1].is_nan());
+    assert!(adata.var_variances(NanPolicy::Propagate).unwrap()[1].is_nan());
+
+    let skipped = adata.var_means(NanPolicy::Skip).unwrap();
+    assert_eq!(skipped, vec![2.0, 6.0]);
+    let variances = adata.var_variances(NanPolicy::Skip).unwrap();
+    assert!((variances[0] - 2.0 / 3.0).abs() < 1e-12);
+    assert!((variances[1] - 4.0).abs() < 1e-12);
+
+    // Weighted: the NaN of var2 removes the weight of obs1 from its denominator
+    let weights = [1.0, 1.0, 2.0];
+    let propagated = adata
+        .var_means_weighted(&weights, NanPolicy::Propagate)
+        .unwrap();
+    assert!(propagated[1].is_nan());
+    let skipped = adata.var_means_weighted(&weights, NanPolicy::Skip).unwrap();
+    assert!((skipped[0] - 9.0 / 4.0).abs() < 1e-12);
+    assert!((skipped[1] - 20.0 / 3.0).abs() < 1e-12);
+    let variances = adata
+        .var_variances_weighted(&weights, NanPolicy::Skip)
+        .unwrap();
+    assert!((variances[1] - 32.0 / 9.0).abs() < 1e-12);
+}
+
+#[test]
+fn test_duplicate_names_report() {
+    let x = ndarray::array![[1.0, 0.0], [2.0, 4.0], [3.0, 2.0]];
+    let obs_names = vec!["AAAC".to_string(), "TTTG".to_string(), "AAAC".to_string()];
+    let var_names = vec!["gene0".to_string(), "gene1".to_string()];
+    let adata = IMAnnData::new_basic(ArrayData::from(x.into_dyn()), obs_names, var_names).unwrap();
+
+    assert_eq!(
+        adata.duplicate_obs_names(),
+        vec![("AAAC".to_string(), vec![0, 2])]
+    );
+    assert!(adata.duplicate_var_names().is_empty());
+}
@@ -29,3 +29,77 @@ fn test_iman_length_converted() -> anyhow::Result<()> {
     println!("{}", imanndata);
     Ok(())
 }
+
+#[test]
+fn test_to_anndata_mem_keeps_shapes() -> anyhow::Result<()> {
+    use anndata::{data::DynCsrMatrix, AnnDataOp, ArrayData, ArrayElemOp};
+    use nalgebra_sparse::{CooMatrix, CsrMatrix};
+
+    let mut coo_matrix = CooMatrix::new(3, 2);
+    coo_matrix.push(0, 0, 1.0);
+    coo_matrix.push(2, 1, 2.0);
+    let csr_matrix: CsrMatrix<f64> = CsrMatrix::from(&coo_matrix);
+    let imanndata = IMAnnData::new_basic(
+        ArrayData::CsrMatrix(DynCsrMatrix::from(csr_matrix)),
+        vec!["obs1".to_string(), "obs2".to_string(), "obs3".to_string()],
+        vec!["var1".to_string(), "var2".to_string()],
+    )?;
+
+    let anndata = imanndata.to_anndata_mem()?;
+    assert_eq!(anndata.n_obs(), 3);
+    assert_eq!(anndata.n_vars(), 2);
+    let shape = anndata.x().shape().unwrap();
+    assert_eq!(vec![shape[0], shape[1]], vec![3, 2]);
+    assert_eq!(anndata.obs_names().into_vec(), imanndata.obs_names());
+
+    // The temporary file is removed together with the object
+    let (anndata, dir) = anndata.into_parts();
+    let path = dir.path().to_path_buf();
+    assert!(path.exists());
+    anndata.close()?;
+    drop(dir);
+    assert!(!path.exists());
+    Ok(())
+}
+
+#[test]
+fn test_convert_subset_during_load() -> anyhow::Result<()> {
+    use anndata::{data::SelectInfoElem, ArrayData};
+
+    let x = ndarray::Array2::from_shape_fn((4, 3), |(i, j)| (i * 3 + j) as f64);
+    let obs_names: Vec<String> = (0..4).map(|i| format!("cell{}", i)).collect();
+    let var_names: Vec<String> = (0..3).map(|i| format!("gene{}", i)).collect();
+    let imanndata = IMAnnData::new_basic(ArrayData::from(x.clone().into_dyn()), obs_names, var_names)?;
+    let embedding = ndarray::Array2::from_shape_fn((4, 2), |(i, j)| (i * 2 + j) as f64);
+    imanndata.obsm().add_array(
+        "X_pca".to_string(),
+        IMArrayElement::new(ArrayData::from(embedding.into_dyn())),
+    )?;
+
+    // The directories are removed when the guards go out of scope at the end of the test
+    let (anndata, _dir) = imanndata.to_anndata_mem()?.into_parts();
+    let obs_sel = SelectInfoElem::Index(vec![1, 3]);
+    let subset = convert_to_in_memory_subset(
+        anndata,
+        &obs_sel,
+        &SelectInfoElem::full(),
+    )?;
+    assert_eq!(subset.n_obs(), 2);
+    assert_eq!(subset.n_vars(), 3);
+    assert_eq!(subset.obs_names(), vec!["cell1", "cell3"]);
+    let sub_x = subset.x().to_dense_f64()?;
+    assert_eq!(sub_x.row(0).to_vec(), x.row(1).to_vec());
+    assert_eq!(sub_x.row(1).to_vec(), x.row(3).to_vec());
+    let pca = subset.obsm().get_array("X_pca")?.to_dense_f64()?;
+    assert_eq!(pca.row(1).to_vec(), vec![6.0, 7.0]);
+
+    let (anndata, _dir_out_of_bounds) = imanndata.to_anndata_mem()?.into_parts();
+    let out_of_bounds = SelectInfoElem::Index(vec![4]);
+    assert!(convert_to_in_memory_subset(
+        anndata,
+        &out_of_bounds,
+        &SelectInfoElem::full(),
+    )
+    .is_err());
+    Ok(())
+}
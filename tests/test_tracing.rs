@@ -0,0 +1,72 @@
+#![cfg(feature = "tracing")]
+
+use std::sync::{Arc, Mutex};
+
+use anndata::{data::DynCsrMatrix, data::SelectInfoElem, ArrayData};
+use anndata_memory::IMAnnData;
+use nalgebra_sparse::{CooMatrix, CsrMatrix};
+use tracing::{
+    span::{Attributes, Id, Record},
+    Event, Metadata, Subscriber,
+};
+
+/// Subscriber that records the names of entered spans.
+struct RecordingSubscriber {
+    spans: Mutex<Vec<&'static str>>,
+    entered: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl Subscriber for RecordingSubscriber {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(span.metadata().name());
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        let name = self.spans.lock().unwrap()[span.into_u64() as usize - 1];
+        self.entered.lock().unwrap().push(name);
+    }
+
+    fn exit(&self, _span: &Id) {}
+}
+
+#[test]
+fn test_subset_inplace_enters_span() {
+    let mut coo_matrix = CooMatrix::new(3, 2);
+    coo_matrix.push(0, 0, 1.0);
+    coo_matrix.push(2, 1, 2.0);
+    let csr_matrix: CsrMatrix<f64> = CsrMatrix::from(&coo_matrix);
+    let mut adata = IMAnnData::new_basic(
+        ArrayData::CsrMatrix(DynCsrMatrix::from(csr_matrix)),
+        vec!["obs1".to_string(), "obs2".to_string(), "obs3".to_string()],
+        vec!["var1".to_string(), "var2".to_string()],
+    )
+    .unwrap();
+
+    let entered = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = RecordingSubscriber {
+        spans: Mutex::new(Vec::new()),
+        entered: entered.clone(),
+    };
+    tracing::subscriber::with_default(subscriber, || {
+        let obs_sel = SelectInfoElem::Index(vec![0, 2]);
+        let var_sel = SelectInfoElem::full();
+        adata.subset_inplace(&[&obs_sel, &var_sel]).unwrap();
+    });
+
+    let entered = entered.lock().unwrap();
+    assert!(entered.contains(&"subset"));
+    assert!(entered.contains(&"subset_step"));
+    assert_eq!(adata.n_obs(), 2);
+}
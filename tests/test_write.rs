@@ -0,0 +1,114 @@
+use anndata::{data::DynCsrMatrix, AnnData, ArrayData, Data};
+use anndata_hdf5::H5;
+use hdf5::types::VarLenUnicode;
+use anndata_memory::{
+    convert_to_in_memory, write_h5ad_with_options, Compression, Element, IMAnnData,
+    IMArrayElement, WriteOptions,
+};
+use nalgebra_sparse::{CooMatrix, CsrMatrix};
+use polars::{
+    prelude::{CategoricalOrdering, DataType, NamedFrom},
+    series::Series,
+};
+use tempfile::tempdir;
+
+fn create_repetitive_data(nrows: usize, ncols: usize) -> IMAnnData {
+    let mut coo_matrix = CooMatrix::new(nrows, ncols);
+    for i in 0..nrows {
+        for j in (0..ncols).step_by(2) {
+            coo_matrix.push(i, j, ((i + j) % 5) as f64);
+        }
+    }
+    let csr_matrix: CsrMatrix<f64> = CsrMatrix::from(&coo_matrix);
+    let obs_names = (0..nrows).map(|i| format!("obs{}", i)).collect();
+    let var_names = (0..ncols).map(|i| format!("var{}", i)).collect();
+    IMAnnData::new_basic(
+        ArrayData::CsrMatrix(DynCsrMatrix::from(csr_matrix)),
+        obs_names,
+        var_names,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_write_with_and_without_compression() -> anyhow::Result<()> {
+    let mut adata = create_repetitive_data(500, 200);
+    let counts = IMArrayElement::new(adata.x().get_data()?);
+    adata.add_layer("counts".to_string(), counts)?;
+    let dir = tempdir()?;
+    let plain_path = dir.path().join("plain.h5ad");
+    let gzip_path = dir.path().join("gzip.h5ad");
+
+    write_h5ad_with_options(
+        &adata,
+        &plain_path,
+        WriteOptions {
+            compression: None,
+            chunk_rows: None,
+        },
+    )?;
+    write_h5ad_with_options(
+        &adata,
+        &gzip_path,
+        WriteOptions {
+            compression: Some(Compression::Gzip(9)),
+            chunk_rows: Some(100),
+        },
+    )?;
+
+    let plain_size = std::fs::metadata(&plain_path)?.len();
+    let gzip_size = std::fs::metadata(&gzip_path)?.len();
+    assert!(gzip_size < plain_size);
+
+    for path in [&plain_path, &gzip_path] {
+        let anndata = AnnData::<H5>::open(H5::open(path)?)?;
+        let imanndata = convert_to_in_memory(anndata)?;
+        assert_eq!(imanndata.n_obs(), 500);
+        assert_eq!(imanndata.n_vars(), 200);
+        assert_eq!(imanndata.obs_names(), adata.obs_names());
+        let counts = imanndata.layers().get_array("counts")?;
+        assert_eq!(counts.get_data()?, adata.get_layer("counts")?.get_data()?);
+
+        let layers = hdf5::File::open(path)?.group("layers")?;
+        let encoding = layers.attr("encoding-type")?.read_scalar::<VarLenUnicode>()?;
+        assert_eq!(encoding.as_str(), "dict");
+        let version = layers.attr("encoding-version")?.read_scalar::<VarLenUnicode>()?;
+        assert_eq!(version.as_str(), "0.1.0");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_cache_round_trip() -> anyhow::Result<()> {
+    let mut adata = create_repetitive_data(20, 10);
+    let groups: Vec<&str> = (0..20).map(|i| ["a", "b", "c"][i % 3]).collect();
+    let group = Series::new("group", groups)
+        .cast(&DataType::Categorical(None, CategoricalOrdering::Physical))?;
+    adata.obs().attach_column_to_df(group)?;
+    adata
+        .obs()
+        .attach_column_to_df(Series::new("n_counts", (0..20).map(|i| i as f64).collect::<Vec<_>>()))?;
+    let counts = adata.x().get_data()?;
+    adata.add_layer("counts".to_string(), IMArrayElement::new(counts))?;
+    let embedding = ndarray::Array2::<f32>::from_shape_fn((20, 2), |(i, j)| (i * 2 + j) as f32);
+    adata
+        .obsm()
+        .add_array("X_umap".to_string(), IMArrayElement::new(ArrayData::from(embedding.into_dyn())))?;
+    adata
+        .uns()
+        .add_data("n_pcs".to_string(), Element::new(Data::from(30i64)))?;
+
+    let dir = tempdir()?;
+    let path = dir.path().join("adata.imcache");
+    adata.save_cache(&path)?;
+    let loaded = IMAnnData::load_cache(&path)?;
+    assert!(loaded.content_equals(&adata));
+    assert!(!loaded.x().0.ptr_eq(&adata.x().0));
+
+    let mut bytes = std::fs::read(&path)?;
+    bytes[0] = b'X';
+    let corrupt_path = dir.path().join("corrupt.imcache");
+    std::fs::write(&corrupt_path, &bytes)?;
+    assert!(IMAnnData::load_cache(&corrupt_path).is_err());
+    Ok(())
+}